@@ -0,0 +1,22 @@
+use green_thread_rs::prelude::*;
+
+const STACK_SIZE: usize = 2 * 1024 * 1024;
+
+fn producer() {
+    let id = spawn(consumer, STACK_SIZE).unwrap();
+    for i in 0..10 {
+        println!("Produce: {}", i);
+        send(id, i);
+    }
+}
+
+fn consumer() {
+    for _ in 0..10 {
+        let msg = recv().unwrap();
+        println!("Consume: {}", msg);
+    }
+}
+
+fn main() {
+    spawn_from_main(producer, STACK_SIZE);
+}
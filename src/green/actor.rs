@@ -0,0 +1,124 @@
+// Actors: green threads owning a state and handling the typed messages sent to their
+// addresses, one at a time.
+
+use super::*;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// A state run by a green thread of its own with `spawn_actor`, which handles the messages
+/// sent to it in the order they arrive.
+pub trait Actor: 'static {
+    type Msg: Send + 'static;
+    fn handle(&mut self, msg: Self::Msg);
+
+    /// Called on the thread of the actor before it handles anything.
+    fn on_start(&mut self) {}
+    /// Called instead of `on_start` when the actor is started again by its supervisor,
+    /// see `Supervisor::actor`.
+    fn on_restart(&mut self) {
+        self.on_start()
+    }
+    /// Called once the actor ends, whether it is stopped, panics or is killed, as an
+    /// `at_exit` hook; not if it is killed while it handles a message.
+    fn on_stop(&mut self) {}
+}
+
+/// The address of an actor spawned by `spawn_actor`, only taking the messages it handles.
+pub struct Addr<A: Actor> {
+    id: ThreadId,
+    _actor: PhantomData<fn(A::Msg)>,
+}
+
+// not derived, which would require A to be Clone as well
+impl<A: Actor> Clone for Addr<A> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<A: Actor> Copy for Addr<A> {}
+
+impl<A: Actor> std::fmt::Debug for Addr<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Addr({})", self.id)
+    }
+}
+
+impl<A: Actor> PartialEq for Addr<A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<A: Actor> Eq for Addr<A> {}
+
+// What the address of an actor sends to its thread
+pub(super) enum Mail<M> {
+    Msg(M),
+    Stop,
+}
+
+/// Spawn a green thread running `actor`, handling the messages sent to the returned address
+/// until it is stopped or killed.
+pub fn spawn_actor<A: Actor>(actor: A, stack_size: usize) -> Result<Addr<A>, SpawnError> {
+    let id = spawn(move || start_actor(actor, false), stack_size)?;
+    Ok(Addr::from_id(id))
+}
+
+// run `actor` on the calling thread with its lifecycle callbacks, until it is stopped
+pub(super) fn start_actor<A: Actor>(actor: A, restarted: bool) {
+    // shared with the hook, which may run on a killing thread
+    let actor = Rc::new(RefCell::new(actor));
+    let stopping = actor.clone();
+    at_exit(move || {
+        // still borrowed if killed while handling a message
+        if let Ok(mut actor) = stopping.try_borrow_mut() {
+            actor.on_stop();
+        }
+    });
+    if restarted {
+        actor.borrow_mut().on_restart();
+    } else {
+        actor.borrow_mut().on_start();
+    }
+    while let Some(Mail::Msg(msg)) = recv_typed::<Mail<A::Msg>>() {
+        actor.borrow_mut().handle(msg);
+    }
+}
+
+// handle the messages sent to the address of the calling thread, until it is stopped
+pub(super) fn run_actor<A: Actor>(actor: &mut A) {
+    while let Some(Mail::Msg(msg)) = recv_typed::<Mail<A::Msg>>() {
+        actor.handle(msg);
+    }
+}
+
+impl<A: Actor> Addr<A> {
+    /// The address of the actor `A` running on the thread `id`, which is not checked.
+    pub fn from_id(id: ThreadId) -> Self {
+        Addr {
+            id,
+            _actor: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Send `msg` to the actor, letting it run.
+    pub fn send(&self, msg: A::Msg) {
+        send_typed(self.id, Mail::Msg(msg))
+    }
+
+    /// Make the actor end once it has handled the messages sent before.
+    pub fn stop(&self) {
+        send_typed(self.id, Mail::<A::Msg>::Stop)
+    }
+
+    /// Whether the thread of the actor is still live.
+    pub fn is_alive(&self) -> bool {
+        !runtime_ptr().is_null() && unsafe { rt().ids.contains(&self.id) }
+    }
+}
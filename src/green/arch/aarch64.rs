@@ -0,0 +1,129 @@
+// AArch64: x19-x28, the frame pointer, the link register and the stack pointer,
+// and d8-d15 when asked.
+
+use super::ContextEntry;
+use std::arch::naked_asm;
+
+context_ops!(Aarch64);
+
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    d8: u64,
+    d9: u64,
+    d10: u64,
+    d11: u64,
+    d12: u64,
+    d13: u64,
+    d14: u64,
+    d15: u64,
+    x19: u64,
+    x20: u64,
+    x21: u64,
+    x22: u64,
+    x23: u64,
+    x24: u64,
+    x25: u64,
+    x26: u64,
+    x27: u64,
+    x28: u64,
+
+    x29: u64, // frame pointer
+    x30: u64, // link register
+    sp: u64,  // stack pointer
+}
+
+impl Registers {
+    // registers starting `entry` on the stack of `stack_size` bytes at `stack`
+    pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        // sp must stay 16-byte aligned, whatever the size of the stack
+        let sp = (stack as usize + stack_size) & !15;
+        Registers {
+            d8: 0,
+            d9: 0,
+            d10: 0,
+            d11: 0,
+            d12: 0,
+            d13: 0,
+            d14: 0,
+            d15: 0,
+            x19: 0,
+            x20: 0,
+            x21: 0,
+            x22: 0,
+            x23: 0,
+            x24: 0,
+            x25: 0,
+            x26: 0,
+            x27: 0,
+            x28: 0,
+            x29: 0,
+            x30: entry as usize as u64,
+            sp: sp as u64,
+        }
+    }
+}
+
+// x2: bit 0 = save d8-d15, bit 1 = restore d8-d15
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn swap_context(
+    save: *mut Registers,
+    restore: *const Registers,
+    flags: u64,
+) {
+    naked_asm!(
+        // save callee-saved register
+        "tbz x2, #0, 1f",
+        "stp d8, d9, [x0]",
+        "stp d10, d11, [x0, #16]",
+        "stp d12, d13, [x0, #16 * 2]",
+        "stp d14, d15, [x0, #16 * 3]",
+        "1:",
+        "stp x19, x20, [x0, #16 * 4]",
+        "stp x21, x22, [x0, #16 * 5]",
+        "stp x23, x24, [x0, #16 * 6]",
+        "stp x25, x26, [x0, #16 * 7]",
+        "stp x27, x28, [x0, #16 * 8]",
+        "stp x29, x30, [x0, #16 * 9]",
+        "mov x3, sp",
+        "str x3, [x0, #16 * 10]",
+        // restore callee-saved registers of the next context
+        "tbz x2, #1, 2f",
+        "ldp d8, d9, [x1]",
+        "ldp d10, d11, [x1, #16]",
+        "ldp d12, d13, [x1, #16 * 2]",
+        "ldp d14, d15, [x1, #16 * 3]",
+        "2:",
+        "ldp x19, x20, [x1, #16 * 4]",
+        "ldp x21, x22, [x1, #16 * 5]",
+        "ldp x23, x24, [x1, #16 * 6]",
+        "ldp x25, x26, [x1, #16 * 7]",
+        "ldp x27, x28, [x1, #16 * 8]",
+        "ldp x29, x30, [x1, #16 * 9]",
+        "ldr x3, [x1, #16 * 10]",
+        "mov sp, x3",
+        "ret",
+    )
+}
+
+// x1: bit 1 = restore d8-d15
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+    naked_asm!(
+        // restore callee-saved registers
+        "tbz x1, #1, 1f",
+        "ldp d8, d9, [x0]",
+        "ldp d10, d11, [x0, #16]",
+        "ldp d12, d13, [x0, #16 * 2]",
+        "ldp d14, d15, [x0, #16 * 3]",
+        "1:",
+        "ldp x19, x20, [x0, #16 * 4]",
+        "ldp x21, x22, [x0, #16 * 5]",
+        "ldp x23, x24, [x0, #16 * 6]",
+        "ldp x25, x26, [x0, #16 * 7]",
+        "ldp x27, x28, [x0, #16 * 8]",
+        "ldp x29, x30, [x0, #16 * 9]",
+        "ldr x2, [x0, #16 * 10]",
+        "mov sp, x2",
+        "ret",
+    )
+}
@@ -0,0 +1,98 @@
+// ARMv7 with the hard-float ABI: r4-r11, lr and sp, and d8-d15 when asked.
+
+use super::ContextEntry;
+use std::arch::naked_asm;
+
+context_ops!(Arm);
+
+// AAPCS with the hard-float ABI: 32-bit core registers, and the 64-bit d8-d15
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    d8: u64,
+    d9: u64,
+    d10: u64,
+    d11: u64,
+    d12: u64,
+    d13: u64,
+    d14: u64,
+    d15: u64,
+    r4: u32,
+    r5: u32,
+    r6: u32,
+    r7: u32,
+    r8: u32,
+    r9: u32,
+    r10: u32,
+    r11: u32,
+
+    lr: u32, // link register
+    sp: u32, // stack pointer
+}
+
+impl Registers {
+    // registers starting `entry` on the stack of `stack_size` bytes at `stack`
+    pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        // 8-byte aligned as the AAPCS expects at a call, whatever the size of the stack
+        let sp = (stack as usize + stack_size) & !7;
+        Registers {
+            d8: 0,
+            d9: 0,
+            d10: 0,
+            d11: 0,
+            d12: 0,
+            d13: 0,
+            d14: 0,
+            d15: 0,
+            r4: 0,
+            r5: 0,
+            r6: 0,
+            r7: 0,
+            r8: 0,
+            r9: 0,
+            r10: 0,
+            r11: 0,
+            lr: entry as usize as u32,
+            sp: sp as u32,
+        }
+    }
+}
+
+// r2: bit 0 = save d8-d15, bit 1 = restore d8-d15; the AAPCS passes a u64 in an even
+// register pair, r2:r3, and switch_context finds it there as well
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn swap_context(
+    save: *mut Registers,
+    restore: *const Registers,
+    flags: u64,
+) {
+    naked_asm!(
+        // save callee-saved register
+        "tst r2, #1",
+        "beq 1f",
+        "vstmia r0, {{d8-d15}}",
+        "1:",
+        "add r3, r0, #64",
+        "stmia r3, {{r4-r11, lr}}",
+        "str sp, [r0, #100]",
+        // restore the next context
+        "mov r0, r1",
+        "b {switch}",
+        switch = sym switch_context,
+    )
+}
+
+// r2: bit 1 = restore d8-d15 (r1 is skipped to align the u64 flags)
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+    naked_asm!(
+        // restore callee-saved registers
+        "tst r2, #2",
+        "beq 1f",
+        "vldmia r0, {{d8-d15}}",
+        "1:",
+        "add r3, r0, #64",
+        "ldmia r3, {{r4-r11, lr}}",
+        "ldr sp, [r0, #100]",
+        "bx lr",
+    )
+}
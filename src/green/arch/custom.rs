@@ -0,0 +1,93 @@
+// The `custom` backend: the ContextOps of the embedder, registered with
+// `register_context_backend!`. Its registers are boxed by the functions the macro defines,
+// so that their type is only known to the embedder.
+
+use super::ContextEntry;
+use std::ffi::c_void;
+
+context_ops!(Custom);
+
+extern "Rust" {
+    fn __green_context_with_entry(
+        stack: *mut u8,
+        stack_size: usize,
+        entry: ContextEntry,
+    ) -> *mut c_void;
+    fn __green_context_swap(save: *mut c_void, restore: *const c_void, flags: u64);
+    fn __green_context_switch(ctx: *const c_void, flags: u64) -> !;
+    fn __green_context_drop(regs: *mut c_void);
+}
+
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    regs: *mut c_void,
+}
+
+impl Registers {
+    // registers starting `entry` on the stack of `stack_size` bytes at `stack`
+    pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        Registers {
+            regs: unsafe { __green_context_with_entry(stack, stack_size, entry) },
+        }
+    }
+}
+
+impl Drop for Registers {
+    fn drop(&mut self) {
+        unsafe { __green_context_drop(self.regs) };
+    }
+}
+
+pub(crate) unsafe fn swap_context(save: *mut Registers, restore: *const Registers, flags: u64) {
+    __green_context_swap((*save).regs, (*restore).regs, flags);
+}
+
+pub(crate) unsafe fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+    __green_context_switch((*ctx).regs, flags);
+}
+
+/// Register `$backend`, a type implementing `green::ContextOps`, as the context switch of
+/// the runtime. Needs the `custom` feature, and must be used exactly once in the binary.
+#[macro_export]
+macro_rules! register_context_backend {
+    ($backend:ty) => {
+        #[no_mangle]
+        unsafe fn __green_context_with_entry(
+            stack: *mut u8,
+            stack_size: usize,
+            entry: $crate::green::ContextEntry,
+        ) -> *mut ::std::ffi::c_void {
+            let regs =
+                <$backend as $crate::green::ContextOps>::with_entry(stack, stack_size, entry);
+            ::std::boxed::Box::into_raw(::std::boxed::Box::new(regs)) as *mut ::std::ffi::c_void
+        }
+
+        #[no_mangle]
+        unsafe fn __green_context_swap(
+            save: *mut ::std::ffi::c_void,
+            restore: *const ::std::ffi::c_void,
+            flags: u64,
+        ) {
+            <$backend as $crate::green::ContextOps>::swap_context(
+                save as *mut <$backend as $crate::green::ContextOps>::Registers,
+                restore as *const <$backend as $crate::green::ContextOps>::Registers,
+                flags,
+            )
+        }
+
+        #[no_mangle]
+        unsafe fn __green_context_switch(ctx: *const ::std::ffi::c_void, flags: u64) -> ! {
+            <$backend as $crate::green::ContextOps>::switch_context(
+                ctx as *const <$backend as $crate::green::ContextOps>::Registers,
+                flags,
+            )
+        }
+
+        #[no_mangle]
+        unsafe fn __green_context_drop(regs: *mut ::std::ffi::c_void) {
+            drop(::std::boxed::Box::from_raw(
+                regs as *mut <$backend as $crate::green::ContextOps>::Registers,
+            ));
+        }
+    };
+}
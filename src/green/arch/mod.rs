@@ -0,0 +1,331 @@
+// The context switch of each target: its `Registers`, and `swap_context`/`switch_context`
+// saving and restoring them. The assembly addresses the fields of `Registers` by offset,
+// so it is `repr(C)`, and aligned to start on its own cache line.
+//
+// The backend is chosen at compile time, `custom` first, then `software`, `ucontext`,
+// and the `asm-*` feature of the target; without any of them, the assembly of the target,
+// or ucontext where there is none.
+
+use std::cell::Cell;
+use std::ptr;
+
+/// The function a new context starts in. It never returns; the `software` backend ends
+/// its OS thread by unwinding out of it.
+pub type ContextEntry = extern "C-unwind" fn();
+
+/// A way of switching between stacks, selected with the cargo features of the crate:
+/// `asm-aarch64`, `asm-x86_64`, `asm-riscv64`, `asm-arm`, `ucontext`, `software`,
+/// or `custom` with `register_context_backend!`.
+///
+/// # Safety
+///
+/// `swap_context` must save the running context into `save` so that resuming `save`
+/// returns from the call, and both functions must then resume `restore` (or `ctx`):
+/// returning from the call that saved it, or calling its entry if it never ran.
+/// Registers are only accessed through pointers, and do not move once saved into.
+pub unsafe trait ContextOps {
+    type Registers;
+
+    /// Registers starting `entry` on the stack of `stack_size` bytes at `stack`.
+    /// `stack` is null for registers that are only ever saved into.
+    fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self::Registers;
+
+    /// Save the running context into `save`, and resume `restore`.
+    ///
+    /// # Safety
+    ///
+    /// Both must point to valid registers, and `restore` must be resumable.
+    unsafe fn swap_context(save: *mut Self::Registers, restore: *const Self::Registers, flags: u64);
+
+    /// Resume `ctx`, abandoning the running context.
+    ///
+    /// # Safety
+    ///
+    /// `ctx` must point to valid and resumable registers.
+    unsafe fn switch_context(ctx: *const Self::Registers, flags: u64) -> !;
+}
+
+// implement ContextOps for a backend type with the Registers, swap_context
+// and switch_context of its module
+macro_rules! context_ops {
+    ($backend:ident) => {
+        pub(crate) struct $backend;
+
+        unsafe impl super::ContextOps for $backend {
+            type Registers = Registers;
+
+            #[inline(always)]
+            fn with_entry(
+                stack: *mut u8,
+                stack_size: usize,
+                entry: super::ContextEntry,
+            ) -> Registers {
+                Registers::with_entry(stack, stack_size, entry)
+            }
+
+            #[inline(always)]
+            unsafe fn swap_context(save: *mut Registers, restore: *const Registers, flags: u64) {
+                swap_context(save, restore, flags)
+            }
+
+            #[inline(always)]
+            unsafe fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+                switch_context(ctx, flags)
+            }
+        }
+    };
+}
+
+#[cfg(all(
+    target_arch = "aarch64",
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-aarch64",
+        not(any(feature = "asm-x86_64", feature = "asm-riscv64", feature = "asm-arm"))
+    )
+))]
+mod aarch64;
+#[cfg(all(
+    target_arch = "aarch64",
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-aarch64",
+        not(any(feature = "asm-x86_64", feature = "asm-riscv64", feature = "asm-arm"))
+    )
+))]
+use aarch64::Aarch64 as Backend;
+
+#[cfg(all(
+    target_arch = "x86_64",
+    not(windows),
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-x86_64",
+        not(any(feature = "asm-aarch64", feature = "asm-riscv64", feature = "asm-arm"))
+    )
+))]
+mod x86_64;
+#[cfg(all(
+    target_arch = "x86_64",
+    not(windows),
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-x86_64",
+        not(any(feature = "asm-aarch64", feature = "asm-riscv64", feature = "asm-arm"))
+    )
+))]
+use x86_64::X86_64 as Backend;
+
+#[cfg(all(
+    target_arch = "x86_64",
+    windows,
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-x86_64",
+        not(any(feature = "asm-aarch64", feature = "asm-riscv64", feature = "asm-arm"))
+    )
+))]
+mod x86_64_windows;
+#[cfg(all(
+    target_arch = "x86_64",
+    windows,
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-x86_64",
+        not(any(feature = "asm-aarch64", feature = "asm-riscv64", feature = "asm-arm"))
+    )
+))]
+use x86_64_windows::X86_64Windows as Backend;
+
+#[cfg(all(
+    target_arch = "riscv64",
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-riscv64",
+        not(any(feature = "asm-aarch64", feature = "asm-x86_64", feature = "asm-arm"))
+    )
+))]
+mod riscv64;
+#[cfg(all(
+    target_arch = "riscv64",
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-riscv64",
+        not(any(feature = "asm-aarch64", feature = "asm-x86_64", feature = "asm-arm"))
+    )
+))]
+use riscv64::Riscv64 as Backend;
+
+#[cfg(all(
+    target_arch = "arm",
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-arm",
+        not(any(
+            feature = "asm-aarch64",
+            feature = "asm-x86_64",
+            feature = "asm-riscv64"
+        ))
+    )
+))]
+mod arm;
+#[cfg(all(
+    target_arch = "arm",
+    not(any(feature = "custom", feature = "software", feature = "ucontext")),
+    any(
+        feature = "asm-arm",
+        not(any(
+            feature = "asm-aarch64",
+            feature = "asm-x86_64",
+            feature = "asm-riscv64"
+        ))
+    )
+))]
+use arm::Arm as Backend;
+
+// with the `ucontext` feature, or without any feature where there is no assembly
+#[cfg(all(
+    not(any(feature = "custom", feature = "software")),
+    any(
+        feature = "ucontext",
+        not(any(
+            feature = "asm-aarch64",
+            feature = "asm-x86_64",
+            feature = "asm-riscv64",
+            feature = "asm-arm",
+            target_arch = "aarch64",
+            target_arch = "x86_64",
+            target_arch = "riscv64",
+            target_arch = "arm"
+        ))
+    )
+))]
+mod ucontext;
+#[cfg(all(
+    not(any(feature = "custom", feature = "software")),
+    any(
+        feature = "ucontext",
+        not(any(
+            feature = "asm-aarch64",
+            feature = "asm-x86_64",
+            feature = "asm-riscv64",
+            feature = "asm-arm",
+            target_arch = "aarch64",
+            target_arch = "x86_64",
+            target_arch = "riscv64",
+            target_arch = "arm"
+        ))
+    )
+))]
+use ucontext::Ucontext as Backend;
+
+#[cfg(all(feature = "software", not(feature = "custom")))]
+mod software;
+#[cfg(all(feature = "software", not(feature = "custom")))]
+use software::Software as Backend;
+
+#[cfg(feature = "custom")]
+mod custom;
+#[cfg(feature = "custom")]
+use custom::Custom as Backend;
+
+#[cfg(all(feature = "asm-aarch64", not(target_arch = "aarch64")))]
+compile_error!("the `asm-aarch64` feature needs an aarch64 target");
+#[cfg(all(feature = "asm-x86_64", not(target_arch = "x86_64")))]
+compile_error!("the `asm-x86_64` feature needs an x86_64 target");
+#[cfg(all(feature = "asm-riscv64", not(target_arch = "riscv64")))]
+compile_error!("the `asm-riscv64` feature needs a riscv64 target");
+#[cfg(all(feature = "asm-arm", not(target_arch = "arm")))]
+compile_error!("the `asm-arm` feature needs an arm target");
+#[cfg(all(
+    not(unix),
+    not(any(feature = "custom", feature = "software")),
+    any(feature = "ucontext", not(target_arch = "x86_64"))
+))]
+compile_error!("there is no ucontext on this target: use the `software` or `custom` feature");
+
+// The registers of the selected backend
+pub(crate) type Registers = <Backend as ContextOps>::Registers;
+
+// The bytes cleared at the top of a new stack, where the first frame finds the return address
+// of its caller: zero, so that the unwinder capturing a backtrace stops there instead of
+// following what a previous thread left on a reused stack
+const STACK_TOP_CLEARED: usize = 64;
+
+#[inline(always)]
+pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Registers {
+    if !stack.is_null() {
+        unsafe {
+            let top = stack.add(stack_size - STACK_TOP_CLEARED);
+            ptr::write_bytes(top, 0, STACK_TOP_CLEARED);
+        }
+    }
+    Backend::with_entry(stack, stack_size, entry)
+}
+
+#[inline(always)]
+pub(crate) unsafe fn swap_context(save: *mut Registers, restore: *const Registers, flags: u64) {
+    Backend::swap_context(save, restore, flags)
+}
+
+#[inline(always)]
+pub(crate) unsafe fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+    Backend::switch_context(ctx, flags)
+}
+
+// Per-OS-thread pointers of the context core and the scheduler, like the running runtime.
+// Every context of the `software` backend runs on an OS thread of its own, so it hands them
+// over to the context it resumes; with the other backends they are plain thread-locals.
+pub(crate) const HOST_LOCALS: usize = 3;
+pub(crate) const COROUTINE_SLOT: usize = 0;
+#[cfg(feature = "scheduler")]
+pub(crate) const RUNTIME_SLOT: usize = 1;
+#[cfg(feature = "model")]
+pub(crate) const MODEL_SLOT: usize = 2;
+
+pub(crate) type HostLocals = [*mut (); HOST_LOCALS];
+
+thread_local! {
+    static HOST: Cell<HostLocals> = const { Cell::new([ptr::null_mut(); HOST_LOCALS]) };
+}
+
+#[inline(always)]
+pub(crate) fn host_local(slot: usize) -> *mut () {
+    HOST.with(|host| host.get()[slot])
+}
+
+#[inline(always)]
+pub(crate) fn set_host_local(slot: usize, value: *mut ()) {
+    HOST.with(|host| {
+        let mut locals = host.get();
+        locals[slot] = value;
+        host.set(locals);
+    })
+}
+
+#[cfg(all(feature = "software", not(feature = "custom")))]
+fn host_locals() -> HostLocals {
+    HOST.with(Cell::get)
+}
+
+#[cfg(all(feature = "software", not(feature = "custom")))]
+fn set_host_locals(locals: HostLocals) {
+    HOST.with(|host| host.set(locals))
+}
+
+// flags for swap_context/switch_context telling whether d8-d15 (fs0-fs11 on riscv64,
+// mxcsr and the x87 control word on x86_64, and xmm6-xmm15 on Windows) must be saved/restored
+pub(crate) const SAVE_FP: u64 = 1;
+pub(crate) const RESTORE_FP: u64 = 2;
+
+#[cfg(feature = "scheduler")]
+pub(crate) fn fp_flags(save_fp: bool, restore_fp: bool) -> u64 {
+    let mut flags = 0;
+    if save_fp {
+        flags |= SAVE_FP;
+    }
+    if restore_fp {
+        flags |= RESTORE_FP;
+    }
+    flags
+}
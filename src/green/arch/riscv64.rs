@@ -0,0 +1,157 @@
+// riscv64: s0-s11, ra and sp, and fs0-fs11 when asked.
+
+use super::ContextEntry;
+use std::arch::naked_asm;
+
+context_ops!(Riscv64);
+
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    fs0: u64,
+    fs1: u64,
+    fs2: u64,
+    fs3: u64,
+    fs4: u64,
+    fs5: u64,
+    fs6: u64,
+    fs7: u64,
+    fs8: u64,
+    fs9: u64,
+    fs10: u64,
+    fs11: u64,
+    s0: u64,
+    s1: u64,
+    s2: u64,
+    s3: u64,
+    s4: u64,
+    s5: u64,
+    s6: u64,
+    s7: u64,
+    s8: u64,
+    s9: u64,
+    s10: u64,
+    s11: u64,
+
+    ra: u64, // return address
+    sp: u64, // stack pointer
+}
+
+impl Registers {
+    // registers starting `entry` on the stack of `stack_size` bytes at `stack`
+    pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        // 16-byte aligned as the psABI expects, whatever the size of the stack
+        let sp = (stack as usize + stack_size) & !15;
+        Registers {
+            fs0: 0,
+            fs1: 0,
+            fs2: 0,
+            fs3: 0,
+            fs4: 0,
+            fs5: 0,
+            fs6: 0,
+            fs7: 0,
+            fs8: 0,
+            fs9: 0,
+            fs10: 0,
+            fs11: 0,
+            s0: 0,
+            s1: 0,
+            s2: 0,
+            s3: 0,
+            s4: 0,
+            s5: 0,
+            s6: 0,
+            s7: 0,
+            s8: 0,
+            s9: 0,
+            s10: 0,
+            s11: 0,
+            ra: entry as usize as u64,
+            sp: sp as u64,
+        }
+    }
+}
+
+// a2: bit 0 = save fs0-fs11, bit 1 = restore fs0-fs11
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn swap_context(
+    save: *mut Registers,
+    restore: *const Registers,
+    flags: u64,
+) {
+    naked_asm!(
+        // save callee-saved register
+        "andi t0, a2, 1",
+        "beqz t0, 1f",
+        "fsd fs0, 0(a0)",
+        "fsd fs1, 8(a0)",
+        "fsd fs2, 16(a0)",
+        "fsd fs3, 24(a0)",
+        "fsd fs4, 32(a0)",
+        "fsd fs5, 40(a0)",
+        "fsd fs6, 48(a0)",
+        "fsd fs7, 56(a0)",
+        "fsd fs8, 64(a0)",
+        "fsd fs9, 72(a0)",
+        "fsd fs10, 80(a0)",
+        "fsd fs11, 88(a0)",
+        "1:",
+        "sd s0, 96(a0)",
+        "sd s1, 104(a0)",
+        "sd s2, 112(a0)",
+        "sd s3, 120(a0)",
+        "sd s4, 128(a0)",
+        "sd s5, 136(a0)",
+        "sd s6, 144(a0)",
+        "sd s7, 152(a0)",
+        "sd s8, 160(a0)",
+        "sd s9, 168(a0)",
+        "sd s10, 176(a0)",
+        "sd s11, 184(a0)",
+        "sd ra, 192(a0)",
+        "sd sp, 200(a0)",
+        // restore the next context
+        "mv a0, a1",
+        "mv a1, a2",
+        "j {switch}",
+        switch = sym switch_context,
+    )
+}
+
+// a1: bit 1 = restore fs0-fs11
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+    naked_asm!(
+        // restore callee-saved registers
+        "andi t0, a1, 2",
+        "beqz t0, 1f",
+        "fld fs0, 0(a0)",
+        "fld fs1, 8(a0)",
+        "fld fs2, 16(a0)",
+        "fld fs3, 24(a0)",
+        "fld fs4, 32(a0)",
+        "fld fs5, 40(a0)",
+        "fld fs6, 48(a0)",
+        "fld fs7, 56(a0)",
+        "fld fs8, 64(a0)",
+        "fld fs9, 72(a0)",
+        "fld fs10, 80(a0)",
+        "fld fs11, 88(a0)",
+        "1:",
+        "ld s0, 96(a0)",
+        "ld s1, 104(a0)",
+        "ld s2, 112(a0)",
+        "ld s3, 120(a0)",
+        "ld s4, 128(a0)",
+        "ld s5, 136(a0)",
+        "ld s6, 144(a0)",
+        "ld s7, 152(a0)",
+        "ld s8, 160(a0)",
+        "ld s9, 168(a0)",
+        "ld s10, 176(a0)",
+        "ld s11, 184(a0)",
+        "ld ra, 192(a0)",
+        "ld sp, 200(a0)",
+        "ret",
+    )
+}
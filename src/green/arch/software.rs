@@ -0,0 +1,126 @@
+// The `software` backend: every context runs on an OS thread of its own, and a switch hands
+// the turn from one thread to the other. Much slower than the others, but it needs neither
+// assembly nor libc; the stacks given to it are left unused, the OS threads have their own.
+
+use super::{host_locals, set_host_locals, ContextEntry, HostLocals};
+use std::cell::Cell;
+use std::panic;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+context_ops!(Software);
+
+// the smallest stack given to the thread of a context
+const MIN_STACK_SIZE: usize = 64 * 1024;
+
+// set by whoever resumes the context with its host locals, and taken by the context
+// as it runs again
+struct Turn {
+    resumed: Mutex<Option<HostLocals>>,
+    cond: Condvar,
+}
+
+// the host locals are only touched by the thread holding the turn
+unsafe impl Send for Turn {}
+unsafe impl Sync for Turn {}
+
+impl Turn {
+    fn give(&self) {
+        *self.resumed.lock().unwrap() = Some(host_locals());
+        self.cond.notify_one();
+    }
+
+    fn wait(&self) {
+        let mut resumed = self.resumed.lock().unwrap();
+        loop {
+            if let Some(locals) = resumed.take() {
+                set_host_locals(locals);
+                return;
+            }
+            resumed = self.cond.wait(resumed).unwrap();
+        }
+    }
+}
+
+// the host locals handed to the thread of a context as it starts
+struct SendLocals(HostLocals);
+
+unsafe impl Send for SendLocals {}
+
+impl SendLocals {
+    fn into_inner(self) -> HostLocals {
+        self.0
+    }
+}
+
+// the payload unwinding out of the entry of a context abandoned by `switch_context`
+struct Abandoned;
+
+thread_local! {
+    // true on the threads spawned for a context, which end when it is abandoned
+    static SPAWNED: Cell<bool> = const { Cell::new(false) };
+}
+
+// the turn of a context is shared with its thread; a context dropped while suspended
+// leaves its thread waiting until the process exits
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    turn: Arc<Turn>,
+    // set until the context first runs, its thread is only spawned then
+    entry: Option<ContextEntry>,
+    stack_size: usize,
+}
+
+impl Registers {
+    // registers starting `entry` on a thread of `stack_size` bytes of stack
+    pub(crate) fn with_entry(_stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        Registers {
+            turn: Arc::new(Turn {
+                resumed: Mutex::new(None),
+                cond: Condvar::new(),
+            }),
+            entry: Some(entry),
+            stack_size,
+        }
+    }
+
+    // run the thread of the context, spawning it if it has not run yet
+    unsafe fn resume(regs: *mut Registers) {
+        match (*regs).entry.take() {
+            Some(entry) => {
+                let locals = SendLocals(host_locals());
+                thread::Builder::new()
+                    .stack_size((*regs).stack_size.max(MIN_STACK_SIZE))
+                    .spawn(move || {
+                        set_host_locals(locals.into_inner());
+                        SPAWNED.with(|spawned| spawned.set(true));
+                        // the entry never returns, it only unwinds once abandoned
+                        let _ = panic::catch_unwind(|| entry());
+                    })
+                    .unwrap();
+            }
+            None => (*regs).turn.give(),
+        }
+    }
+}
+
+// the flags are ignored, each thread keeps its own floating-point registers
+pub(crate) unsafe fn swap_context(save: *mut Registers, restore: *const Registers, _flags: u64) {
+    // whatever `save` was made for, it now resumes here
+    (*save).entry = None;
+    let turn = (*save).turn.clone();
+    Registers::resume(restore as *mut Registers);
+    turn.wait();
+}
+
+pub(crate) unsafe fn switch_context(ctx: *const Registers, _flags: u64) -> ! {
+    Registers::resume(ctx as *mut Registers);
+    if SPAWNED.with(|spawned| spawned.get()) {
+        // the entries are `extern "C-unwind"`, so this ends the thread
+        panic::resume_unwind(Box::new(Abandoned));
+    }
+    // a thread that was not spawned for a context, like main, can only wait
+    loop {
+        thread::park();
+    }
+}
@@ -0,0 +1,62 @@
+// The portable backend: getcontext/makecontext/swapcontext of libc.
+
+use super::ContextEntry;
+use std::ffi::c_void;
+use std::ptr;
+
+context_ops!(Ucontext);
+
+// used with the `ucontext` feature or where there is no assembly;
+// the ucontext functions always switch every register, and the signal mask
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    context: libc::ucontext_t,
+    // set until the context first runs: a ucontext_t may point into itself,
+    // so it is only made once the registers have reached their final address
+    entry: Option<ContextEntry>,
+    stack: *mut u8,
+    stack_size: usize,
+}
+
+impl Registers {
+    // registers starting `entry` on the stack of `stack_size` bytes at `stack`
+    pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        Registers {
+            context: unsafe { std::mem::zeroed() },
+            entry: Some(entry),
+            stack,
+            stack_size,
+        }
+    }
+
+    // make the context of registers that have not run yet
+    unsafe fn make(regs: *mut Registers) {
+        if let Some(entry) = (*regs).entry.take() {
+            let context = &mut (*regs).context;
+            assert_eq!(libc::getcontext(context), 0);
+            context.uc_stack.ss_sp = (*regs).stack as *mut c_void;
+            context.uc_stack.ss_size = (*regs).stack_size;
+            context.uc_link = ptr::null_mut();
+            // makecontext takes an `extern "C" fn()`; the entry never returns through it
+            let entry: extern "C" fn() = std::mem::transmute(entry);
+            libc::makecontext(context, entry, 0);
+        }
+    }
+}
+
+// the flags are ignored, swapcontext always saves and restores the floating-point registers
+pub(crate) unsafe fn swap_context(save: *mut Registers, restore: *const Registers, _flags: u64) {
+    // whatever `save` was made for, it now resumes here
+    (*save).entry = None;
+    Registers::make(restore as *mut Registers);
+    assert_eq!(
+        libc::swapcontext(&mut (*save).context, &(*restore).context),
+        0
+    );
+}
+
+pub(crate) unsafe fn switch_context(ctx: *const Registers, _flags: u64) -> ! {
+    Registers::make(ctx as *mut Registers);
+    libc::setcontext(&(*ctx).context);
+    unreachable!("setcontext failed");
+}
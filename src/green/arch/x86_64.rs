@@ -0,0 +1,103 @@
+// x86_64 with the System V ABI: rbx, rbp, r12-r15 and the stack pointer, and the
+// floating-point control words when asked.
+
+use super::ContextEntry;
+use std::arch::naked_asm;
+
+context_ops!(X86_64);
+
+// System V: there are no callee-saved vector registers,
+// but the control bits of the SSE and x87 units must be preserved
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+
+    rip: u64, // return address
+    rsp: u64, // stack pointer
+    mxcsr: u32,
+    x87_cw: u32,
+}
+
+impl Registers {
+    // registers starting `entry` on the stack of `stack_size` bytes at `stack`
+    pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        // 16-byte aligned as the System V ABI expects, whatever the size of the stack
+        let sp = (stack as usize + stack_size) & !15;
+        Registers {
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rip: entry as usize as u64,
+            // `entry` is jumped to rather than called, so leave the slot of the return address
+            // a call would have pushed to keep the stack aligned as the ABI expects
+            rsp: sp.wrapping_sub(8) as u64,
+            // the default control words, all floating-point exceptions masked
+            mxcsr: 0x1f80,
+            x87_cw: 0x037f,
+        }
+    }
+}
+
+// rdx: bit 0 = save mxcsr and the x87 control word, bit 1 = restore them
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn swap_context(
+    save: *mut Registers,
+    restore: *const Registers,
+    flags: u64,
+) {
+    naked_asm!(
+        // save callee-saved register
+        "mov %rbx, (%rdi)",
+        "mov %rbp, 8(%rdi)",
+        "mov %r12, 16(%rdi)",
+        "mov %r13, 24(%rdi)",
+        "mov %r14, 32(%rdi)",
+        "mov %r15, 40(%rdi)",
+        // resume at the return address, with the stack as it is after returning
+        "mov (%rsp), %rax",
+        "mov %rax, 48(%rdi)",
+        "lea 8(%rsp), %rax",
+        "mov %rax, 56(%rdi)",
+        "test $1, %dl",
+        "jz 1f",
+        "stmxcsr 64(%rdi)",
+        "fnstcw 68(%rdi)",
+        "1:",
+        // restore the next context
+        "mov %rsi, %rdi",
+        "mov %rdx, %rsi",
+        "jmp {switch}",
+        switch = sym switch_context,
+        options(att_syntax),
+    )
+}
+
+// rsi: bit 1 = restore mxcsr and the x87 control word
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+    naked_asm!(
+        // restore callee-saved registers
+        "test $2, %sil",
+        "jz 1f",
+        "ldmxcsr 64(%rdi)",
+        "fldcw 68(%rdi)",
+        "1:",
+        "mov (%rdi), %rbx",
+        "mov 8(%rdi), %rbp",
+        "mov 16(%rdi), %r12",
+        "mov 24(%rdi), %r13",
+        "mov 32(%rdi), %r14",
+        "mov 40(%rdi), %r15",
+        "mov 56(%rdi), %rsp",
+        "jmp *48(%rdi)",
+        options(att_syntax),
+    )
+}
@@ -0,0 +1,168 @@
+// x86_64 with the Windows x64 ABI, whose arguments and callee-saved registers differ from
+// System V.
+
+use super::ContextEntry;
+use std::arch::naked_asm;
+
+context_ops!(X86_64Windows);
+
+// The Windows x64 ABI also preserves rdi, rsi and xmm6-xmm15, and each context has its own
+// stack bounds in the thread information block, which the unwinder checks frames against
+#[repr(C, align(64))]
+pub(crate) struct Registers {
+    rbx: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+
+    rip: u64, // return address
+    rsp: u64, // stack pointer
+    stack_base: u64,
+    stack_limit: u64,
+    mxcsr: u32,
+    x87_cw: u32,
+    xmm6: u128,
+    xmm7: u128,
+    xmm8: u128,
+    xmm9: u128,
+    xmm10: u128,
+    xmm11: u128,
+    xmm12: u128,
+    xmm13: u128,
+    xmm14: u128,
+    xmm15: u128,
+}
+
+// the offset the assembly below saves xmm6 at
+const _: () = assert!(std::mem::offset_of!(Registers, xmm6) == 112);
+
+impl Registers {
+    // registers starting `entry` on the stack of `stack_size` bytes at `stack`
+    pub(crate) fn with_entry(stack: *mut u8, stack_size: usize, entry: ContextEntry) -> Self {
+        // 16-byte aligned as the Windows x64 ABI expects, whatever the size of the stack
+        let sp = (stack as usize + stack_size) & !15;
+        Registers {
+            rbx: 0,
+            rbp: 0,
+            rdi: 0,
+            rsi: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rip: entry as usize as u64,
+            // like a call, leave the slot of the return address and the 32 bytes of shadow space
+            // above it, which `entry` may spill its arguments to
+            rsp: sp.wrapping_sub(40) as u64,
+            stack_base: sp as u64,
+            // the whole stack is committed, so stack probes have no pages to commit
+            stack_limit: 0,
+            // the default control words of Windows, all floating-point exceptions masked
+            mxcsr: 0x1f80,
+            x87_cw: 0x027f,
+            xmm6: 0,
+            xmm7: 0,
+            xmm8: 0,
+            xmm9: 0,
+            xmm10: 0,
+            xmm11: 0,
+            xmm12: 0,
+            xmm13: 0,
+            xmm14: 0,
+            xmm15: 0,
+        }
+    }
+}
+
+// r8: bit 0 = save xmm6-xmm15, mxcsr and the x87 control word, bit 1 = restore them
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn swap_context(
+    save: *mut Registers,
+    restore: *const Registers,
+    flags: u64,
+) {
+    naked_asm!(
+        // save callee-saved register
+        "mov %rbx, (%rcx)",
+        "mov %rbp, 8(%rcx)",
+        "mov %rdi, 16(%rcx)",
+        "mov %rsi, 24(%rcx)",
+        "mov %r12, 32(%rcx)",
+        "mov %r13, 40(%rcx)",
+        "mov %r14, 48(%rcx)",
+        "mov %r15, 56(%rcx)",
+        // resume at the return address, with the stack as it is after returning
+        "mov (%rsp), %rax",
+        "mov %rax, 64(%rcx)",
+        "lea 8(%rsp), %rax",
+        "mov %rax, 72(%rcx)",
+        // the stack base and limit of the thread information block
+        "mov %gs:8, %rax",
+        "mov %rax, 80(%rcx)",
+        "mov %gs:16, %rax",
+        "mov %rax, 88(%rcx)",
+        "test $1, %r8b",
+        "jz 1f",
+        "stmxcsr 96(%rcx)",
+        "fnstcw 100(%rcx)",
+        "movups %xmm6, 112(%rcx)",
+        "movups %xmm7, 128(%rcx)",
+        "movups %xmm8, 144(%rcx)",
+        "movups %xmm9, 160(%rcx)",
+        "movups %xmm10, 176(%rcx)",
+        "movups %xmm11, 192(%rcx)",
+        "movups %xmm12, 208(%rcx)",
+        "movups %xmm13, 224(%rcx)",
+        "movups %xmm14, 240(%rcx)",
+        "movups %xmm15, 256(%rcx)",
+        "1:",
+        // restore the next context
+        "mov %rdx, %rcx",
+        "mov %r8, %rdx",
+        "jmp {switch}",
+        switch = sym switch_context,
+        options(att_syntax),
+    )
+}
+
+// rdx: bit 1 = restore xmm6-xmm15, mxcsr and the x87 control word
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn switch_context(ctx: *const Registers, flags: u64) -> ! {
+    naked_asm!(
+        // restore callee-saved registers
+        "test $2, %dl",
+        "jz 1f",
+        "ldmxcsr 96(%rcx)",
+        "fldcw 100(%rcx)",
+        "movups 112(%rcx), %xmm6",
+        "movups 128(%rcx), %xmm7",
+        "movups 144(%rcx), %xmm8",
+        "movups 160(%rcx), %xmm9",
+        "movups 176(%rcx), %xmm10",
+        "movups 192(%rcx), %xmm11",
+        "movups 208(%rcx), %xmm12",
+        "movups 224(%rcx), %xmm13",
+        "movups 240(%rcx), %xmm14",
+        "movups 256(%rcx), %xmm15",
+        "1:",
+        "mov (%rcx), %rbx",
+        "mov 8(%rcx), %rbp",
+        "mov 16(%rcx), %rdi",
+        "mov 24(%rcx), %rsi",
+        "mov 32(%rcx), %r12",
+        "mov 40(%rcx), %r13",
+        "mov 48(%rcx), %r14",
+        "mov 56(%rcx), %r15",
+        "mov 80(%rcx), %rax",
+        "mov %rax, %gs:8",
+        "mov 88(%rcx), %rax",
+        "mov %rax, %gs:16",
+        "mov 72(%rcx), %rsp",
+        "jmp *64(%rcx)",
+        options(att_syntax),
+    )
+}
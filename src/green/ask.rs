@@ -0,0 +1,182 @@
+// Request and reply between green threads: `ask` sends a request through the typed
+// mailboxes and parks until the receiver answers it with `Request::reply`.
+
+use super::*;
+use std::any::Any;
+use std::marker::PhantomData;
+
+// A request sent by `ask` which has not been answered yet
+pub(super) struct PendingAsk {
+    asker: ThreadId,
+    reply: Option<Box<dyn Any>>,
+    // the request was dropped without a reply
+    dropped: bool,
+}
+
+/// Error returned by `ask` when no reply can come.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AskError {
+    /// the receiver is not a live thread, or ended before replying
+    NoReceiver,
+    /// the receiver dropped the request without replying
+    Dropped,
+    /// no reply arrived before the deadline of a `ServerRef::call`
+    Timeout,
+}
+
+impl std::fmt::Display for AskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AskError::NoReceiver => write!(f, "the receiver ended before replying"),
+            AskError::Dropped => write!(f, "the request was dropped without a reply"),
+            AskError::Timeout => write!(f, "no reply before the deadline"),
+        }
+    }
+}
+
+impl std::error::Error for AskError {}
+
+/// A request sent by `ask`, to be answered with a reply of type `R`.
+#[derive(Debug)]
+pub struct Request<R> {
+    msg: u64,
+    sender: ThreadId,
+    // the key of its `PendingAsk`
+    tag: u64,
+    _reply: PhantomData<fn(R)>,
+}
+
+/// Send `msg` to `key` and wait for its reply of type `R`, letting the other threads run.
+///
+/// The receiver takes the request with `recv_request::<R>` and answers it with
+/// `Request::reply`; if it ends or drops the request first, the error tells which.
+pub fn ask<R: 'static>(key: ThreadId, msg: u64) -> Result<R, AskError> {
+    ask_with(key, msg, |request| send_typed(key, request))
+}
+
+// the waiting of `ask` for the reply to a request which `send` delivers to `key`
+pub(super) fn ask_with<R: 'static, F: FnOnce(Request<R>)>(
+    key: ThreadId,
+    msg: u64,
+    send: F,
+) -> Result<R, AskError> {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "ask is called outside of green threads"
+        );
+        let asker = (*current_ctx()).id;
+        assert!(asker != key, "a green thread cannot ask itself");
+        let runtime = rt();
+        if !runtime.ids.contains(&key) {
+            return Err(AskError::NoReceiver);
+        }
+        let tag = runtime.next_ask;
+        runtime.next_ask += 1;
+        runtime.asks.insert(
+            tag,
+            PendingAsk {
+                asker,
+                reply: None,
+                dropped: false,
+            },
+        );
+        // forgets the request if a `timeout` abandons the wait
+        let _guard = AskGuard { tag, key, asker };
+        send(Request {
+            msg,
+            sender: asker,
+            tag,
+            _reply: PhantomData,
+        });
+        loop {
+            let runtime = rt();
+            let pending = runtime.asks.get_mut(&tag).unwrap();
+            if let Some(reply) = pending.reply.take() {
+                return Ok(*reply.downcast().unwrap());
+            }
+            if pending.dropped {
+                return Err(AskError::Dropped);
+            }
+            if !runtime.ids.contains(&key) {
+                return Err(AskError::NoReceiver);
+            }
+            // the end of the receiver wakes us as well as its reply
+            add_exit_waiter(key, asker);
+            wait();
+        }
+    }
+}
+
+// Removes the `PendingAsk` of a request, and the asker from the waiters of the receiver's end
+struct AskGuard {
+    tag: u64,
+    key: ThreadId,
+    asker: ThreadId,
+}
+
+impl Drop for AskGuard {
+    fn drop(&mut self) {
+        unsafe {
+            rt().asks.remove(&self.tag);
+            forget_exit_waiter(self.key, self.asker);
+        }
+    }
+}
+
+/// Receive the next request sent by `ask` expecting a reply of type `R`, waiting like
+/// `recv_typed` until one arrives; None outside of green threads.
+pub fn recv_request<R: 'static>() -> Option<Request<R>> {
+    recv_typed::<Request<R>>()
+}
+
+/// Take the next request expecting a reply of type `R` if one is there, without parking.
+pub fn try_recv_request<R: 'static>() -> Option<Request<R>> {
+    try_recv_typed::<Request<R>>()
+}
+
+impl<R: 'static> Request<R> {
+    pub fn msg(&self) -> u64 {
+        self.msg
+    }
+
+    /// The thread which asked, and waits for the reply.
+    pub fn sender(&self) -> ThreadId {
+        self.sender
+    }
+
+    /// Answer the request, waking the thread which asked;
+    /// the reply is given back if it stopped waiting for it.
+    pub fn reply(self, value: R) -> Result<(), R> {
+        unsafe {
+            let asker = match rt().asks.get_mut(&self.tag) {
+                Some(pending) => {
+                    pending.reply = Some(Box::new(value));
+                    pending.asker
+                }
+                None => return Err(value),
+            };
+            wake(Some(asker));
+        }
+        Ok(())
+    }
+}
+
+impl<R> Drop for Request<R> {
+    fn drop(&mut self) {
+        // the requests left in the mailboxes are dropped with the runtime
+        if runtime_ptr().is_null() {
+            return;
+        }
+        unsafe {
+            let asker = match rt().asks.get_mut(&self.tag) {
+                Some(pending) if pending.reply.is_none() => {
+                    pending.dropped = true;
+                    pending.asker
+                }
+                _ => return,
+            };
+            wake(Some(asker));
+        }
+    }
+}
@@ -0,0 +1,723 @@
+// Channels between the green threads of a runtime: queues owned by their two ends
+// rather than by a thread, so that the receiving end may be handed to any thread.
+
+use super::*;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+// The state shared by both ends of a channel
+struct Channel<T> {
+    // the values not received yet, queued by sender so that a busy sender does not
+    // starve the others; only the senders with values have a queue
+    queues: HashMap<u64, VecDeque<T>>,
+    // the senders with values, in the order they are served
+    ready: VecDeque<u64>,
+    next_sender: u64,
+    // the thread parked in `Receiver::recv`
+    waiter: Option<ThreadId>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+/// The sending end of a channel created by `channel`; clone it to feed one receiver
+/// from many threads.
+pub struct Sender<T> {
+    id: u64,
+    shared: Rc<RefCell<Channel<T>>>,
+}
+
+/// The receiving end of a channel created by `channel`; it may be moved to any
+/// green thread of the runtime, which then waits on it like in `recv`.
+pub struct Receiver<T> {
+    shared: Rc<RefCell<Channel<T>>>,
+}
+
+/// Create an unbounded channel between the green threads of the running runtime.
+///
+/// Unlike the mailboxes of `send` and `recv`, a channel belongs to no thread: a thread may
+/// receive from as many channels as it likes, and pass their receiving ends around.
+/// Both ends stay on the OS thread of the runtime; see `bridge` to reach other OS threads.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Rc::new(RefCell::new(Channel {
+        queues: HashMap::new(),
+        ready: VecDeque::new(),
+        next_sender: 1,
+        waiter: None,
+        senders: 1,
+        receiver_alive: true,
+    }));
+    (
+        Sender {
+            id: 0,
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Send a value, waking the receiving thread if it waits for one,
+    /// or give it back if the receiver is dropped.
+    ///
+    /// The receiver takes the values of the senders in turn, one value of each
+    /// sender with values at a time, and the values of each sender in order.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let waiter = {
+            let mut channel = self.shared.borrow_mut();
+            if !channel.receiver_alive {
+                return Err(value);
+            }
+            let queue = channel.queues.entry(self.id).or_default();
+            queue.push_back(value);
+            if queue.len() == 1 {
+                channel.ready.push_back(self.id);
+            }
+            // only the first value sent while the receiver waits wakes it
+            channel.waiter.take()
+        };
+        unsafe { wake(waiter) };
+        yield_now();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        let mut channel = self.shared.borrow_mut();
+        channel.senders += 1;
+        let id = channel.next_sender;
+        channel.next_sender += 1;
+        Sender {
+            id,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waiter = {
+            let mut channel = self.shared.borrow_mut();
+            channel.senders -= 1;
+            if channel.senders > 0 {
+                return;
+            }
+            channel.waiter.take()
+        };
+        // let the receiver see the disconnection
+        unsafe { wake(waiter) };
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive a value, waiting while the channel is empty and letting the other threads run;
+    /// None once the sender is dropped and every value is received.
+    pub fn recv(&self) -> Option<T> {
+        let id = current();
+        let _registered = Deregister(|| {
+            let mut channel = self.shared.borrow_mut();
+            if channel.waiter == Some(id) {
+                channel.waiter = None;
+            }
+        });
+        loop {
+            if let Some(value) = self.try_recv() {
+                return Some(value);
+            }
+            {
+                let mut channel = self.shared.borrow_mut();
+                if channel.senders == 0 {
+                    return None;
+                }
+                channel.waiter = Some(id);
+            }
+            // may also return on a message of the thread's mailbox, hence the loop
+            unsafe { wait() };
+        }
+    }
+
+    /// Take a value if one is there, without parking.
+    pub fn try_recv(&self) -> Option<T> {
+        let mut channel = self.shared.borrow_mut();
+        let sender = channel.ready.pop_front()?;
+        let queue = channel.queues.get_mut(&sender).unwrap();
+        let value = queue.pop_front();
+        if queue.is_empty() {
+            channel.queues.remove(&sender);
+        } else {
+            channel.ready.push_back(sender);
+        }
+        value
+    }
+
+    /// The number of values sent and not received yet.
+    pub fn len(&self) -> usize {
+        self.shared
+            .borrow()
+            .queues
+            .values()
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.borrow().ready.is_empty()
+    }
+
+    // for `Select`: a value, or None if the channel is closed; nothing if neither
+    pub(super) fn poll(&self) -> Option<Option<T>> {
+        match self.try_recv() {
+            Some(value) => Some(Some(value)),
+            None if self.shared.borrow().senders == 0 => Some(None),
+            None => None,
+        }
+    }
+
+    // for `Select`: the thread to wake on the next value, if any
+    pub(super) fn set_waiter(&self, waiter: Option<ThreadId>) {
+        self.shared.borrow_mut().waiter = waiter;
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let values = {
+            let mut channel = self.shared.borrow_mut();
+            channel.receiver_alive = false;
+            channel.ready.clear();
+            std::mem::take(&mut channel.queues)
+        };
+        // dropped once the channel is released, values may hold its senders
+        drop(values);
+    }
+}
+
+// The state shared by both ends of a oneshot channel
+struct Oneshot<T> {
+    value: Option<T>,
+    // the thread parked in `OneshotReceiver::recv`
+    waiter: Option<ThreadId>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+/// The sending end of a channel created by `oneshot`, consumed by sending.
+pub struct OneshotSender<T> {
+    shared: Rc<RefCell<Oneshot<T>>>,
+}
+
+/// The receiving end of a channel created by `oneshot`.
+pub struct OneshotReceiver<T> {
+    shared: Rc<RefCell<Oneshot<T>>>,
+}
+
+/// Create a channel for a single value, e.g. the reply of a request; cheaper than `channel`
+/// since the value is kept in a slot of its own instead of a queue.
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let shared = Rc::new(RefCell::new(Oneshot {
+        value: None,
+        waiter: None,
+        sender_alive: true,
+        receiver_alive: true,
+    }));
+    (
+        OneshotSender {
+            shared: shared.clone(),
+        },
+        OneshotReceiver { shared },
+    )
+}
+
+impl<T> OneshotSender<T> {
+    /// Send the value, waking the receiving thread if it waits for it,
+    /// or give it back if the receiver is dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let waiter = {
+            let mut oneshot = self.shared.borrow_mut();
+            if !oneshot.receiver_alive {
+                return Err(value);
+            }
+            oneshot.value = Some(value);
+            oneshot.waiter.take()
+        };
+        unsafe { wake(waiter) };
+        Ok(())
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        let waiter = {
+            let mut oneshot = self.shared.borrow_mut();
+            oneshot.sender_alive = false;
+            oneshot.waiter.take()
+        };
+        unsafe { wake(waiter) };
+    }
+}
+
+impl<T> OneshotReceiver<T> {
+    /// Receive the value, waiting until it is sent and letting the other threads run;
+    /// None if the sender is dropped without sending.
+    pub fn recv(self) -> Option<T> {
+        let id = current();
+        let _registered = Deregister(|| {
+            let mut oneshot = self.shared.borrow_mut();
+            if oneshot.waiter == Some(id) {
+                oneshot.waiter = None;
+            }
+        });
+        loop {
+            {
+                let mut oneshot = self.shared.borrow_mut();
+                if let Some(value) = oneshot.value.take() {
+                    return Some(value);
+                }
+                if !oneshot.sender_alive {
+                    return None;
+                }
+                oneshot.waiter = Some(id);
+            }
+            unsafe { wait() };
+        }
+    }
+
+    /// Take the value if it is sent, without parking.
+    pub fn try_recv(&self) -> Option<T> {
+        self.shared.borrow_mut().value.take()
+    }
+
+    // for `Select`: the value, or None if the sender is dropped without sending;
+    // nothing if neither
+    pub(super) fn poll(&self) -> Option<Option<T>> {
+        let mut oneshot = self.shared.borrow_mut();
+        match oneshot.value.take() {
+            Some(value) => Some(Some(value)),
+            None if !oneshot.sender_alive => Some(None),
+            None => None,
+        }
+    }
+
+    // for `Select`: the thread to wake on the value, if any
+    pub(super) fn set_waiter(&self, waiter: Option<ThreadId>) {
+        self.shared.borrow_mut().waiter = waiter;
+    }
+}
+
+impl<T> Drop for OneshotReceiver<T> {
+    fn drop(&mut self) {
+        let value = {
+            let mut oneshot = self.shared.borrow_mut();
+            oneshot.receiver_alive = false;
+            oneshot.value.take()
+        };
+        drop(value);
+    }
+}
+
+/// What a broadcast channel does when a receiver falls `capacity` values behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// drop the oldest value, the receivers which missed it get `BroadcastRecvError::Lagged`
+    Skip,
+    /// park the senders until the slowest receiver catches up
+    Park,
+}
+
+/// Error returned by `BroadcastReceiver::recv`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastRecvError {
+    /// the receiver missed this many values, it goes on with the oldest one kept
+    Lagged(u64),
+    /// every sender is dropped and every value is received
+    Closed,
+    /// no value is there yet, only returned by `try_recv`
+    Empty,
+}
+
+impl std::fmt::Display for BroadcastRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastRecvError::Lagged(missed) => write!(f, "receiver lagged by {} values", missed),
+            BroadcastRecvError::Closed => write!(f, "channel closed"),
+            BroadcastRecvError::Empty => write!(f, "channel empty"),
+        }
+    }
+}
+
+impl std::error::Error for BroadcastRecvError {}
+
+// The state shared by the ends of a broadcast channel
+struct Broadcast<T> {
+    // the values some receiver has not received yet, `values[0]` being number `first`
+    values: VecDeque<T>,
+    first: u64,
+    capacity: usize,
+    policy: LagPolicy,
+    // the number of the next value of every receiver
+    cursors: HashMap<u64, u64>,
+    next_receiver: u64,
+    senders: usize,
+    // the threads parked in `recv`, and in `send` under `LagPolicy::Park`
+    receivers_waiting: Vec<ThreadId>,
+    senders_waiting: Vec<ThreadId>,
+}
+
+impl<T> Broadcast<T> {
+    fn end(&self) -> u64 {
+        self.first + self.values.len() as u64
+    }
+
+    // drop the values every receiver has received, returns whether there were any
+    fn trim(&mut self) -> bool {
+        let end = self.end();
+        let oldest = self.cursors.values().copied().min().unwrap_or(end);
+        let trimmed = oldest > self.first;
+        while self.first < oldest {
+            self.values.pop_front();
+            self.first += 1;
+        }
+        trimmed
+    }
+
+    fn subscribe(&mut self) -> u64 {
+        let id = self.next_receiver;
+        self.next_receiver += 1;
+        let end = self.end();
+        self.cursors.insert(id, end);
+        id
+    }
+}
+
+/// The sending end of a broadcast channel created by `broadcast`.
+pub struct BroadcastSender<T> {
+    shared: Rc<RefCell<Broadcast<T>>>,
+}
+
+/// A receiving end of a broadcast channel, receiving every value sent after it subscribed.
+pub struct BroadcastReceiver<T> {
+    id: u64,
+    shared: Rc<RefCell<Broadcast<T>>>,
+}
+
+/// Create a channel delivering a copy of every value to each of its receivers; more
+/// receivers subscribe with `BroadcastSender::subscribe`.
+///
+/// Up to `capacity` values are kept for the slowest receiver, beyond which `policy` applies.
+pub fn broadcast<T: Clone>(
+    capacity: usize,
+    policy: LagPolicy,
+) -> (BroadcastSender<T>, BroadcastReceiver<T>) {
+    assert!(capacity > 0, "the capacity of a channel must be positive");
+    let mut channel = Broadcast {
+        values: VecDeque::with_capacity(capacity),
+        first: 0,
+        capacity,
+        policy,
+        cursors: HashMap::new(),
+        next_receiver: 0,
+        senders: 1,
+        receivers_waiting: Vec::new(),
+        senders_waiting: Vec::new(),
+    };
+    let id = channel.subscribe();
+    let shared = Rc::new(RefCell::new(channel));
+    (
+        BroadcastSender {
+            shared: shared.clone(),
+        },
+        BroadcastReceiver { id, shared },
+    )
+}
+
+impl<T: Clone> BroadcastSender<T> {
+    /// Send a value to every receiver and return how many there are,
+    /// or give it back if there are none.
+    pub fn send(&self, value: T) -> Result<usize, T> {
+        let id = current();
+        let _registered = Deregister(|| {
+            self.shared
+                .borrow_mut()
+                .senders_waiting
+                .retain(|&other| other != id)
+        });
+        loop {
+            let mut channel = self.shared.borrow_mut();
+            if channel.cursors.is_empty() {
+                return Err(value);
+            }
+            channel.trim();
+            if channel.values.len() < channel.capacity {
+                break;
+            }
+            if channel.policy == LagPolicy::Skip {
+                channel.values.pop_front();
+                channel.first += 1;
+                break;
+            }
+            if !channel.senders_waiting.contains(&id) {
+                channel.senders_waiting.push(id);
+            }
+            drop(channel);
+            unsafe { wait() };
+        }
+        let (receivers, waiters) = {
+            let mut channel = self.shared.borrow_mut();
+            channel.values.push_back(value);
+            let waiters = std::mem::take(&mut channel.receivers_waiting);
+            (channel.cursors.len(), waiters)
+        };
+        unsafe { wake(waiters) };
+        yield_now();
+        Ok(receivers)
+    }
+
+    /// A new receiver, receiving the values sent from now on.
+    pub fn subscribe(&self) -> BroadcastReceiver<T> {
+        let id = self.shared.borrow_mut().subscribe();
+        BroadcastReceiver {
+            id,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Clone for BroadcastSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.borrow_mut().senders += 1;
+        BroadcastSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BroadcastSender<T> {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut channel = self.shared.borrow_mut();
+            channel.senders -= 1;
+            if channel.senders > 0 {
+                return;
+            }
+            std::mem::take(&mut channel.receivers_waiting)
+        };
+        // let the receivers see the disconnection
+        unsafe { wake(waiters) };
+    }
+}
+
+impl<T: Clone> BroadcastReceiver<T> {
+    /// Receive the next value, waiting until one is sent and letting the other threads run.
+    pub fn recv(&self) -> Result<T, BroadcastRecvError> {
+        let id = current();
+        let _registered = Deregister(|| {
+            self.shared
+                .borrow_mut()
+                .receivers_waiting
+                .retain(|&other| other != id)
+        });
+        loop {
+            match self.try_recv() {
+                Err(BroadcastRecvError::Empty) => {}
+                result => return result,
+            }
+            {
+                let mut channel = self.shared.borrow_mut();
+                if !channel.receivers_waiting.contains(&id) {
+                    channel.receivers_waiting.push(id);
+                }
+            }
+            unsafe { wait() };
+        }
+    }
+
+    /// The number of values kept for this receiver and not received yet.
+    pub fn len(&self) -> usize {
+        let channel = self.shared.borrow();
+        let cursor = channel.cursors[&self.id].max(channel.first);
+        (channel.end() - cursor) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take the next value if it is sent, without parking.
+    pub fn try_recv(&self) -> Result<T, BroadcastRecvError> {
+        let (value, waiters) = {
+            let mut channel = self.shared.borrow_mut();
+            let first = channel.first;
+            let cursor = channel.cursors.get_mut(&self.id).unwrap();
+            if *cursor < first {
+                let missed = first - *cursor;
+                *cursor = first;
+                return Err(BroadcastRecvError::Lagged(missed));
+            }
+            let index = (*cursor - first) as usize;
+            let value = match channel.values.get(index) {
+                Some(value) => value.clone(),
+                None if channel.senders == 0 => return Err(BroadcastRecvError::Closed),
+                None => return Err(BroadcastRecvError::Empty),
+            };
+            *channel.cursors.get_mut(&self.id).unwrap() += 1;
+            // the slowest receiver makes room for the parked senders
+            let waiters = if channel.trim() {
+                std::mem::take(&mut channel.senders_waiting)
+            } else {
+                Vec::new()
+            };
+            (value, waiters)
+        };
+        unsafe { wake(waiters) };
+        Ok(value)
+    }
+}
+
+impl<T> Drop for BroadcastReceiver<T> {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut channel = self.shared.borrow_mut();
+            channel.cursors.remove(&self.id);
+            channel.trim();
+            std::mem::take(&mut channel.senders_waiting)
+        };
+        unsafe { wake(waiters) };
+    }
+}
+
+// The state shared by the ends of a watch channel
+struct Watch<T> {
+    value: T,
+    // bumped by every send
+    version: u64,
+    senders: usize,
+    // the threads parked in `WatchReceiver::changed`
+    waiters: Vec<ThreadId>,
+}
+
+/// The sending end of a watch channel created by `watch`.
+pub struct WatchSender<T> {
+    shared: Rc<RefCell<Watch<T>>>,
+}
+
+/// A receiving end of a watch channel; it remembers the last value it has seen.
+pub struct WatchReceiver<T> {
+    seen: u64,
+    shared: Rc<RefCell<Watch<T>>>,
+}
+
+/// Create a channel holding only the latest value sent, starting with `initial`;
+/// for configuration updates, shutdown flags and other state watched by many threads.
+pub fn watch<T: Clone>(initial: T) -> (WatchSender<T>, WatchReceiver<T>) {
+    let shared = Rc::new(RefCell::new(Watch {
+        value: initial,
+        version: 0,
+        senders: 1,
+        waiters: Vec::new(),
+    }));
+    (
+        WatchSender {
+            shared: shared.clone(),
+        },
+        WatchReceiver { seen: 0, shared },
+    )
+}
+
+impl<T: Clone> WatchSender<T> {
+    /// Replace the value, waking every receiver waiting for a change.
+    pub fn send(&self, value: T) {
+        let (old, waiters) = {
+            let mut watch = self.shared.borrow_mut();
+            watch.version += 1;
+            let old = std::mem::replace(&mut watch.value, value);
+            (old, std::mem::take(&mut watch.waiters))
+        };
+        // dropped once the channel is released, it may hold its ends
+        drop(old);
+        unsafe { wake(waiters) };
+        yield_now();
+    }
+
+    /// The current value.
+    pub fn get(&self) -> T {
+        self.shared.borrow().value.clone()
+    }
+
+    /// A new receiver, which has seen the current value.
+    pub fn subscribe(&self) -> WatchReceiver<T> {
+        WatchReceiver {
+            seen: self.shared.borrow().version,
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Clone for WatchSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.borrow_mut().senders += 1;
+        WatchSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for WatchSender<T> {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut watch = self.shared.borrow_mut();
+            watch.senders -= 1;
+            if watch.senders > 0 {
+                return;
+            }
+            std::mem::take(&mut watch.waiters)
+        };
+        // let the receivers see the disconnection
+        unsafe { wake(waiters) };
+    }
+}
+
+impl<T: Clone> WatchReceiver<T> {
+    /// Wait until the value differs from the last one seen by this receiver, letting the
+    /// other threads run, and return it; the values replaced meanwhile are skipped.
+    /// None once every sender is dropped and the last value is seen.
+    pub fn changed(&mut self) -> Option<T> {
+        let id = current();
+        let _registered = Deregister(|| {
+            self.shared
+                .borrow_mut()
+                .waiters
+                .retain(|&other| other != id)
+        });
+        loop {
+            {
+                let mut watch = self.shared.borrow_mut();
+                if watch.version != self.seen {
+                    self.seen = watch.version;
+                    return Some(watch.value.clone());
+                }
+                if watch.senders == 0 {
+                    return None;
+                }
+                if !watch.waiters.contains(&id) {
+                    watch.waiters.push(id);
+                }
+            }
+            unsafe { wait() };
+        }
+    }
+
+    /// The current value, without marking it as seen.
+    pub fn get(&self) -> T {
+        self.shared.borrow().value.clone()
+    }
+}
+
+impl<T> Clone for WatchReceiver<T> {
+    fn clone(&self) -> Self {
+        WatchReceiver {
+            seen: self.seen,
+            shared: self.shared.clone(),
+        }
+    }
+}
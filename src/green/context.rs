@@ -0,0 +1,238 @@
+// The context switch core: the stacks and their guard pages, and stackful coroutines
+// resumed directly by their caller. Always built; the switch itself is in `arch`.
+
+use super::arch::{
+    host_local, set_host_local, swap_context, switch_context, with_entry, Registers,
+    COROUTINE_SLOT, RESTORE_FP, SAVE_FP,
+};
+#[cfg(unix)]
+use nix::sys::mman::{mprotect, ProtFlags};
+use std::alloc::{alloc, dealloc, Layout};
+use std::ffi::c_void;
+use std::io;
+use std::ptr;
+use std::thread;
+
+pub(super) const PAGE_SIZE: usize = 4096;
+
+/// The smallest stack size `spawn` accepts, and `Coroutine::new` rounds up to: the guard page
+/// and three usable pages.
+pub const MIN_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+// make the lowest page of a stack inaccessible, so that overflowing it faults
+#[cfg(unix)]
+pub(super) unsafe fn protect_guard_page(stack: *mut u8) -> io::Result<()> {
+    mprotect(stack as *mut c_void, PAGE_SIZE, ProtFlags::PROT_NONE).map_err(io::Error::from)
+}
+
+// make the guard page accessible again before returning the stack to the allocator
+#[cfg(unix)]
+pub(super) unsafe fn unprotect_guard_page(stack: *mut u8) {
+    mprotect(
+        stack as *mut c_void,
+        PAGE_SIZE,
+        ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+    )
+    .unwrap();
+}
+
+#[cfg(windows)]
+const PAGE_NOACCESS: u32 = 0x01;
+#[cfg(windows)]
+const PAGE_READWRITE: u32 = 0x04;
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn VirtualProtect(addr: *mut c_void, size: usize, protect: u32, old: *mut u32) -> i32;
+}
+
+#[cfg(windows)]
+pub(super) unsafe fn protect_guard_page(stack: *mut u8) -> io::Result<()> {
+    let mut old = 0;
+    if VirtualProtect(stack as *mut c_void, PAGE_SIZE, PAGE_NOACCESS, &mut old) == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub(super) unsafe fn unprotect_guard_page(stack: *mut u8) {
+    let mut old = 0;
+    assert!(VirtualProtect(stack as *mut c_void, PAGE_SIZE, PAGE_READWRITE, &mut old) != 0);
+}
+
+#[cfg(feature = "scheduler")]
+pub(super) const CACHE_LINE_SIZE: usize = 64;
+
+/// Error returned when a coroutine cannot be created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoroutineError {
+    /// the stack size is too large to be laid out
+    InvalidStackSize(usize),
+    /// the stack could not be allocated, or its guard page protected
+    OutOfMemory,
+}
+
+impl std::fmt::Display for CoroutineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoroutineError::InvalidStackSize(size) => {
+                write!(f, "invalid stack size: {} bytes", size)
+            }
+            CoroutineError::OutOfMemory => write!(f, "cannot allocate the stack of a coroutine"),
+        }
+    }
+}
+
+impl std::error::Error for CoroutineError {}
+
+/// The result of resuming a coroutine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoroutineState<Y, R> {
+    /// the coroutine suspended itself with this value
+    Yielded(Y),
+    /// the coroutine returned this value
+    Complete(R),
+}
+
+pub(super) type CoroutineBody<Y, R, I> = Box<dyn FnOnce(&Yielder<Y, I>, I) -> R>;
+
+// Boxed so that the registers and slots keep their address while the coroutine is suspended
+pub(super) struct CoroutineInner<Y, R, I> {
+    regs: Registers,
+    // the registers of whoever called resume
+    caller: Registers,
+    body: Option<CoroutineBody<Y, R, I>>,
+    input: Option<I>,
+    yielded: Option<Y>,
+    result: Option<thread::Result<R>>,
+    stack: *mut u8,
+    stack_layout: Layout,
+}
+
+impl<Y, R, I> Drop for CoroutineInner<Y, R, I> {
+    fn drop(&mut self) {
+        unsafe {
+            unprotect_guard_page(self.stack);
+            dealloc(self.stack, self.stack_layout);
+        }
+    }
+}
+
+/// A stackful coroutine using the same context switch as the green threads,
+/// but explicitly resumed by its caller instead of being scheduled.
+///
+/// The body receives the first input and a `Yielder` to suspend itself with;
+/// each `resume` runs it until it yields or returns.
+/// Dropping a suspended coroutine frees its stack without unwinding the body.
+pub struct Coroutine<Y, R, I = ()> {
+    inner: Box<CoroutineInner<Y, R, I>>,
+    done: bool,
+}
+
+/// The handle a coroutine body uses to give values back to its caller.
+pub struct Yielder<Y, I> {
+    regs: *mut Registers,
+    caller: *const Registers,
+    yielded: *mut Option<Y>,
+    input: *mut Option<I>,
+}
+
+impl<Y, I> Yielder<Y, I> {
+    /// Give `value` to the caller of `resume`, and return the input of the next `resume`.
+    pub fn suspend(&self, value: Y) -> I {
+        unsafe {
+            *self.yielded = Some(value);
+            swap_context(self.regs, self.caller, SAVE_FP | RESTORE_FP);
+            (*self.input).take().unwrap()
+        }
+    }
+}
+
+// The coroutine being started is in COROUTINE_SLOT, read by `coroutine_entry` on its first resume
+
+pub(super) extern "C-unwind" fn coroutine_entry<Y, R, I>() {
+    unsafe {
+        let inner = host_local(COROUTINE_SLOT) as *mut CoroutineInner<Y, R, I>;
+        let body = (*inner).body.take().unwrap();
+        let input = (*inner).input.take().unwrap();
+        let yielder = Yielder {
+            regs: &mut (*inner).regs,
+            caller: &(*inner).caller,
+            yielded: &mut (*inner).yielded,
+            input: &mut (*inner).input,
+        };
+
+        // a panic cannot unwind past this frame, it is handed to the caller of resume instead
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| body(&yielder, input)));
+        (*inner).result = Some(result);
+
+        switch_context(&(*inner).caller, RESTORE_FP);
+    }
+}
+
+impl<Y, R, I> Coroutine<Y, R, I> {
+    /// Create a coroutine running `body` on a stack of `stack_size` bytes, rounded up to
+    /// `MIN_STACK_SIZE`; it starts on the first `resume`.
+    ///
+    /// Fails with `InvalidStackSize` if the stack cannot be laid out, and with `OutOfMemory`
+    /// if it cannot be allocated or its guard page protected.
+    pub fn new<F>(body: F, stack_size: usize) -> Result<Self, CoroutineError>
+    where
+        F: FnOnce(&Yielder<Y, I>, I) -> R + 'static,
+    {
+        let stack_size = stack_size.max(MIN_STACK_SIZE);
+        let layout = Layout::from_size_align(stack_size, PAGE_SIZE)
+            .map_err(|_| CoroutineError::InvalidStackSize(stack_size))?;
+        let stack = unsafe { alloc(layout) };
+        if stack.is_null() {
+            return Err(CoroutineError::OutOfMemory);
+        }
+
+        // the kernel may fail to split the mapping, which is running out of memory too
+        if unsafe { protect_guard_page(stack) }.is_err() {
+            unsafe { dealloc(stack, layout) };
+            return Err(CoroutineError::OutOfMemory);
+        }
+
+        let regs = with_entry(stack, stack_size, coroutine_entry::<Y, R, I>);
+
+        let inner = Box::new(CoroutineInner {
+            regs,
+            caller: with_entry(ptr::null_mut(), 0, coroutine_entry::<Y, R, I>),
+            body: Some(Box::new(body)),
+            input: None,
+            yielded: None,
+            result: None,
+            stack,
+            stack_layout: layout,
+        });
+        Ok(Coroutine { inner, done: false })
+    }
+
+    /// Run the coroutine until it yields or completes; a panic in the body is propagated.
+    pub fn resume(&mut self, input: I) -> CoroutineState<Y, R> {
+        assert!(!self.done, "resumed a completed coroutine");
+        let inner = &mut *self.inner as *mut CoroutineInner<Y, R, I>;
+        unsafe {
+            (*inner).input = Some(input);
+            set_host_local(COROUTINE_SLOT, inner as *mut ());
+            swap_context(&mut (*inner).caller, &(*inner).regs, SAVE_FP | RESTORE_FP);
+
+            if let Some(value) = (*inner).yielded.take() {
+                return CoroutineState::Yielded(value);
+            }
+            self.done = true;
+            match (*inner).result.take().unwrap() {
+                Ok(result) => CoroutineState::Complete(result),
+                Err(payload) => std::panic::resume_unwind(payload),
+            }
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.done
+    }
+}
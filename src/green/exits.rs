@@ -0,0 +1,206 @@
+// Links and monitors: what the end of a thread does to the threads watching it.
+
+use super::*;
+
+/// Link the calling thread with `id`: when either of them ends by panicking or being
+/// killed, the other is killed too. Returns false if `id` is not a live thread.
+pub fn link(id: ThreadId) -> bool {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "link is called outside of green threads"
+        );
+        let me = (*current_ctx()).id;
+        let runtime = rt();
+        if !runtime.ids.contains(&id) {
+            return false;
+        }
+        if id == me {
+            return true;
+        }
+        for (a, b) in [(me, id), (id, me)] {
+            let peers = runtime.linked.entry(a).or_default();
+            if !peers.contains(&b) {
+                peers.push(b);
+            }
+        }
+        true
+    }
+}
+
+/// Remove the link between the calling thread and `id`; false if there was none.
+pub fn unlink(id: ThreadId) -> bool {
+    unsafe {
+        if current_ctx().is_null() {
+            return false;
+        }
+        let me = (*current_ctx()).id;
+        forget_link(me, id) && forget_link(id, me)
+    }
+}
+
+/// The threads linked with `id`.
+pub fn links(id: ThreadId) -> Vec<ThreadId> {
+    if runtime_ptr().is_null() {
+        return Vec::new();
+    }
+    unsafe { rt().linked.get(&id).cloned().unwrap_or_default() }
+}
+
+// remove `peer` from the threads linked with `id`, returns whether it was there
+unsafe fn forget_link(id: ThreadId, peer: ThreadId) -> bool {
+    let runtime = rt();
+    let peers = match runtime.linked.get_mut(&id) {
+        Some(peers) => peers,
+        None => return false,
+    };
+    let len = peers.len();
+    peers.retain(|&linked| linked != peer);
+    let found = peers.len() < len;
+    if peers.is_empty() {
+        runtime.linked.remove(&id);
+    }
+    found
+}
+
+/// The message a thread trapping exits receives when a thread linked with it ends,
+/// taken with `recv_typed::<LinkExit>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkExit {
+    pub id: ThreadId,
+    pub reason: ExitStatus,
+}
+
+/// Make the calling thread trap exits, or stop trapping them: while it does, the end of a
+/// thread linked with it sends it a `LinkExit` instead of killing it, however the thread
+/// ended. Returns whether it trapped exits before.
+pub fn trap_exit(trap: bool) -> bool {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "trap_exit is called outside of green threads"
+        );
+        let me = (*current_ctx()).id;
+        let trapping = &mut rt().trapping;
+        if trap {
+            !trapping.insert(me)
+        } else {
+            trapping.remove(&me)
+        }
+    }
+}
+
+// break the links of an ending thread: the threads linked with it which trap exits are told,
+// the others killed unless it ended normally. They may switch, so the running thread must
+// still be in front of the queue
+pub(super) unsafe fn propagate_exit(id: ThreadId, status: &ExitStatus) {
+    let runtime = rt();
+    if runtime.linked.is_empty() && runtime.trapping.is_empty() {
+        return;
+    }
+    runtime.trapping.remove(&id);
+    for peer in rt().linked.remove(&id).unwrap_or_default() {
+        forget_link(peer, id);
+        if rt().trapping.contains(&peer) {
+            let reason = status.clone();
+            deliver_typed(peer, LinkExit { id, reason });
+        } else if *status != ExitStatus::Normal {
+            kill(peer);
+        }
+    }
+}
+
+/// What identifies a monitor set up by `monitor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MonitorRef {
+    key: u64,
+    target: ThreadId,
+}
+
+/// The message a monitor receives when the thread it monitors ends,
+/// taken with `recv_typed::<Down>()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Down {
+    pub monitor: MonitorRef,
+    pub id: ThreadId,
+    pub reason: ExitStatus,
+}
+
+/// Monitor `id` from the calling thread: a `Down` is sent to its typed mailbox when `id`
+/// ends, however it ends, and right away if it has already ended.
+///
+/// Unlike a link, the end of either thread does not end the other, and the thread
+/// monitored does not know about it.
+pub fn monitor(id: ThreadId) -> MonitorRef {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "monitor is called outside of green threads"
+        );
+        let me = (*current_ctx()).id;
+        let runtime = rt();
+        let monitor = MonitorRef {
+            key: runtime.next_monitor,
+            target: id,
+        };
+        runtime.next_monitor += 1;
+        if let Some(reason) = runtime.exits.get(&id) {
+            let reason = reason.clone();
+            deliver_typed(
+                me,
+                Down {
+                    monitor,
+                    id,
+                    reason,
+                },
+            );
+            return monitor;
+        }
+        assert!(
+            runtime.ids.contains(&id),
+            "no green thread has the id {}",
+            id
+        );
+        runtime.monitors.entry(id).or_default().push((monitor, me));
+        monitor
+    }
+}
+
+/// Remove a monitor, so that no `Down` is sent for it anymore; false if it was not set
+/// up or its `Down` is already sent.
+pub fn demonitor(monitor: MonitorRef) -> bool {
+    unsafe {
+        let runtime = rt();
+        let monitors = match runtime.monitors.get_mut(&monitor.target) {
+            Some(monitors) => monitors,
+            None => return false,
+        };
+        let len = monitors.len();
+        monitors.retain(|&(other, _)| other != monitor);
+        let found = monitors.len() < len;
+        if monitors.is_empty() {
+            runtime.monitors.remove(&monitor.target);
+        }
+        found
+    }
+}
+
+// send a `Down` to the live monitors of an ending thread, without switching
+pub(super) unsafe fn notify_monitors(id: ThreadId, reason: &ExitStatus) {
+    if rt().monitors.is_empty() {
+        return;
+    }
+    for (monitor, watcher) in rt().monitors.remove(&id).unwrap_or_default() {
+        if rt().ids.contains(&watcher) {
+            let reason = reason.clone();
+            deliver_typed(
+                watcher,
+                Down {
+                    monitor,
+                    id,
+                    reason,
+                },
+            );
+        }
+    }
+}
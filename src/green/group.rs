@@ -0,0 +1,118 @@
+// Process groups: sets of threads addressed together, to send them a message or kill them.
+
+use super::*;
+
+/// What identifies a group made by `create_group`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GroupId(u64);
+
+impl std::fmt::Display for GroupId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Make an empty group, which lasts as long as the runtime.
+pub fn create_group() -> GroupId {
+    unsafe {
+        let runtime = rt();
+        let gid = GroupId(runtime.next_group);
+        runtime.next_group += 1;
+        runtime.groups.insert(gid, Vec::new());
+        gid
+    }
+}
+
+/// Make the calling thread a member of `gid` until it leaves it or ends;
+/// false if it already is, or the group does not exist.
+pub fn join_group(gid: GroupId) -> bool {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "join_group is called outside of green threads"
+        );
+        let id = (*current_ctx()).id;
+        let runtime = rt();
+        let members = match runtime.groups.get_mut(&gid) {
+            Some(members) => members,
+            None => return false,
+        };
+        if members.contains(&id) {
+            return false;
+        }
+        members.push(id);
+        runtime.memberships.entry(id).or_default().push(gid);
+        true
+    }
+}
+
+/// Remove the calling thread from `gid`; false if it was not a member.
+pub fn leave_group(gid: GroupId) -> bool {
+    unsafe {
+        if current_ctx().is_null() {
+            return false;
+        }
+        let id = (*current_ctx()).id;
+        let runtime = rt();
+        let groups = match runtime.memberships.get_mut(&id) {
+            Some(groups) => groups,
+            None => return false,
+        };
+        let i = match groups.iter().position(|&group| group == gid) {
+            Some(i) => i,
+            None => return false,
+        };
+        groups.swap_remove(i);
+        if groups.is_empty() {
+            runtime.memberships.remove(&id);
+        }
+        if let Some(members) = runtime.groups.get_mut(&gid) {
+            members.retain(|&member| member != id);
+        }
+        true
+    }
+}
+
+/// The members of `gid`, in the order they joined it.
+pub fn group_members(gid: GroupId) -> Vec<ThreadId> {
+    unsafe { rt().groups.get(&gid).cloned().unwrap_or_default() }
+}
+
+/// Send `msg` to every member of `gid` like `send_all`, and return how many they are.
+pub fn group_send(gid: GroupId, msg: u64) -> usize {
+    let members = group_members(gid);
+    send_all(&members, msg);
+    members.len()
+}
+
+/// Kill every member of `gid`, see `kill`, and return how many were killed;
+/// if the calling thread is one of them, it is killed last and this does not return.
+pub fn group_kill(gid: GroupId) -> usize {
+    if current_ctx().is_null() {
+        return 0;
+    }
+    let members = group_members(gid);
+    let current = current();
+    let mut killed = 0;
+    for &id in members.iter().filter(|&&id| id != current) {
+        if kill(id) {
+            killed += 1;
+        }
+    }
+    if members.contains(&current) {
+        kill(current);
+    }
+    killed
+}
+
+// remove an ending thread from its groups
+pub(super) unsafe fn leave_groups(id: ThreadId) {
+    if rt().memberships.is_empty() {
+        return;
+    }
+    for gid in rt().memberships.remove(&id).unwrap_or_default() {
+        if let Some(members) = rt().groups.get_mut(&gid) {
+            members.retain(|&member| member != id);
+        }
+    }
+}
@@ -0,0 +1,1305 @@
+// The mailboxes of the green threads: lock-free queues, point-to-point links,
+// and the senders on other OS threads.
+
+use super::*;
+use std::any::{Any, TypeId};
+use std::cell::UnsafeCell;
+use std::collections::{HashMap, VecDeque};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+pub(super) struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    value: Option<T>,
+}
+
+// Vyukov's lock-free multi-producer single-consumer queue.
+// `push` is a single atomic swap on `head` and may be called by any number of producers,
+// `pop` only touches `tail` and must only be called by the owner of the mailbox.
+// The queue always holds one node whose value has already been taken (the stub).
+pub(super) struct MpscQueue<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: UnsafeCell<*mut Node<T>>,
+}
+
+unsafe impl<T: Send> Send for MpscQueue<T> {}
+unsafe impl<T: Send> Sync for MpscQueue<T> {}
+
+impl<T> MpscQueue<T> {
+    pub(super) fn new() -> Self {
+        let stub = Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value: None,
+        }));
+        MpscQueue {
+            head: AtomicPtr::new(stub),
+            tail: UnsafeCell::new(stub),
+        }
+    }
+    // `node` must be a node obtained by `Box::into_raw` holding a value
+    unsafe fn push(&self, node: *mut Node<T>) {
+        (*node).next.store(ptr::null_mut(), Ordering::Relaxed);
+        let prev = self.head.swap(node, Ordering::AcqRel);
+        (*prev).next.store(node, Ordering::Release);
+    }
+    // return the value and the node which is no longer used by the queue;
+    // may return None while a producer is in the middle of `push`
+    unsafe fn pop(&self) -> Option<(T, *mut Node<T>)> {
+        let tail = *self.tail.get();
+        let next = (*tail).next.load(Ordering::Acquire);
+        if next.is_null() {
+            return None;
+        }
+        *self.tail.get() = next;
+        let value = (*next).value.take().unwrap();
+        Some((value, tail))
+    }
+    // the value `pop` would return, without taking it; same restriction as `pop`
+    unsafe fn peek(&self) -> Option<&T> {
+        let next = (*(*self.tail.get())).next.load(Ordering::Acquire);
+        next.as_ref().and_then(|node| node.value.as_ref())
+    }
+    // visit the queued values in order without taking them; same restriction as `pop`
+    unsafe fn for_each<F: FnMut(&T)>(&self, mut f: F) {
+        let mut node = (*(*self.tail.get())).next.load(Ordering::Acquire);
+        while !node.is_null() {
+            f((*node).value.as_ref().unwrap());
+            node = (*node).next.load(Ordering::Acquire);
+        }
+    }
+}
+
+impl<T> Drop for MpscQueue<T> {
+    fn drop(&mut self) {
+        let mut node = *self.tail.get_mut();
+        while !node.is_null() {
+            unsafe {
+                let next = (*node).next.load(Ordering::Relaxed);
+                drop(Box::from_raw(node));
+                node = next;
+            }
+        }
+    }
+}
+
+// MPSC queues keyed by Thread ID;
+// the nodes are recycled on dequeue, so a steady flow of messages does not allocate
+pub(super) struct MappedList<T> {
+    map: HashMap<ThreadId, Arc<MpscQueue<T>>>,
+    // free nodes linked through `next`
+    free: *mut Node<T>,
+    // values sent with priority, taken before all the others
+    urgent: HashMap<ThreadId, VecDeque<T>>,
+    // values taken out of the queues but skipped by a selective receive, in order;
+    // they come before the values still queued
+    saved: HashMap<ThreadId, VecDeque<T>>,
+}
+
+// The values of a `MappedList` kept out of its queues, in the order they are taken
+#[derive(Clone, Copy)]
+enum Lane {
+    Urgent,
+    Saved,
+}
+
+const LANES: [Lane; 2] = [Lane::Urgent, Lane::Saved];
+
+impl<T> MappedList<T> {
+    pub(super) fn new() -> Self {
+        MappedList {
+            map: HashMap::new(),
+            free: ptr::null_mut(),
+            urgent: HashMap::new(),
+            saved: HashMap::new(),
+        }
+    }
+    fn alloc_node(&mut self, value: T) -> *mut Node<T> {
+        if self.free.is_null() {
+            return Box::into_raw(Box::new(Node {
+                next: AtomicPtr::new(ptr::null_mut()),
+                value: Some(value),
+            }));
+        }
+        unsafe {
+            let node = self.free;
+            self.free = (*node).next.load(Ordering::Relaxed);
+            (*node).value = Some(value);
+            node
+        }
+    }
+    pub(super) fn push_back(&mut self, id: ThreadId, value: T) {
+        let node = self.alloc_node(value);
+        let queue = self
+            .map
+            .entry(id)
+            .or_insert_with(|| Arc::new(MpscQueue::new()));
+        unsafe { queue.push(node) };
+    }
+    // the queue of `id`, to be shared with producers on other OS threads
+    fn queue(&mut self, id: ThreadId) -> Arc<MpscQueue<T>> {
+        self.map
+            .entry(id)
+            .or_insert_with(|| Arc::new(MpscQueue::new()))
+            .clone()
+    }
+    fn pop_front(&mut self, id: ThreadId) -> Option<T> {
+        let queue = self.map.get(&id)?;
+        let (value, node) = unsafe { queue.pop()? };
+
+        // put the node back to the free list
+        unsafe { (*node).next.store(self.free, Ordering::Relaxed) };
+        self.free = node;
+        Some(value)
+    }
+    fn lane(&self, lane: Lane) -> &HashMap<ThreadId, VecDeque<T>> {
+        match lane {
+            Lane::Urgent => &self.urgent,
+            Lane::Saved => &self.saved,
+        }
+    }
+    fn lane_mut(&mut self, lane: Lane) -> &mut HashMap<ThreadId, VecDeque<T>> {
+        match lane {
+            Lane::Urgent => &mut self.urgent,
+            Lane::Saved => &mut self.saved,
+        }
+    }
+    // add a value to a lane of `id`, after the ones already there
+    fn push_lane(&mut self, id: ThreadId, lane: Lane, value: T) {
+        self.lane_mut(lane).entry(id).or_default().push_back(value);
+    }
+    // take the next value of `id` kept out of its queue, from the urgent lane first
+    fn pop_lanes(&mut self, id: ThreadId) -> Option<T> {
+        for lane in LANES {
+            let values = match self.lane_mut(lane).get_mut(&id) {
+                Some(values) => values,
+                None => continue,
+            };
+            let value = values.pop_front();
+            if values.is_empty() {
+                self.lane_mut(lane).remove(&id);
+            }
+            return value;
+        }
+        None
+    }
+    // take the values of a lane of `id` out to be scanned, to be restored afterwards
+    fn take_lane(&mut self, id: ThreadId, lane: Lane) -> VecDeque<T> {
+        self.lane_mut(lane).remove(&id).unwrap_or_default()
+    }
+    fn restore_lane(&mut self, id: ThreadId, lane: Lane, values: VecDeque<T>) {
+        if !values.is_empty() {
+            self.lane_mut(lane).insert(id, values);
+        }
+    }
+}
+
+// A `MappedList` of `send_typed` whatever the type of its messages, for closing mailboxes
+pub(super) trait TypedList {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    // drop the messages of `id`
+    fn remove(&mut self, id: ThreadId);
+}
+
+impl<T: 'static> TypedList for MappedList<T> {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn remove(&mut self, id: ThreadId) {
+        self.map.remove(&id);
+        self.urgent.remove(&id);
+        self.saved.remove(&id);
+    }
+}
+
+impl<T> Drop for MappedList<T> {
+    fn drop(&mut self) {
+        while !self.free.is_null() {
+            unsafe {
+                let node = Box::from_raw(self.free);
+                self.free = node.next.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// A message of `send`, with the thread which sent it
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Envelope {
+    pub(super) sender: ThreadId,
+    pub(super) msg: u64,
+    // when the message goes stale, see `send_with_ttl`
+    pub(super) expires: Option<Instant>,
+}
+
+// Fixed-capacity FIFO used as the mailbox of a point-to-point link
+pub(super) struct RingBuffer<T> {
+    buf: Vec<Option<T>>,
+    head: usize,
+    len: usize,
+}
+
+impl<T> RingBuffer<T> {
+    fn with_capacity(capacity: usize) -> Self {
+        let mut buf = Vec::with_capacity(capacity);
+        buf.resize_with(capacity, || None);
+        RingBuffer {
+            buf,
+            head: 0,
+            len: 0,
+        }
+    }
+    // give the value back if the buffer is full
+    fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == self.buf.len() {
+            return Err(value);
+        }
+        let tail = (self.head + self.len) % self.buf.len();
+        self.buf[tail] = Some(value);
+        self.len += 1;
+        Ok(())
+    }
+    fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.buf[self.head].take();
+        self.head = (self.head + 1) % self.buf.len();
+        self.len -= 1;
+        value
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).map(move |i| self.buf[(self.head + i) % self.buf.len()].as_ref().unwrap())
+    }
+}
+
+// A link from exactly one sender to one receiver, whose messages bypass `Runtime::messages`
+pub(super) struct Link {
+    sender: ThreadId,
+    ring: RingBuffer<u64>,
+    // set when the ring was full and the sender fell back to `messages`;
+    // the sender keeps using `messages` until both are drained, to preserve its order
+    overflowed: bool,
+}
+
+// The capacity of a bounded mailbox, and the senders parked until it has room
+pub(super) struct Bound {
+    capacity: usize,
+    policy: OverflowPolicy,
+    // the messages queued, those of remote senders once `count_remote_arrivals` has seen them
+    len: usize,
+    senders: VecDeque<ThreadId>,
+}
+
+// Wakeups coming from other OS threads
+pub(super) struct Remote {
+    // Thread IDs to move from `waiting` to `contexts`
+    pub(super) wakeups: MpscQueue<ThreadId>,
+    // set when `wakeups` may be non-empty, so that polling is a single load
+    pub(super) pending: AtomicBool,
+    // the number of live RemoteSenders, while it is non-zero running out of threads is not a deadlock
+    pub(super) senders: AtomicUsize,
+    // the OS thread parked by the idle scheduler; the one the runtime started on, except under
+    // the `software` backend, where each green thread has an OS thread of its own
+    pub(super) thread: Mutex<Thread>,
+}
+
+impl Remote {
+    pub(super) fn wake(&self, id: ThreadId) {
+        let node = Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value: Some(id),
+        }));
+        unsafe { self.wakeups.push(node) };
+        self.pending.store(true, Ordering::Release);
+        self.unpark();
+    }
+
+    // let the idle scheduler look at its wakeups again
+    pub(super) fn unpark(&self) {
+        self.thread.lock().unwrap().unpark();
+    }
+
+    // record the calling OS thread as the one to unpark, before it looks at the wakeups and
+    // parks; a wakeup pushed after the record unparks it, one pushed before is seen
+    pub(super) fn parks_here(&self) {
+        let mut thread = self.thread.lock().unwrap();
+        if thread.id() != thread::current().id() {
+            *thread = thread::current();
+        }
+    }
+}
+
+/// A handle to send messages to a green thread from any OS thread.
+pub struct RemoteSender {
+    key: ThreadId,
+    // the thread which made the handle, the sender of its messages
+    sender: ThreadId,
+    queue: Arc<MpscQueue<Envelope>>,
+    // counted before the message is queued, so that a receiver taking it has counted it
+    arrived: Arc<AtomicUsize>,
+    remote: Arc<Remote>,
+}
+
+impl RemoteSender {
+    pub fn send(&self, msg: u64) {
+        self.arrived.fetch_add(1, Ordering::Release);
+        let node = Box::into_raw(Box::new(Node {
+            next: AtomicPtr::new(ptr::null_mut()),
+            value: Some(Envelope {
+                sender: self.sender,
+                msg,
+                expires: None,
+            }),
+        }));
+        unsafe { self.queue.push(node) };
+        self.remote.wake(self.key);
+    }
+}
+
+impl Clone for RemoteSender {
+    fn clone(&self) -> Self {
+        self.remote.senders.fetch_add(1, Ordering::Relaxed);
+        RemoteSender {
+            key: self.key,
+            sender: self.sender,
+            queue: self.queue.clone(),
+            arrived: self.arrived.clone(),
+            remote: self.remote.clone(),
+        }
+    }
+}
+
+impl Drop for RemoteSender {
+    fn drop(&mut self) {
+        self.remote.senders.fetch_sub(1, Ordering::Release);
+        // let an idle scheduler notice that it may be dead-locked now
+        self.remote.unpark();
+    }
+}
+
+/// Create a handle which lets other OS threads send messages to `key`, on behalf
+/// of the calling thread.
+///
+/// While any RemoteSender is alive, a green thread waiting in `recv` with nothing else to run
+/// is not a deadlock: the scheduler spins for a while, then parks the OS thread until a message arrives.
+pub fn remote_sender(key: ThreadId) -> RemoteSender {
+    unsafe {
+        let remote = remote();
+        remote.senders.fetch_add(1, Ordering::Relaxed);
+        RemoteSender {
+            key,
+            sender: current(),
+            queue: rt().messages.queue(key),
+            arrived: rt().remote_arrivals.entry(key).or_default().clone(),
+            remote,
+        }
+    }
+}
+
+pub(super) unsafe fn remote() -> Arc<Remote> {
+    rt().remote.clone()
+}
+
+// move the threads woken by other OS threads, or by their timers, to the execution queue
+pub(super) unsafe fn poll_remote() {
+    expire_timers();
+    let runtime = rt();
+    if !runtime.remote.pending.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    while let Some((id, node)) = runtime.remote.wakeups.pop() {
+        drop(Box::from_raw(node));
+        if let Some(ctx) = runtime.waiting.remove(&id) {
+            runtime.contexts.push_back(ctx);
+        }
+    }
+}
+
+// add the messages RemoteSenders queued for `key` to its bound
+unsafe fn count_remote_arrivals(key: ThreadId, bound: &mut Bound) {
+    if let Some(arrived) = rt().remote_arrivals.get(&key) {
+        bound.len += arrived.swap(0, Ordering::Acquire);
+    }
+}
+
+pub(super) unsafe fn has_remote_senders() -> bool {
+    // messages delayed by a simulation, and timers, count as senders that will wake their
+    // receivers
+    rt().remote.senders.load(Ordering::Acquire) > 0
+        || model::has_delayed()
+        || !rt().timers.is_empty()
+}
+
+/// Open a point-to-point link from the calling thread to `key`.
+///
+/// Messages the calling thread sends to `key` then go through a ring buffer of `capacity`
+/// messages instead of the general message queue, falling back to it when the ring is full.
+/// Messages of the link are received before the ones of other senders.
+/// Returns false if `key` already has a link from another live thread.
+pub fn connect(key: ThreadId, capacity: usize) -> bool {
+    assert!(capacity > 0, "the capacity of a link must be positive");
+    unsafe {
+        let runtime = rt();
+        let sender = (*current_ctx()).id;
+        if let Some(link) = runtime.links.get(&key) {
+            let in_use = !link.ring.is_empty() || link.overflowed;
+            if link.sender != sender && (runtime.ids.contains(&link.sender) || in_use) {
+                return false;
+            }
+            if in_use {
+                // reconnecting from the same sender keeps the pending messages
+                return true;
+            }
+        }
+        let link = Link {
+            sender,
+            ring: RingBuffer::with_capacity(capacity),
+            overflowed: false,
+        };
+        runtime.links.insert(key, link);
+        true
+    }
+}
+
+/// Send `msg` to `key`; if its mailbox is bounded and full, its `OverflowPolicy` applies.
+pub fn send(key: ThreadId, msg: u64) {
+    unsafe {
+        if make_room(key) {
+            deliver(key, msg, None);
+        }
+    }
+    yield_now();
+}
+
+/// Send `msg` to `key` like `send`, to be received within `ttl`; once it is stale,
+/// the receiver does not get it, and it goes to the dead letters instead.
+pub fn send_with_ttl(key: ThreadId, msg: u64, ttl: Duration) {
+    unsafe {
+        if make_room(key) {
+            deliver(key, msg, Some(Instant::now() + ttl));
+        }
+    }
+    yield_now();
+}
+
+/// Send `msg` to `key` ahead of the messages already in its mailbox, and of the ones
+/// sent later with `send`; for control messages (shutdown, reconfiguration) which should
+/// not wait behind a backlog. Messages sent with priority are received in the order sent,
+/// and never held back by a full mailbox.
+pub fn send_priority(key: ThreadId, msg: u64) {
+    unsafe {
+        let envelope = Envelope {
+            sender: current(),
+            msg,
+            expires: None,
+        };
+        let runtime = rt();
+        if !runtime.ids.contains(&key) {
+            dead_letter(key, envelope, DeadLetterReason::NoReceiver);
+            return;
+        }
+        runtime.report.delivered += 1;
+        if let Some(bound) = runtime.bounds.get_mut(&key) {
+            bound.len += 1;
+        }
+        runtime.messages.push_lane(key, Lane::Urgent, envelope);
+        wake(Some(key));
+    }
+    yield_now();
+}
+
+/// Send `msg` to every thread in `keys`, waking all of them before switching only once.
+pub fn send_all(keys: &[ThreadId], msg: u64) {
+    unsafe {
+        for &key in keys {
+            if make_room(key) {
+                deliver(key, msg, None);
+            }
+        }
+    }
+    yield_now();
+}
+
+/// The error of `try_send` when the mailbox is full, giving the message back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full(pub u64);
+
+impl std::fmt::Display for Full {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mailbox full")
+    }
+}
+
+impl std::error::Error for Full {}
+
+/// Send `msg` to `key` like `send`, or give it back if its mailbox is bounded and full,
+/// whatever the policy of the mailbox.
+pub fn try_send(key: ThreadId, msg: u64) -> Result<(), Full> {
+    unsafe {
+        let full = rt().bounds.get_mut(&key).is_some_and(|bound| {
+            count_remote_arrivals(key, bound);
+            bound.len >= bound.capacity
+        });
+        if full {
+            return Err(Full(msg));
+        }
+        deliver(key, msg, None);
+    }
+    yield_now();
+    Ok(())
+}
+
+/// What `send` does when the bounded mailbox of the receiver is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// park the sender until the receiver takes a message
+    Block,
+    /// drop the message being sent
+    DropNewest,
+    /// drop the oldest message of the mailbox to make room
+    DropOldest,
+}
+
+/// Bound the mailbox of the calling thread to `capacity` messages, or remove the bound
+/// with None; `policy` says what sending to the full mailbox does, and dropped messages
+/// are counted in `RunReport::dropped`.
+///
+/// Messages of `RemoteSender`s are never held back, but take room in the mailbox.
+pub fn set_mailbox_capacity(capacity: Option<usize>, policy: OverflowPolicy) {
+    assert!(
+        capacity != Some(0),
+        "the capacity of a mailbox must be positive"
+    );
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "set_mailbox_capacity is called outside of green threads"
+        );
+        let key = (*current_ctx()).id;
+        let runtime = rt();
+        match capacity {
+            Some(capacity) => {
+                let len = count_messages(runtime, key);
+                if !runtime.bounds.contains_key(&key) {
+                    // the messages of remote senders queued so far are counted with the others
+                    if let Some(arrived) = runtime.remote_arrivals.get(&key) {
+                        arrived.store(0, Ordering::Relaxed);
+                    }
+                }
+                let bound = runtime.bounds.entry(key).or_insert(Bound {
+                    capacity,
+                    policy,
+                    len,
+                    senders: VecDeque::new(),
+                });
+                bound.capacity = capacity;
+                bound.policy = policy;
+                // senders parked on the previous policy send again
+                if policy != OverflowPolicy::Block {
+                    wake(std::mem::take(&mut bound.senders));
+                }
+            }
+            None => {
+                if let Some(bound) = runtime.bounds.remove(&key) {
+                    wake(bound.senders);
+                }
+            }
+        }
+    }
+}
+
+/// The number of messages waiting in the mailbox of `id` to be received by `recv`,
+/// the ones of its link and of remote senders included; 0 outside of a runtime.
+pub fn mailbox_len(id: ThreadId) -> usize {
+    if runtime_ptr().is_null() {
+        return 0;
+    }
+    unsafe { count_messages(rt(), id) }
+}
+
+pub fn mailbox_is_empty(id: ThreadId) -> bool {
+    mailbox_len(id) == 0
+}
+
+/// The capacity of the mailbox of `id` set by `set_mailbox_capacity`, None if it is unbounded.
+pub fn mailbox_capacity(id: ThreadId) -> Option<usize> {
+    if runtime_ptr().is_null() {
+        return None;
+    }
+    unsafe { rt().bounds.get(&id).map(|bound| bound.capacity) }
+}
+
+/// Spawn a thread like `spawn`, whose mailbox is bounded to `capacity` messages
+/// from its start; see `set_mailbox_capacity`.
+pub fn spawn_bounded<F: FnOnce() + 'static>(
+    func: F,
+    stack_size: usize,
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> Result<ThreadId, SpawnError> {
+    assert!(capacity > 0, "the capacity of a mailbox must be positive");
+    spawn(
+        move || {
+            set_mailbox_capacity(Some(capacity), policy);
+            func()
+        },
+        stack_size,
+    )
+}
+
+// make room for a message in the mailbox of `key` if it is full, as its policy says;
+// false if the message is to be dropped. A thread sending to itself is never parked,
+// nothing would wake it
+unsafe fn make_room(key: ThreadId) -> bool {
+    if rt().bounds.is_empty() {
+        return true;
+    }
+    let sender = (*current_ctx()).id;
+    let _registered = Deregister(|| {
+        if let Some(bound) = rt().bounds.get_mut(&key) {
+            bound.senders.retain(|&id| id != sender);
+        }
+    });
+    loop {
+        let bound = match rt().bounds.get_mut(&key) {
+            Some(bound) => {
+                count_remote_arrivals(key, bound);
+                bound
+            }
+            None => return true,
+        };
+        if bound.len < bound.capacity {
+            return true;
+        }
+        match bound.policy {
+            OverflowPolicy::Block if key == sender => return true,
+            OverflowPolicy::Block => {
+                if !bound.senders.contains(&sender) {
+                    bound.senders.push_back(sender);
+                }
+                wait();
+            }
+            OverflowPolicy::DropNewest => {
+                rt().report.dropped += 1;
+                return false;
+            }
+            OverflowPolicy::DropOldest => {
+                rt().report.dropped += 1;
+                pop_message(key);
+                return true;
+            }
+        }
+    }
+}
+
+// make the waiting threads among `ids` executable, without switching
+pub(super) unsafe fn wake(ids: impl IntoIterator<Item = ThreadId>) {
+    for id in ids {
+        let runtime = rt();
+        if let Some(ctx) = runtime.waiting.remove(&id) {
+            runtime.contexts.push_back(ctx);
+        }
+    }
+}
+
+// forget the mailbox of an ending thread, and wake the senders parked on it;
+// the messages it did not receive go to the dead letters
+pub(super) unsafe fn close_mailbox(key: ThreadId) {
+    while let Some(envelope) = take_message(key) {
+        dead_letter(key, envelope, DeadLetterReason::NoReceiver);
+    }
+    let runtime = rt();
+    // dropped with the last RemoteSender still holding it
+    runtime.messages.map.remove(&key);
+    runtime.links.remove(&key);
+    runtime.remote_arrivals.remove(&key);
+    for typed in runtime.typed.values_mut() {
+        typed.remove(key);
+    }
+    // they send again, or find the receiver gone
+    if let Some(bound) = runtime.bounds.remove(&key) {
+        wake(bound.senders);
+    }
+}
+
+// queue the message and make the receiver executable, without switching
+pub(super) unsafe fn deliver(key: ThreadId, msg: u64, expires: Option<Instant>) {
+    if model::intercept(key, msg) {
+        return;
+    }
+    let sender = (*current_ctx()).id;
+    let envelope = Envelope {
+        sender,
+        msg,
+        expires,
+    };
+    let runtime = rt();
+    let link = runtime.links.get_mut(&key);
+    // only a live thread has a link
+    if link.is_none() && !runtime.ids.contains(&key) {
+        dead_letter(key, envelope, DeadLetterReason::NoReceiver);
+        return;
+    }
+    runtime.report.delivered += 1;
+    if let Some(bound) = runtime.bounds.get_mut(&key) {
+        bound.len += 1;
+    }
+    match link {
+        Some(link) if link.sender == sender && !link.overflowed => {
+            // the ring keeps no TTL, such a message goes to the queue as on an overflow
+            if expires.is_some() || link.ring.push(msg).is_err() {
+                link.overflowed = true;
+                runtime.messages.push_back(key, envelope);
+            }
+        }
+        _ => runtime.messages.push_back(key, envelope),
+    }
+    if let Some(ctx) = runtime.waiting.remove(&key) {
+        runtime.contexts.push_back(ctx);
+    }
+}
+
+pub fn recv() -> Option<u64> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        let key = (*current_ctx()).id;
+        loop {
+            poll_remote();
+            if let Some(msg) = pop_message(key) {
+                return Some(msg);
+            }
+            wait();
+        }
+    }
+}
+
+/// Take every message waiting in the mailbox of the calling thread, in order, e.g. to throw
+/// away an obsolete backlog; empty outside of green threads.
+pub fn drain_mailbox() -> Vec<u64> {
+    let mut msgs = Vec::new();
+    unsafe {
+        if current_ctx().is_null() {
+            return msgs;
+        }
+        let key = (*current_ctx()).id;
+        poll_remote();
+        while let Some(msg) = pop_message(key) {
+            msgs.push(msg);
+        }
+    }
+    msgs
+}
+
+/// The message `recv` would return next, left in the mailbox; None if the mailbox is
+/// empty or outside of green threads. The messages which went stale are skipped.
+pub fn peek() -> Option<u64> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        let key = (*current_ctx()).id;
+        poll_remote();
+        // the stale messages in front go to the dead letters, as `recv` would send them;
+        // `expired` takes the runtime itself, so it is fetched again after each call
+        loop {
+            let front = LANES.iter().find_map(|&lane| {
+                let values = rt().messages.lane(lane).get(&key)?;
+                values.front().copied()
+            });
+            match front {
+                Some(envelope) if expired(key, &envelope) => {
+                    rt().messages.pop_lanes(key);
+                }
+                Some(envelope) => return Some(envelope.msg),
+                None => break,
+            }
+        }
+        if let Some(&msg) = rt()
+            .links
+            .get(&key)
+            .and_then(|link| link.ring.iter().next())
+        {
+            return Some(msg);
+        }
+        loop {
+            let envelope = *rt().messages.map.get(&key)?.peek()?;
+            if !expired(key, &envelope) {
+                return Some(envelope.msg);
+            }
+            rt().messages.pop_front(key);
+        }
+    }
+}
+
+/// Receive up to `max` messages at once: wait like `recv` for the first one, then take
+/// the ones already there without parking; empty outside of green threads.
+pub fn recv_many(max: usize) -> Vec<u64> {
+    let mut msgs = Vec::new();
+    if max == 0 {
+        return msgs;
+    }
+    match recv() {
+        Some(msg) => msgs.push(msg),
+        None => return msgs,
+    }
+    unsafe {
+        let key = (*current_ctx()).id;
+        while msgs.len() < max {
+            match pop_message(key) {
+                Some(msg) => msgs.push(msg),
+                None => break,
+            }
+        }
+    }
+    msgs
+}
+
+/// Take the next message of the calling thread if one is there, without parking;
+/// None if the mailbox is empty or outside of green threads.
+pub fn try_recv() -> Option<u64> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        poll_remote();
+        pop_message((*current_ctx()).id)
+    }
+}
+
+/// Error returned by `recv_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    /// no message arrived in time
+    Timeout,
+    /// called outside of green threads, which have no mailbox
+    NoMailbox,
+}
+
+impl std::fmt::Display for RecvTimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecvTimeoutError::Timeout => write!(f, "no message arrived in time"),
+            RecvTimeoutError::NoMailbox => {
+                write!(f, "recv_timeout is called outside of green threads")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecvTimeoutError {}
+
+/// Receive a message like `recv`, or give up once `timeout` has elapsed; the thread is
+/// parked meanwhile, and woken by its timer if no message arrives.
+pub fn recv_timeout(timeout: Duration) -> Result<u64, RecvTimeoutError> {
+    unsafe {
+        if current_ctx().is_null() {
+            return Err(RecvTimeoutError::NoMailbox);
+        }
+        let key = (*current_ctx()).id;
+        let deadline = Instant::now() + timeout;
+        let timer = std::cell::Cell::new(None);
+        let _registered = Deregister(|| {
+            if let Some(timer) = timer.take() {
+                rt().timers.cancel(timer);
+            }
+        });
+        loop {
+            poll_remote();
+            if let Some(msg) = pop_message(key) {
+                return Ok(msg);
+            }
+            if Instant::now() >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            // registered once the mailbox is found empty, so a ready message costs no timer
+            if timer.get().is_none() {
+                timer.set(Some(rt().timers.insert(deadline, key)));
+            }
+            wait();
+        }
+    }
+}
+
+// the mailboxes of the messages of type `T`, made on the first message of that type
+unsafe fn typed_messages<T: 'static>(runtime: &mut Runtime) -> &mut MappedList<T> {
+    runtime
+        .typed
+        .entry(TypeId::of::<T>())
+        .or_insert_with(|| Box::new(MappedList::<T>::new()))
+        .as_any_mut()
+        .downcast_mut()
+        .unwrap()
+}
+
+/// Send `msg` of any type to `key`, to be received with `recv_typed::<T>`.
+///
+/// Every type of message has a mailbox of its own next to the one of `send` and `recv`,
+/// so a thread only receives the types it asks for, each of them in the order sent.
+/// Typed messages to a thread which has ended are dropped, and counted in
+/// `RunReport::dropped`; they cannot be dead letters, which are `u64` messages.
+pub fn send_typed<T: Send + 'static>(key: ThreadId, msg: T) {
+    unsafe { deliver_typed(key, msg) };
+    yield_now();
+}
+
+// queue a typed message and make the receiver executable, without switching;
+// dropped if the receiver has ended
+pub(super) unsafe fn deliver_typed<T: 'static>(key: ThreadId, msg: T) {
+    let runtime = rt();
+    if !runtime.ids.contains(&key) {
+        runtime.report.dropped += 1;
+        return;
+    }
+    runtime.report.delivered += 1;
+    typed_messages(runtime).push_back(key, msg);
+    if let Some(ctx) = runtime.waiting.remove(&key) {
+        runtime.contexts.push_back(ctx);
+    }
+}
+
+/// Receive the next message of type `T` sent by `send_typed`, waiting like `recv`
+/// until one arrives; None outside of green threads.
+pub fn recv_typed<T: Send + 'static>() -> Option<T> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        let key = (*current_ctx()).id;
+        loop {
+            poll_remote();
+            if let Some(msg) = typed_messages(rt()).pop_front(key) {
+                return Some(msg);
+            }
+            wait();
+        }
+    }
+}
+
+/// Take the next message of type `T` if one is there, without parking;
+/// None if there is none or outside of green threads.
+pub fn try_recv_typed<T: Send + 'static>() -> Option<T> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        poll_remote();
+        typed_messages(rt()).pop_front((*current_ctx()).id)
+    }
+}
+
+/// A message of any type, for the mailboxes of `send_any`.
+pub type AnyMessage = Box<dyn Any + Send>;
+
+/// Send a message of any type to `key`, to be received with `recv_any` or `recv_downcast`.
+///
+/// Unlike `send_typed`, the messages of every type share one mailbox, received in the
+/// order sent, so a thread may take the next one without knowing its type.
+pub fn send_any(key: ThreadId, msg: AnyMessage) {
+    send_typed(key, msg)
+}
+
+/// Receive the next message sent by `send_any`, waiting until one arrives;
+/// None outside of green threads.
+pub fn recv_any() -> Option<AnyMessage> {
+    recv_typed::<AnyMessage>()
+}
+
+/// Receive the next message sent by `send_any` like `recv_any`, as a `T`;
+/// a message of another type is given back as the error. None outside of green threads.
+pub fn recv_downcast<T: 'static>() -> Option<Result<T, AnyMessage>> {
+    let msg = recv_any()?;
+    Some(msg.downcast().map(|msg| *msg))
+}
+
+/// Receive the first message for which `pred` is true, waiting like `recv` until one
+/// arrives; the others stay in the mailbox, in order, for the next receives.
+/// None outside of green threads.
+pub fn recv_matching<F: FnMut(u64) -> bool>(mut pred: F) -> Option<u64> {
+    recv_selected(|_, msg| pred(msg)).map(|envelope| envelope.msg)
+}
+
+/// Receive a message like `recv`, with the thread which sent it; the messages of a
+/// `RemoteSender` come from the thread which made it.
+pub fn recv_with_sender() -> Option<(ThreadId, u64)> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        let key = (*current_ctx()).id;
+        loop {
+            poll_remote();
+            if let Some(envelope) = pop_with_sender(key) {
+                return Some((envelope.sender, envelope.msg));
+            }
+            wait();
+        }
+    }
+}
+
+/// Receive the next message sent by `sender`, waiting until one arrives; the messages of
+/// other threads stay in the mailbox, as with `recv_matching`.
+pub fn recv_from(sender: ThreadId) -> Option<u64> {
+    recv_selected(|from, _| from == sender).map(|envelope| envelope.msg)
+}
+
+// the selective receive of `recv_matching` and `recv_from`
+fn recv_selected<F: FnMut(ThreadId, u64) -> bool>(mut pred: F) -> Option<Envelope> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        let key = (*current_ctx()).id;
+        // the messages skipped before are looked at once; the ones sent with priority again
+        // after each wait, from the first one not looked at yet
+        if let Ok(envelope) = select_in_lane(key, Lane::Saved, 0, &mut pred) {
+            return Some(envelope);
+        }
+        let mut seen = 0;
+        loop {
+            poll_remote();
+            match select_in_lane(key, Lane::Urgent, seen, &mut pred) {
+                Ok(envelope) => return Some(envelope),
+                Err(len) => seen = len,
+            }
+            while let Some(envelope) = take_queued(key) {
+                if expired(key, &envelope) {
+                    continue;
+                }
+                if pred(envelope.sender, envelope.msg) {
+                    make_room_after_receive(key);
+                    return Some(envelope);
+                }
+                rt().messages.push_lane(key, Lane::Saved, envelope);
+            }
+            wait();
+        }
+    }
+}
+
+// take the first message of a lane of `key` from `from` on for which `pred` is true, or give
+// the length of the lane once looked at; out of the runtime since `pred` may use it
+unsafe fn select_in_lane<F: FnMut(ThreadId, u64) -> bool>(
+    key: ThreadId,
+    lane: Lane,
+    from: usize,
+    pred: &mut F,
+) -> Result<Envelope, usize> {
+    let mut values = rt().messages.take_lane(key, lane);
+    let mut i = from;
+    let mut selected = None;
+    while i < values.len() {
+        if expired(key, &values[i]) {
+            values.remove(i);
+        } else if pred(values[i].sender, values[i].msg) {
+            selected = values.remove(i);
+            break;
+        } else {
+            i += 1;
+        }
+    }
+    rt().messages.restore_lane(key, lane, values);
+    match selected {
+        Some(envelope) => {
+            make_room_after_receive(key);
+            Ok(envelope)
+        }
+        None => Err(i),
+    }
+}
+
+// take the next message for `key`
+pub(super) unsafe fn pop_message(key: ThreadId) -> Option<u64> {
+    pop_with_sender(key).map(|envelope| envelope.msg)
+}
+
+// take the next message for `key` and its sender, making room for a sender parked on
+// its bounded mailbox
+unsafe fn pop_with_sender(key: ThreadId) -> Option<Envelope> {
+    let envelope = take_message(key)?;
+    make_room_after_receive(key);
+    Some(envelope)
+}
+
+// wake a sender parked on the bounded mailbox of `key`, which a message has just left
+unsafe fn make_room_after_receive(key: ThreadId) {
+    if rt().bounds.is_empty() {
+        return;
+    }
+    let runtime = rt();
+    let bound = match runtime.bounds.get_mut(&key) {
+        Some(bound) => bound,
+        None => return,
+    };
+    count_remote_arrivals(key, bound);
+    bound.len = bound.len.saturating_sub(1);
+    // the first one still parked, a killed sender does not take the room
+    while let Some(sender) = bound.senders.pop_front() {
+        if let Some(ctx) = runtime.waiting.remove(&sender) {
+            runtime.contexts.push_back(ctx);
+            break;
+        }
+    }
+}
+
+// take the next message for `key`: the ones sent with priority first, then the ones
+// skipped by `recv_matching`; stale ones go to the dead letters
+unsafe fn take_message(key: ThreadId) -> Option<Envelope> {
+    loop {
+        let envelope = match rt().messages.pop_lanes(key) {
+            Some(envelope) => envelope,
+            None => take_queued(key)?,
+        };
+        if !expired(key, &envelope) {
+            return Some(envelope);
+        }
+    }
+}
+
+// route a message taken from the mailbox of `key` to the dead letters if it is stale,
+// returns whether it did
+unsafe fn expired(key: ThreadId, envelope: &Envelope) -> bool {
+    match envelope.expires {
+        Some(expires) if Instant::now() >= expires => {
+            dead_letter(key, *envelope, DeadLetterReason::Expired);
+            make_room_after_receive(key);
+            true
+        }
+        _ => false,
+    }
+}
+
+// take the next queued message for `key` from its link first, then from the message queue
+unsafe fn take_queued(key: ThreadId) -> Option<Envelope> {
+    let runtime = rt();
+    let link = match runtime.links.get_mut(&key) {
+        Some(link) => link,
+        None => return runtime.messages.pop_front(key),
+    };
+    if let Some(msg) = link.ring.pop() {
+        return Some(Envelope {
+            sender: link.sender,
+            msg,
+            expires: None,
+        });
+    }
+    let msg = runtime.messages.pop_front(key);
+    if msg.is_none() {
+        // everything the sender queued is delivered, so it may use the ring again
+        link.overflowed = false;
+    }
+    msg
+}
+
+// the messages `pop_message` would return for `key`, in order
+pub(super) unsafe fn mailbox_contents(runtime: &Runtime, key: ThreadId) -> Vec<u64> {
+    let mut msgs = Vec::new();
+    for lane in LANES {
+        if let Some(values) = runtime.messages.lane(lane).get(&key) {
+            msgs.extend(values.iter().map(|envelope| envelope.msg));
+        }
+    }
+    if let Some(link) = runtime.links.get(&key) {
+        msgs.extend(link.ring.iter());
+    }
+    if let Some(queue) = runtime.messages.map.get(&key) {
+        queue.for_each(|envelope| msgs.push(envelope.msg));
+    }
+    msgs
+}
+
+// the number of messages `mailbox_contents` would return, without copying them
+pub(super) unsafe fn count_messages(runtime: &Runtime, key: ThreadId) -> usize {
+    let mut len = LANES
+        .iter()
+        .map(|&lane| {
+            runtime
+                .messages
+                .lane(lane)
+                .get(&key)
+                .map_or(0, VecDeque::len)
+        })
+        .sum::<usize>();
+    len += runtime.links.get(&key).map_or(0, |link| link.ring.len);
+    if let Some(queue) = runtime.messages.map.get(&key) {
+        queue.for_each(|_| len += 1);
+    }
+    len
+}
+
+/// Why a message went to the dead letters, see `recv_dead_letter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterReason {
+    /// its receiver had ended, or ended before receiving it
+    NoReceiver,
+    /// it went stale before being received, see `send_with_ttl`
+    Expired,
+}
+
+/// A message which could not be delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub sender: ThreadId,
+    pub receiver: ThreadId,
+    pub msg: u64,
+    pub reason: DeadLetterReason,
+}
+
+// The number of dead letters kept for the observers; the oldest are dropped beyond it
+const MAX_DEAD_LETTERS: usize = 1024;
+
+// route an undeliverable message to the dead letters, and wake the threads waiting for one
+unsafe fn dead_letter(receiver: ThreadId, envelope: Envelope, reason: DeadLetterReason) {
+    let runtime = rt();
+    if runtime.dead_letters.len() == MAX_DEAD_LETTERS {
+        runtime.dead_letters.pop_front();
+        runtime.report.dropped += 1;
+    }
+    runtime.dead_letters.push_back(DeadLetter {
+        sender: envelope.sender,
+        receiver,
+        msg: envelope.msg,
+        reason,
+    });
+    wake(std::mem::take(&mut runtime.dead_letter_waiters));
+}
+
+/// Receive the next message which could not be delivered, because its receiver has ended
+/// or it went stale, waiting until there is one; None outside of green threads.
+///
+/// The runtime keeps the last 1024 dead letters, the older ones are counted in
+/// `RunReport::dropped`.
+pub fn recv_dead_letter() -> Option<DeadLetter> {
+    unsafe {
+        if current_ctx().is_null() {
+            return None;
+        }
+        let id = (*current_ctx()).id;
+        let _registered = Deregister(|| rt().dead_letter_waiters.retain(|&other| other != id));
+        loop {
+            let runtime = rt();
+            if let Some(letter) = runtime.dead_letters.pop_front() {
+                return Some(letter);
+            }
+            if !runtime.dead_letter_waiters.contains(&id) {
+                runtime.dead_letter_waiters.push(id);
+            }
+            wait();
+        }
+    }
+}
+
+/// Take the next dead letter if there is one, without parking.
+pub fn try_recv_dead_letter() -> Option<DeadLetter> {
+    if runtime_ptr().is_null() {
+        return None;
+    }
+    unsafe { rt().dead_letters.pop_front() }
+}
@@ -0,0 +1,150 @@
+// The green thread runtime, one module per subsystem.
+// `arch` and `context` are the context switch core and are always built; the others are cargo
+// features: `scheduler` for green threads and their mailboxes, `sync` for futures and channels
+// shared with OS threads, and `net` for message codecs and RPC.
+
+mod arch;
+#[cfg(feature = "scheduler")]
+use arch::*;
+pub use arch::{ContextEntry, ContextOps};
+
+mod context;
+pub use context::*;
+
+#[cfg(feature = "scheduler")]
+mod mailbox;
+#[cfg(feature = "scheduler")]
+pub use mailbox::*;
+
+#[cfg(feature = "scheduler")]
+mod scheduler;
+#[cfg(feature = "scheduler")]
+pub use scheduler::*;
+
+#[cfg(feature = "scheduler")]
+mod runtime;
+#[cfg(feature = "scheduler")]
+pub use runtime::*;
+
+#[cfg(feature = "scheduler")]
+mod overflow;
+#[cfg(feature = "scheduler")]
+use overflow::*;
+
+#[cfg(feature = "scheduler")]
+mod timer;
+#[cfg(feature = "scheduler")]
+pub use timer::*;
+
+#[cfg(feature = "scheduler")]
+mod channel;
+#[cfg(feature = "scheduler")]
+pub use channel::*;
+
+#[cfg(feature = "scheduler")]
+mod select;
+#[cfg(feature = "scheduler")]
+pub use select::*;
+
+#[cfg(feature = "scheduler")]
+mod ask;
+#[cfg(feature = "scheduler")]
+pub use ask::*;
+
+#[cfg(feature = "scheduler")]
+mod pubsub;
+#[cfg(feature = "scheduler")]
+pub use pubsub::*;
+
+#[cfg(feature = "scheduler")]
+mod group;
+#[cfg(feature = "scheduler")]
+pub use group::*;
+
+#[cfg(feature = "scheduler")]
+mod router;
+#[cfg(feature = "scheduler")]
+pub use router::*;
+
+#[cfg(feature = "scheduler")]
+mod actor;
+#[cfg(feature = "scheduler")]
+pub use actor::*;
+
+#[cfg(feature = "scheduler")]
+mod server;
+#[cfg(feature = "scheduler")]
+pub use server::*;
+
+#[cfg(feature = "scheduler")]
+mod exits;
+#[cfg(feature = "scheduler")]
+pub use exits::*;
+
+#[cfg(feature = "scheduler")]
+mod supervisor;
+#[cfg(feature = "scheduler")]
+pub use supervisor::*;
+
+#[cfg(feature = "sync")]
+mod sync;
+#[cfg(feature = "sync")]
+pub use sync::*;
+
+#[cfg(feature = "net")]
+mod net;
+#[cfg(feature = "net")]
+pub use net::*;
+
+#[cfg(feature = "net")]
+mod remote;
+#[cfg(feature = "net")]
+pub use remote::*;
+
+// Compiled to nothing unless the `profile` feature is enabled.
+#[cfg(feature = "profile")]
+mod profile;
+#[cfg(feature = "profile")]
+pub use profile::{switch_profile, SwitchProfile};
+
+#[cfg(all(feature = "scheduler", not(feature = "profile")))]
+mod profile {
+    #[inline(always)]
+    pub fn begin() {}
+    #[inline(always)]
+    pub fn end() {}
+}
+
+// Without the `model` feature the scheduler always runs the front of the queue.
+#[cfg(feature = "model")]
+mod model;
+#[cfg(feature = "model")]
+pub use model::{
+    model_check, model_replay, sim_now, simulate, ModelFailure, ModelReport, SimConfig, SimReport,
+};
+
+#[cfg(all(feature = "scheduler", not(feature = "model")))]
+mod model {
+    #[inline(always)]
+    pub unsafe fn pick_front() {}
+    #[inline(always)]
+    pub fn run_entry(entry: super::BoxEntry) {
+        entry()
+    }
+    #[inline(always)]
+    pub unsafe fn intercept(_key: super::ThreadId, _msg: u64) -> bool {
+        false
+    }
+    #[inline(always)]
+    pub unsafe fn deliver_delayed() -> bool {
+        false
+    }
+    #[inline(always)]
+    pub unsafe fn has_delayed() -> bool {
+        false
+    }
+    #[inline(always)]
+    pub fn random_id() -> u64 {
+        rand::random()
+    }
+}
@@ -0,0 +1,425 @@
+// Systematic exploration and seeded simulation of the scheduling decisions, for testing.
+
+use super::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ptr;
+
+// the function run over and over, every run starting from the same state
+pub(super) type Entry = fn();
+
+// One scheduling decision: which of the `choices` executable threads runs next
+#[derive(Clone, Copy)]
+struct Decision {
+    chosen: usize,
+    choices: usize,
+}
+
+struct State {
+    // the decisions of the current run, replayed up to the branch being explored
+    path: Vec<Decision>,
+    pos: usize,
+    invariant: Option<fn()>,
+    failure: Option<String>,
+    // set when simulating instead of exploring
+    sim: Option<Sim>,
+}
+
+// A delayed message: (due time, order of sending, receiver, sender, message)
+type Delayed = (u64, u64, ThreadId, ThreadId, u64);
+
+struct Sim {
+    config: SimConfig,
+    rng: StdRng,
+    // virtual time, in scheduling decisions
+    now: u64,
+    // delayed messages ordered by due time, then order of sending
+    delayed: BinaryHeap<Reverse<Delayed>>,
+    sent: u64,
+    // the main green thread, which is never crashed
+    root: Option<ThreadId>,
+    report: SimReport,
+}
+
+/// The faults a simulation injects, each with a probability between 0 and 1.
+#[derive(Debug, Clone, Copy)]
+pub struct SimConfig {
+    /// seed of every random decision, so a failing run can be reproduced
+    pub seed: u64,
+    /// chance that a sent message is lost
+    pub drop_rate: f64,
+    /// chance that a sent message is delivered twice
+    pub duplicate_rate: f64,
+    /// chance that a sent message is delivered up to `max_delay` ticks later
+    pub delay_rate: f64,
+    pub max_delay: u64,
+    /// chance, at each scheduling decision, that an executable thread other than
+    /// the main green thread is crashed (dropped without being resumed)
+    pub crash_rate: f64,
+}
+
+impl SimConfig {
+    /// A simulation with seeded scheduling only, and no fault.
+    pub fn new(seed: u64) -> Self {
+        SimConfig {
+            seed,
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay: 0,
+            crash_rate: 0.0,
+        }
+    }
+}
+
+/// What happened during a simulation.
+#[derive(Debug, Clone, Default)]
+pub struct SimReport {
+    /// the virtual time at the end of the run
+    pub ticks: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delayed: u64,
+    /// ids of the threads crashed, in order
+    pub crashed: Vec<ThreadId>,
+}
+
+// the exploration or simulation in progress on this OS thread, null if none
+#[inline(always)]
+fn state() -> *mut State {
+    host_local(MODEL_SLOT) as *mut State
+}
+
+// Safety: as for `rt()`, the reference must not be kept across a context switch
+unsafe fn sim() -> Option<&'static mut Sim> {
+    if state().is_null() {
+        return None;
+    }
+    (*state()).sim.as_mut()
+}
+
+/// Summary of an exploration that found no failure.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelReport {
+    /// the number of interleavings run
+    pub runs: usize,
+    /// false if `max_runs` was reached before every interleaving was run
+    pub complete: bool,
+}
+
+/// An interleaving in which an assertion failed or the threads deadlocked.
+#[derive(Debug, Clone)]
+pub struct ModelFailure {
+    /// the run (starting at 1) which failed
+    pub run: usize,
+    /// the index in the execution queue chosen at each decision, for `model_replay`
+    pub schedule: Vec<usize>,
+    /// the panic message
+    pub message: String,
+}
+
+// move the thread chosen for the next decision to the front of the execution queue
+pub unsafe fn pick_front() {
+    if state().is_null() || rt().contexts.len() < 2 {
+        return;
+    }
+    // the invariant and the exit hooks of a crashed thread are user code, which may use
+    // the state itself, so no reference to it is kept across them
+    if let Some(invariant) = (*state()).invariant {
+        if let Err(payload) = std::panic::catch_unwind(invariant) {
+            fail(payload);
+        }
+    }
+    if let Some(sim) = sim() {
+        sim.now += 1;
+        if let Some(ctx) = sim.crash() {
+            kill_context(ctx);
+        }
+    }
+
+    let state = &mut *state();
+    if let Some(sim) = state.sim.as_mut() {
+        while sim.deliver_due() {}
+        let choices = rt().contexts.len();
+        let chosen = sim.rng.gen_range(0..choices);
+        state.path.push(Decision { chosen, choices });
+        state.pos += 1;
+        if chosen > 0 {
+            let ctx = rt().contexts.remove(chosen).unwrap();
+            rt().contexts.push_front(ctx);
+        }
+        return;
+    }
+
+    let choices = rt().contexts.len();
+    let chosen = match state.path.get_mut(state.pos) {
+        Some(decision) => {
+            // a program that is not deterministic may offer fewer choices on the replay
+            decision.chosen = decision.chosen.min(choices - 1);
+            decision.choices = choices;
+            decision.chosen
+        }
+        None => {
+            state.path.push(Decision { chosen: 0, choices });
+            0
+        }
+    };
+    state.pos += 1;
+
+    if chosen > 0 {
+        let ctx = rt().contexts.remove(chosen).unwrap();
+        rt().contexts.push_front(ctx);
+    }
+}
+
+impl Sim {
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.rng.gen_bool(rate.min(1.0))
+    }
+    // take an executable thread other than the running one out of the queue, to be
+    // killed as if it had crashed
+    unsafe fn crash(&mut self) -> Option<Box<Context>> {
+        if rt().contexts.len() < 2 || !self.chance(self.config.crash_rate) {
+            return None;
+        }
+        let i = self.rng.gen_range(0..rt().contexts.len());
+        let id = rt().contexts[i].id;
+        if Some(id) == self.root || ptr::eq(&*rt().contexts[i], current_ctx()) {
+            return None;
+        }
+        self.report.crashed.push(id);
+        rt().contexts.remove(i)
+    }
+    // deliver the earliest delayed message if it is due
+    unsafe fn deliver_due(&mut self) -> bool {
+        match self.delayed.peek() {
+            Some(Reverse((due, ..))) if *due <= self.now => {}
+            _ => return false,
+        }
+        let Reverse((_, _, key, sender, msg)) = self.delayed.pop().unwrap();
+        rt().report.delivered += 1;
+        let envelope = Envelope {
+            sender,
+            msg,
+            expires: None,
+        };
+        rt().messages.push_back(key, envelope);
+        if let Some(ctx) = rt().waiting.remove(&key) {
+            rt().contexts.push_back(ctx);
+        }
+        true
+    }
+    fn delay(&mut self, key: ThreadId, sender: ThreadId, msg: u64) {
+        let due = self.now + self.rng.gen_range(1..=self.config.max_delay.max(1));
+        self.delayed
+            .push(Reverse((due, self.sent, key, sender, msg)));
+        self.sent += 1;
+    }
+}
+
+// inject the faults of the simulation into a send, returns true if it took the message
+pub unsafe fn intercept(key: ThreadId, msg: u64) -> bool {
+    let sim = match sim() {
+        Some(sim) => sim,
+        None => return false,
+    };
+    let config = sim.config;
+    let sender = (*current_ctx()).id;
+    if sim.chance(config.drop_rate) {
+        sim.report.dropped += 1;
+        return true;
+    }
+    if sim.chance(config.duplicate_rate) {
+        sim.report.duplicated += 1;
+        sim.delay(key, sender, msg);
+    }
+    if sim.chance(config.delay_rate) {
+        sim.report.delayed += 1;
+        sim.delay(key, sender, msg);
+        return true;
+    }
+    false
+}
+
+// jump the virtual time to the next delayed message when no thread is executable
+pub unsafe fn deliver_delayed() -> bool {
+    let sim = match sim() {
+        Some(sim) => sim,
+        None => return false,
+    };
+    if let Some(Reverse((due, ..))) = sim.delayed.peek() {
+        sim.now = sim.now.max(*due);
+    }
+    sim.deliver_due()
+}
+
+pub unsafe fn has_delayed() -> bool {
+    sim().is_some_and(|sim| !sim.delayed.is_empty())
+}
+
+pub fn random_id() -> u64 {
+    match unsafe { sim() } {
+        Some(sim) => {
+            let id = sim.rng.gen();
+            // the first id is the one of the main green thread
+            sim.root.get_or_insert(ThreadId(id));
+            id
+        }
+        None => rand::random(),
+    }
+}
+
+/// The virtual time of the running simulation, in ticks, or 0 outside of `simulate`.
+pub fn sim_now() -> u64 {
+    unsafe { sim().map_or(0, |sim| sim.now) }
+}
+
+pub fn run_entry(entry: BoxEntry) {
+    if state().is_null() {
+        return entry();
+    }
+    if let Err(payload) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(entry)) {
+        unsafe { fail(payload) };
+    }
+}
+
+// record the failure and abandon the run by going back to the main context,
+// the remaining threads are dropped without being resumed
+unsafe fn fail(payload: Box<dyn std::any::Any + Send>) -> ! {
+    (*state()).failure = Some(panic_message(payload));
+    rt().current = ptr::null_mut();
+    switch_context(&rt().main, RESTORE_FP);
+}
+
+unsafe fn run(func: Entry, stack_size: usize, state: &mut State) -> Option<ModelFailure> {
+    state.pos = 0;
+    set_host_local(MODEL_SLOT, state as *mut State as *mut ());
+    spawn_from_main(func, stack_size);
+    if let Some(sim) = state.sim.as_mut() {
+        sim.delayed.clear();
+    }
+    set_host_local(MODEL_SLOT, ptr::null_mut());
+    state.path.truncate(state.pos);
+    state.failure.take().map(|message| ModelFailure {
+        run: 0,
+        schedule: state.path.iter().map(|decision| decision.chosen).collect(),
+        message,
+    })
+}
+
+/// Run `func` as the main green thread once for every interleaving of its threads
+/// (depth first, at most `max_runs` of them), until a thread panics or deadlocks.
+///
+/// At every point where more than one thread is executable, each of them is tried in turn;
+/// `invariant` is also checked there. The program must be deterministic apart from the
+/// scheduling: no other OS threads, timers or randomness deciding what is sent.
+pub fn model_check(
+    func: Entry,
+    stack_size: usize,
+    max_runs: usize,
+    invariant: Option<fn()>,
+) -> Result<ModelReport, ModelFailure> {
+    let mut state = State {
+        path: Vec::new(),
+        pos: 0,
+        invariant,
+        failure: None,
+        sim: None,
+    };
+    let mut runs = 0;
+    loop {
+        runs += 1;
+        if let Some(failure) = unsafe { run(func, stack_size, &mut state) } {
+            return Err(ModelFailure {
+                run: runs,
+                ..failure
+            });
+        }
+
+        // depth first: take the next choice of the deepest decision with one left
+        while let Some(decision) = state.path.last_mut() {
+            if decision.chosen + 1 < decision.choices {
+                decision.chosen += 1;
+                break;
+            }
+            state.path.pop();
+        }
+        if state.path.is_empty() {
+            return Ok(ModelReport {
+                runs,
+                complete: true,
+            });
+        }
+        if runs >= max_runs {
+            return Ok(ModelReport {
+                runs,
+                complete: false,
+            });
+        }
+    }
+}
+
+/// Run `func` once with the interleaving of a `ModelFailure`, e.g. to debug it.
+pub fn model_replay(
+    func: Entry,
+    stack_size: usize,
+    schedule: &[usize],
+) -> Result<(), ModelFailure> {
+    let mut state = State {
+        path: schedule
+            .iter()
+            .map(|&chosen| Decision {
+                chosen,
+                choices: chosen + 1,
+            })
+            .collect(),
+        pos: 0,
+        invariant: None,
+        failure: None,
+        sim: None,
+    };
+    match unsafe { run(func, stack_size, &mut state) } {
+        Some(failure) => Err(ModelFailure { run: 1, ..failure }),
+        None => Ok(()),
+    }
+}
+
+/// Run `func` as the main green thread on a deterministic simulation: the next thread is
+/// picked at random, and messages and threads are lost, duplicated, delayed or crashed
+/// as `config` says, all from `config.seed`, so a failing seed reproduces the same run.
+///
+/// Time is virtual: it advances by one tick per scheduling decision, and jumps to the next
+/// delayed message when every thread is waiting. The same restrictions as `model_check` apply.
+pub fn simulate(
+    func: Entry,
+    stack_size: usize,
+    config: SimConfig,
+) -> Result<SimReport, ModelFailure> {
+    let rng = StdRng::seed_from_u64(config.seed);
+    let mut state = State {
+        path: Vec::new(),
+        pos: 0,
+        invariant: None,
+        failure: None,
+        sim: Some(Sim {
+            config,
+            rng,
+            now: 0,
+            delayed: BinaryHeap::new(),
+            sent: 0,
+            root: None,
+            report: SimReport::default(),
+        }),
+    };
+    let failure = unsafe { run(func, stack_size, &mut state) };
+    let sim = state.sim.unwrap();
+    match failure {
+        Some(failure) => Err(ModelFailure { run: 1, ..failure }),
+        None => Ok(SimReport {
+            ticks: sim.now,
+            ..sim.report
+        }),
+    }
+}
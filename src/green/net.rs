@@ -0,0 +1,345 @@
+// Encoding of messages leaving the process, and typed RPC on top of it, within the process
+// or with the nodes of `remote`.
+
+use super::*;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Error returned when bytes cannot be decoded into a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// the encoded message does not have the size the codec expects
+    Length { expected: usize, found: usize },
+    /// the bytes are not a valid encoding
+    Invalid(String),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Length { expected, found } => {
+                write!(f, "expected {} bytes, found {}", expected, found)
+            }
+            CodecError::Invalid(reason) => write!(f, "invalid message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Converts messages of type `T` to and from bytes, for messages leaving the process
+/// (remote nodes, snapshots on disk).
+pub trait MessageCodec<T> {
+    /// MIME-like name of the encoding, sent along the bytes so the peer can pick the same codec.
+    fn content_type(&self) -> &'static str;
+    /// Append the encoding of `msg` to `buf`.
+    fn encode(&self, msg: &T, buf: &mut Vec<u8>);
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// The default codec of `u64` messages: 8 bytes, little endian.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct U64Codec;
+
+impl MessageCodec<u64> for U64Codec {
+    fn content_type(&self) -> &'static str {
+        "application/x-green-u64"
+    }
+    fn encode(&self, msg: &u64, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&msg.to_le_bytes());
+    }
+    fn decode(&self, bytes: &[u8]) -> Result<u64, CodecError> {
+        let bytes: [u8; 8] = bytes.try_into().map_err(|_| CodecError::Length {
+            expected: 8,
+            found: bytes.len(),
+        })?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+/// A codec passing byte messages through unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BytesCodec;
+
+impl MessageCodec<Vec<u8>> for BytesCodec {
+    fn content_type(&self) -> &'static str {
+        "application/octet-stream"
+    }
+    fn encode(&self, msg: &Vec<u8>, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(msg);
+    }
+    fn decode(&self, bytes: &[u8]) -> Result<Vec<u8>, CodecError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Error returned by an RPC call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+    /// no reply arrived before the deadline
+    Timeout,
+    /// the call was cancelled by the client
+    Cancelled,
+    /// the other end is dropped
+    Disconnected,
+    /// the request or the reply could not be decoded
+    Codec(CodecError),
+    /// the request or the reply, of this many bytes, is larger than a node can receive
+    TooLarge(usize),
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "rpc deadline exceeded"),
+            RpcError::Cancelled => write!(f, "rpc cancelled"),
+            RpcError::Disconnected => write!(f, "rpc peer disconnected"),
+            RpcError::Codec(err) => write!(f, "rpc codec error: {}", err),
+            RpcError::TooLarge(len) => write!(f, "rpc message too large: {} bytes", len),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+pub(super) type RpcReply = Result<Vec<u8>, RpcError>;
+
+// A request as it travels to the server, already encoded by the client's codec
+pub(super) struct RpcRequest {
+    pub(super) deadline: Option<Instant>,
+    pub(super) body: Vec<u8>,
+    // set by the client giving up on the call, so the server can skip it
+    pub(super) cancelled: Arc<AtomicBool>,
+    pub(super) reply: Box<dyn FnOnce(RpcReply) + Send>,
+}
+
+// Where a client sends its requests
+#[derive(Clone)]
+enum Route {
+    Local(BridgeSender<RpcRequest>),
+    // a service of another node, see `Node::rpc_client`
+    Remote(RemoteRoute),
+}
+
+/// The calling end of an RPC service taking `Req` and answering `Resp`, both encoded with `C`.
+pub struct RpcClient<Req, Resp, C> {
+    route: Route,
+    next_id: Arc<AtomicU64>,
+    codec: C,
+    _marker: PhantomData<fn(&Req) -> Resp>,
+}
+
+/// The serving end of an RPC service.
+pub struct RpcServer<Req, Resp, C> {
+    requests: BridgeReceiver<RpcRequest>,
+    codec: C,
+    _marker: PhantomData<fn(Req) -> Resp>,
+}
+
+/// A call sent to the server whose reply has not been taken yet.
+pub struct PendingCall<Resp, C> {
+    id: u64,
+    deadline: Option<Instant>,
+    reply: BridgeReceiver<RpcReply>,
+    cancelled: Arc<AtomicBool>,
+    // the service of another node the call went to, told when the client gives up
+    remote: Option<RemoteRoute>,
+    codec: C,
+    _marker: PhantomData<fn() -> Resp>,
+}
+
+/// Create an RPC service whose requests and replies go through `codec`,
+/// the same way they are framed for a remote node; see `Node::serve_rpc` to let
+/// other nodes call it.
+///
+/// Clients and the server may live on the green threads of different runtimes.
+pub fn rpc<Req, Resp, C>(codec: C) -> (RpcClient<Req, Resp, C>, RpcServer<Req, Resp, C>)
+where
+    C: MessageCodec<Req> + MessageCodec<Resp> + Clone,
+{
+    let (sender, receiver) = bridge();
+    (
+        RpcClient::new(Route::Local(sender), codec.clone()),
+        RpcServer {
+            requests: receiver,
+            codec,
+            _marker: PhantomData,
+        },
+    )
+}
+
+impl<Req, Resp, C> RpcClient<Req, Resp, C> {
+    fn new(route: Route, codec: C) -> Self {
+        RpcClient {
+            route,
+            next_id: Arc::new(AtomicU64::new(0)),
+            codec,
+            _marker: PhantomData,
+        }
+    }
+
+    // a client of the service of another node `route` calls
+    pub(super) fn remote(route: RemoteRoute, codec: C) -> Self {
+        RpcClient::new(Route::Remote(route), codec)
+    }
+
+    // where the requests of a local service go, None for the service of another node
+    pub(super) fn local_requests(&self) -> Option<BridgeSender<RpcRequest>> {
+        match &self.route {
+            Route::Local(requests) => Some(requests.clone()),
+            Route::Remote(_) => None,
+        }
+    }
+}
+
+impl<Req, Resp, C> RpcClient<Req, Resp, C>
+where
+    C: MessageCodec<Req> + MessageCodec<Resp> + Clone,
+{
+    /// Send a request without waiting for its reply; `timeout` bounds the whole call.
+    pub fn start(
+        &self,
+        req: &Req,
+        timeout: Option<Duration>,
+    ) -> Result<PendingCall<Resp, C>, RpcError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut body = Vec::new();
+        MessageCodec::<Req>::encode(&self.codec, req, &mut body);
+
+        let (reply, receiver) = bridge();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let (id, remote) = match &self.route {
+            Route::Local(requests) => {
+                let request = RpcRequest {
+                    deadline,
+                    body,
+                    cancelled: cancelled.clone(),
+                    reply: Box::new(move |result| {
+                        // the client may have timed out and dropped its end
+                        let _ = reply.send(result);
+                    }),
+                };
+                if requests.send(request).is_err() {
+                    return Err(RpcError::Disconnected);
+                }
+                (self.next_id.fetch_add(1, Ordering::Relaxed), None)
+            }
+            Route::Remote(route) => (route.start(&body, timeout, reply)?, Some(route.clone())),
+        };
+        Ok(PendingCall {
+            id,
+            deadline,
+            reply: receiver,
+            cancelled,
+            remote,
+            codec: self.codec.clone(),
+            _marker: PhantomData,
+        })
+    }
+    /// Send a request and wait for its reply.
+    pub fn call(&self, req: &Req, timeout: Option<Duration>) -> Result<Resp, RpcError> {
+        self.start(req, timeout)?.wait()
+    }
+}
+
+impl<Req, Resp, C: Clone> Clone for RpcClient<Req, Resp, C> {
+    fn clone(&self) -> Self {
+        RpcClient {
+            route: self.route.clone(),
+            next_id: self.next_id.clone(),
+            codec: self.codec.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Resp, C: MessageCodec<Resp>> PendingCall<Resp, C> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    /// Wait for the reply, letting the other green threads run meanwhile.
+    pub fn wait(self) -> Result<Resp, RpcError> {
+        let reply = match self.deadline {
+            None => self.reply.recv(),
+            Some(deadline) => match self.reply.recv_until(deadline) {
+                Ok(reply) => reply,
+                Err(Elapsed) => {
+                    self.give_up();
+                    return Err(RpcError::Timeout);
+                }
+            },
+        };
+        let body = reply.ok_or(RpcError::Disconnected)??;
+        self.codec.decode(&body).map_err(RpcError::Codec)
+    }
+    /// Give up on the call; the server skips it if it has not started handling it yet.
+    pub fn cancel(self) {
+        if self.reply.try_recv().is_none() {
+            self.give_up();
+        }
+    }
+}
+
+impl<Resp, C> PendingCall<Resp, C> {
+    fn give_up(&self) {
+        self.cancelled.store(true, Ordering::Release);
+        if let Some(route) = &self.remote {
+            route.cancel(self.id);
+        }
+    }
+}
+
+impl<Resp, C> Drop for PendingCall<Resp, C> {
+    fn drop(&mut self) {
+        // a reply arriving later is dropped, as the one of a local call is
+        if let Some(route) = &self.remote {
+            route.forget(self.id);
+        }
+    }
+}
+
+impl<Req, Resp, C> RpcServer<Req, Resp, C>
+where
+    C: MessageCodec<Req> + MessageCodec<Resp>,
+{
+    /// Handle requests until every client is dropped.
+    pub fn serve<F: FnMut(Req) -> Resp>(&self, mut handler: F) {
+        while let Some(request) = self.requests.recv() {
+            self.handle(request, &mut handler);
+        }
+    }
+    /// Handle the requests already queued, and return how many were received.
+    pub fn poll<F: FnMut(Req) -> Resp>(&self, mut handler: F) -> usize {
+        let mut count = 0;
+        while let Some(request) = self.requests.try_recv() {
+            self.handle(request, &mut handler);
+            count += 1;
+        }
+        count
+    }
+    fn handle<F: FnMut(Req) -> Resp>(&self, request: RpcRequest, handler: &mut F) {
+        if request.cancelled.load(Ordering::Acquire) {
+            (request.reply)(Err(RpcError::Cancelled));
+            return;
+        }
+        if request
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            (request.reply)(Err(RpcError::Timeout));
+            return;
+        }
+        let reply = match MessageCodec::<Req>::decode(&self.codec, &request.body) {
+            Ok(req) => {
+                let resp = handler(req);
+                let mut body = Vec::new();
+                MessageCodec::<Resp>::encode(&self.codec, &resp, &mut body);
+                Ok(body)
+            }
+            Err(err) => Err(RpcError::Codec(err)),
+        };
+        (request.reply)(reply);
+    }
+}
@@ -0,0 +1,113 @@
+// Stack overflows of green threads: a fault on the guard page of the running thread ends
+// that thread with `ExitStatus::StackOverflow` instead of the process.
+//
+// The signal handler restarts the faulting thread at the top of its own stack, in a function
+// ending it; its frames are abandoned without being unwound, as when it is killed. Other
+// faults go to the handler installed before, usually the one of std aborting the process.
+// Only on Linux on x86_64 and aarch64, elsewhere an overflow still faults.
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    not(feature = "software")
+))]
+mod handler {
+    use super::super::*;
+    use std::mem;
+    use std::ptr;
+    use std::sync::Once;
+
+    // The size of the alternate signal stack given to OS threads which have none
+    const ALT_STACK_SIZE: usize = 64 * 1024;
+
+    // the handlers of SIGSEGV and SIGBUS installed before ours
+    static mut PREVIOUS: [mem::MaybeUninit<libc::sigaction>; 2] =
+        [mem::MaybeUninit::uninit(), mem::MaybeUninit::uninit()];
+    static INSTALL: Once = Once::new();
+    const SIGNALS: [libc::c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+    // install the handler once per process, and make sure the calling OS thread has
+    // an alternate stack to run it on, since the faulting stack is full
+    pub(in super::super) fn install_overflow_handler() {
+        unsafe {
+            INSTALL.call_once(|| {
+                for (i, &signal) in SIGNALS.iter().enumerate() {
+                    let mut action: libc::sigaction = mem::zeroed();
+                    action.sa_sigaction = on_fault as *const () as usize;
+                    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                    libc::sigemptyset(&mut action.sa_mask);
+                    let previous = ptr::addr_of_mut!(PREVIOUS[i]) as *mut libc::sigaction;
+                    libc::sigaction(signal, &action, previous);
+                }
+            });
+            let mut current: libc::stack_t = mem::zeroed();
+            libc::sigaltstack(ptr::null(), &mut current);
+            if current.ss_flags & libc::SS_DISABLE != 0 {
+                // kept for the life of the OS thread
+                let stack = Box::leak(vec![0u8; ALT_STACK_SIZE].into_boxed_slice());
+                let alt = libc::stack_t {
+                    ss_sp: stack.as_mut_ptr() as *mut libc::c_void,
+                    ss_flags: 0,
+                    ss_size: ALT_STACK_SIZE,
+                };
+                libc::sigaltstack(&alt, ptr::null_mut());
+            }
+        }
+    }
+
+    extern "C" fn on_fault(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        ucontext: *mut libc::c_void,
+    ) {
+        unsafe {
+            let ctx = current_ctx();
+            let addr = (*info).si_addr() as usize;
+            if ctx.is_null() || !(*ctx).in_guard_page(addr) {
+                // fault again with the previous handler
+                let i = SIGNALS.iter().position(|&s| s == signal).unwrap();
+                let previous = ptr::addr_of!(PREVIOUS[i]) as *const libc::sigaction;
+                libc::sigaction(signal, previous, ptr::null_mut());
+                return;
+            }
+            let top = (*ctx).stack_top() & !15;
+            let ucontext = &mut *(ucontext as *mut libc::ucontext_t);
+            #[cfg(target_arch = "x86_64")]
+            {
+                let regs = &mut ucontext.uc_mcontext.gregs;
+                // as if called, with the return address pushed
+                regs[libc::REG_RSP as usize] = (top - 8) as i64;
+                regs[libc::REG_RIP as usize] = stack_overflowed as *const () as usize as i64;
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                let regs = &mut ucontext.uc_mcontext;
+                regs.sp = top as u64;
+                regs.pc = stack_overflowed as *const () as usize as u64;
+                regs.regs[30] = 0;
+            }
+        }
+    }
+
+    // end the running thread after its stack overflowed, from the top of that stack
+    extern "C" fn stack_overflowed() -> ! {
+        unsafe {
+            (*current_ctx()).run_exit_hooks();
+            exit_current(ExitStatus::StackOverflow)
+        }
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    not(feature = "software")
+))]
+pub(super) use handler::install_overflow_handler;
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    not(feature = "software")
+)))]
+pub(super) fn install_overflow_handler() {}
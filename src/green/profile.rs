@@ -0,0 +1,115 @@
+// Sampling of the cost of a context switch, from just before swap_context/switch_context
+// in the thread leaving to just after it in the thread resuming.
+
+use std::cell::{Cell, RefCell};
+
+// The number of most recent samples kept for the percentiles
+const MAX_SAMPLES: usize = 4096;
+
+// Samples are kept per OS thread, like runtimes
+thread_local! {
+    static START: Cell<u64> = const { Cell::new(0) };
+    static SAMPLES: RefCell<Samples> = const {
+        RefCell::new(Samples {
+            samples: [0; MAX_SAMPLES],
+            count: 0,
+        })
+    };
+}
+
+struct Samples {
+    samples: [u64; MAX_SAMPLES],
+    count: u64,
+}
+
+/// Percentiles of the context switch cost, in ticks of the hardware counter
+/// (CNTVCT_EL0 on AArch64, where it ticks at a fixed frequency; TSC on x86_64;
+/// the time CSR on riscv64; nanoseconds of the monotonic clock elsewhere).
+#[derive(Debug, Clone, Copy)]
+pub struct SwitchProfile {
+    /// the total number of switches sampled
+    pub count: u64,
+    pub min: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+fn ticks() -> u64 {
+    let ticks: u64;
+    unsafe { std::arch::asm!("mrs {}, cntvct_el0", out(reg) ticks, options(nomem, nostack)) };
+    ticks
+}
+
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn ticks() -> u64 {
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(target_arch = "riscv64")]
+#[inline(always)]
+fn ticks() -> u64 {
+    let ticks: u64;
+    unsafe { std::arch::asm!("rdtime {}", out(reg) ticks, options(nomem, nostack)) };
+    ticks
+}
+
+// the cycle counter of ARMv7 is not readable from user space, so fall back to the clock,
+// as on the architectures without a counter read here
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "x86_64",
+    target_arch = "riscv64"
+)))]
+#[inline(always)]
+fn ticks() -> u64 {
+    let now = nix::time::clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC).unwrap();
+    now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64
+}
+
+#[inline(always)]
+pub fn begin() {
+    START.with(|start| start.set(ticks()));
+}
+
+#[inline(always)]
+pub fn end() {
+    let start = START.with(|start| start.replace(0));
+    if start == 0 {
+        return;
+    }
+    let elapsed = ticks().wrapping_sub(start);
+    SAMPLES.with(|samples| {
+        let mut samples = samples.borrow_mut();
+        let i = samples.count as usize % MAX_SAMPLES;
+        samples.samples[i] = elapsed;
+        samples.count += 1;
+    });
+}
+
+/// Percentiles of the cost of the most recent context switches of the runtimes of the
+/// calling OS thread, or None if none was sampled.
+pub fn switch_profile() -> Option<SwitchProfile> {
+    SAMPLES.with(|samples| {
+        let samples = samples.borrow();
+        if samples.count == 0 {
+            return None;
+        }
+        let len = (samples.count as usize).min(MAX_SAMPLES);
+        let mut sorted = samples.samples[..len].to_vec();
+        sorted.sort_unstable();
+        let percentile = |p: usize| sorted[(len - 1) * p / 100];
+        Some(SwitchProfile {
+            count: samples.count,
+            min: sorted[0],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: sorted[len - 1],
+        })
+    })
+}
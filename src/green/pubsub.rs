@@ -0,0 +1,92 @@
+// Publish/subscribe: named topics fanning the messages of `publish` out to the mailboxes
+// of their subscribers.
+
+use super::*;
+
+/// Subscribe the calling thread to `topic`, so that it receives with `recv` the messages
+/// published to it, until it unsubscribes or ends; false if it was already subscribed.
+pub fn subscribe(topic: &str) -> bool {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "subscribe is called outside of green threads"
+        );
+        let id = (*current_ctx()).id;
+        let runtime = rt();
+        let subscribers = runtime.topics.entry(topic.to_string()).or_default();
+        if subscribers.contains(&id) {
+            return false;
+        }
+        subscribers.push(id);
+        runtime
+            .subscriptions
+            .entry(id)
+            .or_default()
+            .push(topic.to_string());
+        true
+    }
+}
+
+/// Unsubscribe the calling thread from `topic`; false if it was not subscribed.
+pub fn unsubscribe(topic: &str) -> bool {
+    unsafe {
+        if current_ctx().is_null() {
+            return false;
+        }
+        let id = (*current_ctx()).id;
+        let runtime = rt();
+        let topics = match runtime.subscriptions.get_mut(&id) {
+            Some(topics) => topics,
+            None => return false,
+        };
+        let i = match topics.iter().position(|name| name == topic) {
+            Some(i) => i,
+            None => return false,
+        };
+        topics.swap_remove(i);
+        if topics.is_empty() {
+            runtime.subscriptions.remove(&id);
+        }
+        leave_topic(topic, id);
+        true
+    }
+}
+
+/// Send `msg` to every thread subscribed to `topic` like `send_all`, and return
+/// how many they are.
+pub fn publish(topic: &str, msg: u64) -> usize {
+    let subscribers = subscribers(topic);
+    send_all(&subscribers, msg);
+    subscribers.len()
+}
+
+/// The threads subscribed to `topic`, in the order they subscribed.
+pub fn subscribers(topic: &str) -> Vec<ThreadId> {
+    unsafe { rt().topics.get(topic).cloned().unwrap_or_default() }
+}
+
+/// The topics with at least one subscriber.
+pub fn topics() -> Vec<String> {
+    unsafe { rt().topics.keys().cloned().collect() }
+}
+
+// remove `id` from the subscribers of `topic`, forgetting the topic once it has none
+unsafe fn leave_topic(topic: &str, id: ThreadId) {
+    let runtime = rt();
+    if let Some(subscribers) = runtime.topics.get_mut(topic) {
+        subscribers.retain(|&subscriber| subscriber != id);
+        if subscribers.is_empty() {
+            runtime.topics.remove(topic);
+        }
+    }
+}
+
+// unsubscribe an ending thread from all its topics
+pub(super) unsafe fn leave_topics(id: ThreadId) {
+    if rt().subscriptions.is_empty() {
+        return;
+    }
+    for topic in rt().subscriptions.remove(&id).unwrap_or_default() {
+        leave_topic(&topic, id);
+    }
+}
@@ -0,0 +1,680 @@
+// Remote nodes: runtimes of other processes or machines, connected over TCP, whose green
+// threads are sent messages like the local ones.
+//
+// A frame is the id of the receiving thread (8 bytes), the length of the content type of its
+// codec (1 byte) and that content type, the length of the message (4 bytes), then the message
+// encoded by the codec, all little endian. Each peer is written to by an OS thread of its own,
+// and each connection read by another, so that no green thread blocks on the network; the
+// messages read are delivered by a green thread of the node.
+//
+// RPC calls travel as frames of their own content types: a request names the service and
+// the node to reply to, the reply and the cancellation carry the id of the call in place of
+// a thread.
+
+use super::*;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The largest message, once encoded, a node receives from another one.
+pub const MAX_FRAME: usize = 1 << 20;
+
+// The content types of the frames of RPC calls
+const RPC_REQUEST: &str = "application/x-green-rpc-request";
+const RPC_REPLY: &str = "application/x-green-rpc-reply";
+const RPC_CANCEL: &str = "application/x-green-rpc-cancel";
+
+// The timeout of a request without one
+const NO_TIMEOUT: u64 = u64::MAX;
+
+/// A green thread of any node, qualified by the address its node listens on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RemoteId {
+    pub node: SocketAddr,
+    pub thread: ThreadId,
+}
+
+impl std::fmt::Display for RemoteId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.thread, self.node)
+    }
+}
+
+impl RemoteId {
+    /// Send `msg` to the thread, see `send_remote`.
+    pub fn send(&self, msg: u64) {
+        send_remote(*self, msg)
+    }
+}
+
+/// A node of the calling runtime, listening for the messages other nodes send to its green
+/// threads, see `start_node`. It stops listening once dropped.
+pub struct Node {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    // stops the green thread delivering the messages
+    deliveries: BridgeSender<Option<Incoming>>,
+    decoders: Decoders,
+    rpc: SharedRpc,
+}
+
+// A message read from another node, delivered by the green thread of the node
+enum Incoming {
+    Msg(ThreadId, u64),
+    // sends a message decoded by a codec of `Node::accept_codec`
+    Typed(Box<dyn FnOnce() + Send>),
+}
+
+// The codecs of the typed messages a node accepts, by content type
+type Decoders = Arc<Mutex<HashMap<&'static str, Decoder>>>;
+type Decoder = Box<dyn Fn(ThreadId, &[u8]) -> Result<Incoming, CodecError> + Send>;
+
+// The RPC calls of a node, shared with its readers
+#[derive(Default)]
+struct RpcState {
+    // the services other nodes call, see `Node::serve_rpc`
+    services: HashMap<String, BridgeSender<RpcRequest>>,
+    // the calls of the clients of `Node::rpc_client` waiting for their reply
+    calls: HashMap<u64, BridgeSender<RpcReply>>,
+    next_call: u64,
+    // the requests of other nodes not replied to yet, by client node and call
+    running: HashMap<(SocketAddr, u64), Arc<AtomicBool>>,
+    // set once the node is dropped
+    stopped: bool,
+}
+
+type SharedRpc = Arc<Mutex<RpcState>>;
+
+// How a client of `Node::rpc_client` reaches its service
+#[derive(Clone)]
+pub(super) struct RemoteRoute {
+    server: SocketAddr,
+    service: String,
+    // the node of the client, the replies come back to
+    node: SocketAddr,
+    rpc: SharedRpc,
+}
+
+thread_local! {
+    // the addresses of the nodes of the runtime of this OS thread
+    static LOCAL_NODES: RefCell<Vec<SocketAddr>> = const { RefCell::new(Vec::new()) };
+}
+
+// the queues of the OS threads writing to each peer, shared by the runtimes of every OS thread
+static PEERS: OnceLock<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>> = OnceLock::new();
+
+/// Listen on `addr` for other nodes, and spawn a green thread delivering the messages they
+/// send to the threads of the calling runtime, as `send` from that thread would.
+///
+/// The runtime does not end while the node listens, since messages may still arrive.
+pub fn start_node<A: ToSocketAddrs>(addr: A) -> io::Result<Node> {
+    assert!(
+        !current_ctx().is_null(),
+        "start_node is called outside of green threads"
+    );
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let (deliveries, receiver) = bridge::<Option<Incoming>>();
+    let decoders = Decoders::default();
+    let rpc = SharedRpc::default();
+
+    let readers = Readers {
+        deliveries: deliveries.clone(),
+        decoders: decoders.clone(),
+        rpc: rpc.clone(),
+    };
+    let stopping = stopped.clone();
+    thread::Builder::new()
+        .name(format!("green-node-{}", addr))
+        .spawn(move || accept(listener, readers, stopping))?;
+    spawn(
+        move || {
+            while let Some(Some(incoming)) = receiver.recv() {
+                match incoming {
+                    Incoming::Msg(id, msg) => send(id, msg),
+                    Incoming::Typed(deliver) => deliver(),
+                }
+            }
+        },
+        default_stack_size(),
+    )
+    .map_err(|err| io::Error::other(err.to_string()))?;
+
+    LOCAL_NODES.with(|nodes| nodes.borrow_mut().push(addr));
+    Ok(Node {
+        addr,
+        stopped,
+        deliveries,
+        decoders,
+        rpc,
+    })
+}
+
+impl Node {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The id other nodes send to the local thread `id` with.
+    pub fn id_of(&self, id: ThreadId) -> RemoteId {
+        RemoteId {
+            node: self.addr,
+            thread: id,
+        }
+    }
+
+    /// Accept the messages other nodes encode with `codec`, see `send_remote_typed`: they
+    /// are decoded and sent with `send_typed`. The content type of the codec picks it, so
+    /// each type of message needs a codec with a content type of its own.
+    pub fn accept_codec<T, C>(&self, codec: C)
+    where
+        T: Send + 'static,
+        C: MessageCodec<T> + Send + 'static,
+    {
+        let content_type = codec.content_type();
+        let decoder: Decoder = Box::new(move |id, bytes| {
+            let msg = codec.decode(bytes)?;
+            Ok(Incoming::Typed(Box::new(move || send_typed(id, msg))))
+        });
+        self.decoders.lock().unwrap().insert(content_type, decoder);
+    }
+
+    /// Let other nodes call the RPC service of `client` as `service`, see `rpc_client`; its
+    /// `RpcServer` handles their calls along with the local ones.
+    pub fn serve_rpc<Req, Resp, C>(&self, service: &str, client: &RpcClient<Req, Resp, C>) {
+        let requests = client
+            .local_requests()
+            .expect("the RPC service of another node cannot be served again");
+        let mut rpc = self.rpc.lock().unwrap();
+        rpc.services.insert(service.to_string(), requests);
+    }
+
+    /// A client of the RPC service `service` of the node listening on `server`, see
+    /// `serve_rpc`. The replies come back to this node, so its calls fail with
+    /// `RpcError::Disconnected` once it is stopped.
+    pub fn rpc_client<Req, Resp, C>(
+        &self,
+        server: SocketAddr,
+        service: &str,
+        codec: C,
+    ) -> RpcClient<Req, Resp, C> {
+        assert!(
+            service.len() <= u8::MAX as usize,
+            "the service name {:?} is too long",
+            service
+        );
+        let route = RemoteRoute {
+            server,
+            service: service.to_string(),
+            node: self.addr,
+            rpc: self.rpc.clone(),
+        };
+        RpcClient::remote(route, codec)
+    }
+
+    /// Stop listening; the messages read but not delivered yet are dropped.
+    pub fn stop(self) {}
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        LOCAL_NODES.with(|nodes| nodes.borrow_mut().retain(|&addr| addr != self.addr));
+        self.stopped.store(true, Ordering::Release);
+        let _ = self.deliveries.send(None);
+        // no reply can reach the calls left
+        let mut rpc = self.rpc.lock().unwrap();
+        rpc.stopped = true;
+        rpc.calls.clear();
+        rpc.services.clear();
+        drop(rpc);
+        // wake the listener up so that it sees it is stopped
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+// What the readers of the connections of a node pass the frames they read to
+#[derive(Clone)]
+struct Readers {
+    deliveries: BridgeSender<Option<Incoming>>,
+    decoders: Decoders,
+    rpc: SharedRpc,
+}
+
+// accept the connections of other nodes until the node is stopped
+fn accept(listener: TcpListener, readers: Readers, stopped: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if stopped.load(Ordering::Acquire) {
+            return;
+        }
+        if let Ok(stream) = stream {
+            let readers = readers.clone();
+            let _ = thread::Builder::new()
+                .name("green-node-reader".into())
+                .spawn(move || read_frames(stream, readers));
+        }
+    }
+}
+
+// pass the messages of a connection to the node, until it is closed or the node stopped
+fn read_frames(mut stream: TcpStream, readers: Readers) {
+    let Readers {
+        deliveries,
+        decoders,
+        rpc,
+    } = readers;
+    let u64_type = MessageCodec::<u64>::content_type(&U64Codec);
+    let mut id = [0u8; 8];
+    let mut len = [0u8; 4];
+    let mut content_type = Vec::new();
+    let mut body = Vec::new();
+    loop {
+        let mut type_len = [0u8; 1];
+        let read = stream
+            .read_exact(&mut id)
+            .and_then(|()| stream.read_exact(&mut type_len));
+        if read.is_err() {
+            return;
+        }
+        content_type.resize(type_len[0] as usize, 0);
+        if stream.read_exact(&mut content_type).is_err() || stream.read_exact(&mut len).is_err() {
+            return;
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME {
+            return;
+        }
+        body.resize(len, 0);
+        if stream.read_exact(&mut body).is_err() {
+            return;
+        }
+        let id = u64::from_le_bytes(id);
+        match std::str::from_utf8(&content_type) {
+            Ok(RPC_REQUEST) => {
+                receive_request(&rpc, &body);
+                continue;
+            }
+            Ok(RPC_REPLY) => {
+                receive_reply(&rpc, id, &body);
+                continue;
+            }
+            Ok(RPC_CANCEL) => {
+                receive_cancel(&rpc, id, &body);
+                continue;
+            }
+            _ => {}
+        }
+        let id = ThreadId(id);
+        // a message which cannot be decoded is dropped, the next frames are still valid
+        let incoming = if content_type == u64_type.as_bytes() {
+            U64Codec.decode(&body).map(|msg| Incoming::Msg(id, msg))
+        } else {
+            let decoders = decoders.lock().unwrap();
+            match std::str::from_utf8(&content_type)
+                .ok()
+                .and_then(|content_type| decoders.get(content_type))
+            {
+                Some(decode) => decode(id, &body),
+                None => continue,
+            }
+        };
+        if let Ok(incoming) = incoming {
+            if deliveries.send(Some(incoming)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Send `msg` to `to`: like `send` if its node is one of the calling runtime, otherwise
+/// encoded and written to that node, connected to on the first message.
+///
+/// Sending to another node does not wait for the network; the messages which cannot be
+/// written, as the node is unreachable, are dropped. Panics if the encoded message is
+/// larger than `MAX_FRAME`.
+pub fn send_remote(to: RemoteId, msg: u64) {
+    if is_local(to.node) {
+        return send(to.thread, msg);
+    }
+    write_frame(to, &msg, &U64Codec);
+}
+
+/// Send the typed `msg` to `to`: like `send_typed` if its node is one of the calling runtime,
+/// without encoding it, otherwise encoded with `codec` and written to that node as
+/// `send_remote` does; that node takes it with `Node::accept_codec` given the same codec.
+pub fn send_remote_typed<T, C>(to: RemoteId, msg: T, codec: &C)
+where
+    T: Send + 'static,
+    C: MessageCodec<T>,
+{
+    if is_local(to.node) {
+        return send_typed(to.thread, msg);
+    }
+    write_frame(to, &msg, codec);
+}
+
+fn is_local(node: SocketAddr) -> bool {
+    LOCAL_NODES.with(|nodes| nodes.borrow().contains(&node))
+}
+
+// encode `msg` in a frame and queue it for the writer of its node
+fn write_frame<T, C: MessageCodec<T>>(to: RemoteId, msg: &T, codec: &C) {
+    let mut frame = frame_header(to.thread.0, codec.content_type());
+    codec.encode(msg, &mut frame);
+    let len = body_len(&frame);
+    assert!(
+        len <= MAX_FRAME,
+        "the message to {} is {} bytes, more than MAX_FRAME",
+        to,
+        len
+    );
+    queue_frame(to.node, frame);
+}
+
+// the start of a frame to `id`, the body is appended to it
+fn frame_header(id: u64, content_type: &str) -> Vec<u8> {
+    assert!(
+        content_type.len() <= u8::MAX as usize,
+        "the content type {:?} is too long",
+        content_type
+    );
+    let mut frame = Vec::with_capacity(32);
+    frame.extend_from_slice(&id.to_le_bytes());
+    frame.push(content_type.len() as u8);
+    frame.extend_from_slice(content_type.as_bytes());
+    frame.extend_from_slice(&[0; 4]);
+    frame
+}
+
+// the length of the body of `frame`, after its header
+fn body_len(frame: &[u8]) -> usize {
+    frame.len() - body_start(frame)
+}
+
+fn body_start(frame: &[u8]) -> usize {
+    9 + frame[8] as usize + 4
+}
+
+// fill in the length of the body of `frame`, at most MAX_FRAME, and queue it for the writer
+// of `node`
+fn queue_frame(node: SocketAddr, mut frame: Vec<u8>) {
+    let start = body_start(&frame);
+    let len = (frame.len() - start) as u32;
+    frame[start - 4..start].copy_from_slice(&len.to_le_bytes());
+
+    let mut peers = PEERS
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap();
+    let writer = match peers.entry(node) {
+        Entry::Occupied(writer) => writer.into_mut(),
+        Entry::Vacant(entry) => {
+            let (sender, receiver) = mpsc::channel();
+            let spawned = thread::Builder::new()
+                .name(format!("green-peer-{}", node))
+                .spawn(move || write_frames(node, receiver));
+            // dropped as if the node were unreachable, the next frame tries again
+            if spawned.is_err() {
+                return;
+            }
+            entry.insert(sender)
+        }
+    };
+    let _ = writer.send(frame);
+}
+
+// write the frames sent to `node`, connecting again after an error; kept for the life
+// of the process
+fn write_frames(node: SocketAddr, frames: mpsc::Receiver<Vec<u8>>) {
+    let mut stream: Option<TcpStream> = None;
+    for frame in frames {
+        if stream.is_none() {
+            stream = TcpStream::connect(node).ok();
+            if let Some(stream) = &stream {
+                let _ = stream.set_nodelay(true);
+            }
+        }
+        if let Some(connected) = &mut stream {
+            if connected.write_all(&frame).is_err() {
+                stream = None;
+            }
+        }
+    }
+}
+
+impl RemoteRoute {
+    // send the request of a new call, whose reply is passed to `reply`, and return its id
+    pub(super) fn start(
+        &self,
+        body: &[u8],
+        timeout: Option<Duration>,
+        reply: BridgeSender<RpcReply>,
+    ) -> Result<u64, RpcError> {
+        // the header of the request, at most 2 addresses of 255 bytes
+        if body.len() + 16 + 2 * 256 > MAX_FRAME {
+            return Err(RpcError::TooLarge(body.len()));
+        }
+        let call = {
+            let mut rpc = self.rpc.lock().unwrap();
+            if rpc.stopped {
+                return Err(RpcError::Disconnected);
+            }
+            let call = rpc.next_call;
+            rpc.next_call += 1;
+            rpc.calls.insert(call, reply);
+            call
+        };
+        // in whole milliseconds, rounded up
+        let timeout = timeout.map_or(NO_TIMEOUT, |timeout| {
+            timeout
+                .as_micros()
+                .div_ceil(1000)
+                .min(NO_TIMEOUT as u128 - 1) as u64
+        });
+        let mut frame = frame_header(0, RPC_REQUEST);
+        frame.extend_from_slice(&call.to_le_bytes());
+        frame.extend_from_slice(&timeout.to_le_bytes());
+        push_str(&mut frame, &self.node.to_string());
+        push_str(&mut frame, &self.service);
+        frame.extend_from_slice(body);
+        queue_frame(self.server, frame);
+        Ok(call)
+    }
+
+    // the client gave up on `call`: drop its reply, and let the server skip it
+    pub(super) fn cancel(&self, call: u64) {
+        if self.rpc.lock().unwrap().calls.remove(&call).is_none() {
+            // replied to already
+            return;
+        }
+        let mut frame = frame_header(call, RPC_CANCEL);
+        push_str(&mut frame, &self.node.to_string());
+        queue_frame(self.server, frame);
+    }
+
+    // drop the reply of `call` if it still comes
+    pub(super) fn forget(&self, call: u64) {
+        self.rpc.lock().unwrap().calls.remove(&call);
+    }
+}
+
+// The reply to a call of another node, written back to it once dropped; Disconnected if the
+// request is dropped unanswered
+struct RemoteReply {
+    rpc: SharedRpc,
+    client: SocketAddr,
+    call: u64,
+    reply: Option<RpcReply>,
+}
+
+impl RemoteReply {
+    fn send(mut self, reply: RpcReply) {
+        self.reply = Some(reply);
+    }
+}
+
+impl Drop for RemoteReply {
+    fn drop(&mut self) {
+        self.rpc
+            .lock()
+            .unwrap()
+            .running
+            .remove(&(self.client, self.call));
+        let reply = match self.reply.take() {
+            Some(Ok(body)) if body.len() + 1 > MAX_FRAME => Err(RpcError::TooLarge(body.len())),
+            Some(reply) => reply,
+            None => Err(RpcError::Disconnected),
+        };
+        let mut frame = frame_header(self.call, RPC_REPLY);
+        match reply {
+            Ok(body) => {
+                frame.push(0);
+                frame.extend_from_slice(&body);
+            }
+            Err(RpcError::Timeout) => frame.push(1),
+            Err(RpcError::Cancelled) => frame.push(2),
+            Err(RpcError::Disconnected) => frame.push(3),
+            Err(RpcError::Codec(CodecError::Length { expected, found })) => {
+                frame.push(4);
+                frame.extend_from_slice(&(expected as u64).to_le_bytes());
+                frame.extend_from_slice(&(found as u64).to_le_bytes());
+            }
+            Err(RpcError::Codec(CodecError::Invalid(reason))) => {
+                frame.push(5);
+                // cut to fit, on a character boundary
+                let mut len = reason.len().min(MAX_FRAME - 1);
+                while !reason.is_char_boundary(len) {
+                    len -= 1;
+                }
+                frame.extend_from_slice(&reason.as_bytes()[..len]);
+            }
+            Err(RpcError::TooLarge(len)) => {
+                frame.push(6);
+                frame.extend_from_slice(&(len as u64).to_le_bytes());
+            }
+        }
+        queue_frame(self.client, frame);
+    }
+}
+
+// pass a request of another node to its service; a malformed one is dropped
+fn receive_request(rpc: &SharedRpc, body: &[u8]) {
+    let mut body = body;
+    let request = (|| {
+        let call = take_u64(&mut body)?;
+        let timeout = take_u64(&mut body)?;
+        let client = take_str(&mut body)?.parse::<SocketAddr>().ok()?;
+        let service = take_str(&mut body)?;
+        Some((call, timeout, client, service))
+    })();
+    let (call, timeout, client, service) = match request {
+        Some(request) => request,
+        None => return,
+    };
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let requests = {
+        let mut state = rpc.lock().unwrap();
+        let requests = state.services.get(service).cloned();
+        if requests.is_some() {
+            state.running.insert((client, call), cancelled.clone());
+        }
+        requests
+    };
+    let reply = RemoteReply {
+        rpc: rpc.clone(),
+        client,
+        call,
+        reply: None,
+    };
+    let requests = match requests {
+        Some(requests) => requests,
+        // no such service, replied to as Disconnected
+        None => return,
+    };
+    let request = RpcRequest {
+        deadline: (timeout != NO_TIMEOUT)
+            .then(|| Instant::now().checked_add(Duration::from_millis(timeout)))
+            .flatten(),
+        body: body.to_vec(),
+        cancelled,
+        reply: Box::new(move |result| reply.send(result)),
+    };
+    // a request the server drops is replied to as Disconnected
+    let _ = requests.send(request);
+}
+
+// pass the reply of another node to the call waiting for it, if it still does
+fn receive_reply(rpc: &SharedRpc, call: u64, body: &[u8]) {
+    let reply = decode_reply(body).unwrap_or_else(|| {
+        Err(RpcError::Codec(CodecError::Invalid(
+            "malformed RPC reply".to_string(),
+        )))
+    });
+    let waiting = rpc.lock().unwrap().calls.remove(&call);
+    if let Some(waiting) = waiting {
+        let _ = waiting.send(reply);
+    }
+}
+
+fn decode_reply(body: &[u8]) -> Option<RpcReply> {
+    let (&status, mut body) = body.split_first()?;
+    Some(match status {
+        0 => Ok(body.to_vec()),
+        1 => Err(RpcError::Timeout),
+        2 => Err(RpcError::Cancelled),
+        3 => Err(RpcError::Disconnected),
+        4 => Err(RpcError::Codec(CodecError::Length {
+            expected: take_u64(&mut body)? as usize,
+            found: take_u64(&mut body)? as usize,
+        })),
+        5 => Err(RpcError::Codec(CodecError::Invalid(
+            String::from_utf8_lossy(body).into_owned(),
+        ))),
+        6 => Err(RpcError::TooLarge(take_u64(&mut body)? as usize)),
+        _ => return None,
+    })
+}
+
+// let the server skip a call its client gave up on
+fn receive_cancel(rpc: &SharedRpc, call: u64, body: &[u8]) {
+    let mut body = body;
+    let client = match take_str(&mut body).and_then(|client| client.parse::<SocketAddr>().ok()) {
+        Some(client) => client,
+        None => return,
+    };
+    let running = rpc.lock().unwrap().running.remove(&(client, call));
+    if let Some(cancelled) = running {
+        cancelled.store(true, Ordering::Release);
+    }
+}
+
+// append `s` with its length in a byte
+fn push_str(frame: &mut Vec<u8>, s: &str) {
+    frame.push(s.len() as u8);
+    frame.extend_from_slice(s.as_bytes());
+}
+
+// split the first `n` bytes off `bytes`
+fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if bytes.len() < n {
+        return None;
+    }
+    let (head, tail) = bytes.split_at(n);
+    *bytes = tail;
+    Some(head)
+}
+
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    take(bytes, 8).map(|head| u64::from_le_bytes(head.try_into().unwrap()))
+}
+
+fn take_str<'a>(bytes: &mut &'a [u8]) -> Option<&'a str> {
+    let len = take(bytes, 1)?[0] as usize;
+    std::str::from_utf8(take(bytes, len)?).ok()
+}
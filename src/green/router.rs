@@ -0,0 +1,132 @@
+// Routers: sets of threads doing the same work, a message sent to one of them chosen by
+// a policy, so that producers need not keep their ids.
+
+use super::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// How a `Router` chooses the target of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /// each target in turn
+    RoundRobin,
+    /// the target with the fewest messages waiting in its mailbox, the first one on a tie
+    LeastMailbox,
+    /// the target given by a hash of the key of the message, the same for a key as long as
+    /// that target is in the router; adding or removing a target only moves its own keys
+    ConsistentHash,
+}
+
+/// A set of target threads, each message sent to one of them as its `Routing` says.
+///
+/// The targets which ended are dropped as messages are routed.
+#[derive(Debug, Clone)]
+pub struct Router {
+    targets: Vec<ThreadId>,
+    routing: Routing,
+    // the next target of the round robin
+    next: usize,
+}
+
+impl Router {
+    pub fn new(targets: Vec<ThreadId>, routing: Routing) -> Self {
+        Router {
+            targets,
+            routing,
+            next: 0,
+        }
+    }
+
+    pub fn targets(&self) -> &[ThreadId] {
+        &self.targets
+    }
+
+    /// Add `id` to the targets; false if it is one already.
+    pub fn add(&mut self, id: ThreadId) -> bool {
+        if self.targets.contains(&id) {
+            return false;
+        }
+        self.targets.push(id);
+        true
+    }
+
+    /// Remove `id` from the targets; false if it was not one.
+    pub fn remove(&mut self, id: ThreadId) -> bool {
+        let len = self.targets.len();
+        self.targets.retain(|&target| target != id);
+        self.targets.len() < len
+    }
+
+    /// The target of a message with `key`, which only `Routing::ConsistentHash` looks at;
+    /// None if no target is live. Routing a message by hand, e.g. a typed one, to the
+    /// returned target counts it for the round robin.
+    pub fn route<K: Hash + ?Sized>(&mut self, key: &K) -> Option<ThreadId> {
+        if !runtime_ptr().is_null() {
+            let ids = unsafe { &rt().ids };
+            self.targets.retain(|id| ids.contains(id));
+        }
+        if self.targets.is_empty() {
+            return None;
+        }
+        let target = match self.routing {
+            Routing::RoundRobin => {
+                let target = self.targets[self.next % self.targets.len()];
+                self.next = self.next.wrapping_add(1);
+                target
+            }
+            Routing::LeastMailbox => *self
+                .targets
+                .iter()
+                .min_by_key(|&&id| mailbox_len(id))
+                .unwrap(),
+            Routing::ConsistentHash => {
+                // rendezvous hashing: the target scoring highest with the key
+                let key = hash_of(key);
+                *self
+                    .targets
+                    .iter()
+                    .max_by_key(|&&id| hash_of(&(key, id)))
+                    .unwrap()
+            }
+        };
+        Some(target)
+    }
+
+    /// Send `msg` to the target routed with the message as its key, and return that target;
+    /// None if no target is live, and the message is dropped.
+    pub fn send(&mut self, msg: u64) -> Option<ThreadId> {
+        self.send_keyed(&msg, msg)
+    }
+
+    /// Send `msg` to the target routed with `key`, and return that target; None if no
+    /// target is live, and the message is dropped.
+    pub fn send_keyed<K: Hash + ?Sized>(&mut self, key: &K, msg: u64) -> Option<ThreadId> {
+        let target = self.route(key)?;
+        send(target, msg);
+        Some(target)
+    }
+}
+
+// with the fixed keys of `DefaultHasher::new`, the same on every run
+fn hash_of<K: Hash + ?Sized>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Spawn a green thread forwarding the messages sent to it with `router.send`, so that
+/// producers send to a single id; the targets see it as the sender.
+///
+/// It ends once every target has ended.
+pub fn spawn_router(mut router: Router, stack_size: usize) -> Result<ThreadId, SpawnError> {
+    spawn(
+        move || {
+            while let Some(msg) = recv() {
+                if router.send(msg).is_none() {
+                    break;
+                }
+            }
+        },
+        stack_size,
+    )
+}
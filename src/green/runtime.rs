@@ -0,0 +1,315 @@
+// The runtime: the state of a run of green threads, owned by `RuntimeBuilder::run`
+// from start to end, and the builder configuring it.
+
+use super::*;
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// The state of a run of green threads: their contexts, queues and mailboxes.
+///
+/// Built and run by `Runtime::builder().run(entry)`, which returns once every thread
+/// has ended or is waiting forever. Every OS thread may run a runtime of its own,
+/// independent of the others but for `RemoteSender`s and the channels of `sync`.
+//
+// A runtime is owned by the `run` call which built it, and only reached through the
+// pointer of its OS thread's `RUNTIME_SLOT`, set for as long as it runs. Its raw pointers
+// make it neither Send nor Sync, so it is only ever touched by that OS thread.
+pub struct Runtime {
+    // the registers of the OS thread which started the run, resumed when it ends
+    pub(super) main: Registers,
+    // the context of the running green thread (the front of `contexts`), updated at every
+    // switch; null while the main context runs
+    pub(super) current: *mut Context,
+    // execution queue, the running thread in front
+    pub(super) contexts: VecDeque<Box<Context>>,
+    // threads waiting for a message or another thread, by Thread ID
+    pub(super) waiting: HashMap<ThreadId, Box<Context>>,
+    // ids of the live threads
+    pub(super) ids: HashSet<ThreadId>,
+    // the messages of `send` with their senders, by receiver
+    pub(super) messages: MappedList<Envelope>,
+    // the `MappedList`s of the messages of `send_typed`, by the type of their messages
+    pub(super) typed: HashMap<TypeId, Box<dyn TypedList>>,
+    // the requests of `ask` waiting for their replies, by tag
+    pub(super) asks: HashMap<u64, PendingAsk>,
+    pub(super) next_ask: u64,
+    // point-to-point links, keyed by the receiver's Thread ID
+    pub(super) links: HashMap<ThreadId, Link>,
+    // the messages which could not be delivered, and the threads waiting for them
+    pub(super) dead_letters: VecDeque<DeadLetter>,
+    pub(super) dead_letter_waiters: Vec<ThreadId>,
+    // the bounded mailboxes, see `set_mailbox_capacity`
+    pub(super) bounds: HashMap<ThreadId, Bound>,
+    // the messages RemoteSenders queued for each thread, not counted by its bound yet
+    pub(super) remote_arrivals: HashMap<ThreadId, Arc<AtomicUsize>>,
+    // wakeups from other OS threads
+    pub(super) remote: Arc<Remote>,
+    // how the finished threads ended
+    pub(super) exits: HashMap<ThreadId, ExitStatus>,
+    // the keys of `exits` from the oldest, to forget it past `MAX_EXITS`
+    pub(super) exit_order: VecDeque<ThreadId>,
+    // the threads blocked in wait_for_exit, by the thread they wait for
+    pub(super) exit_waiters: HashMap<ThreadId, Vec<ThreadId>>,
+    // called with how a thread ended, see `observe_exit`
+    pub(super) exit_observers: HashMap<ThreadId, Vec<ExitObserver>>,
+    // the live threads spawned by each thread
+    pub(super) children: HashMap<ThreadId, Vec<ThreadId>>,
+    // the subscribers of each topic of `publish`, and the topics of each subscriber
+    pub(super) topics: HashMap<String, Vec<ThreadId>>,
+    pub(super) subscriptions: HashMap<ThreadId, Vec<String>>,
+    // the members of each group of `create_group`, and the groups of each member
+    pub(super) groups: HashMap<GroupId, Vec<ThreadId>>,
+    pub(super) memberships: HashMap<ThreadId, Vec<GroupId>>,
+    pub(super) next_group: u64,
+    // the threads linked with each thread, see `link`
+    pub(super) linked: HashMap<ThreadId, Vec<ThreadId>>,
+    // the threads trapping exits, see `trap_exit`
+    pub(super) trapping: HashSet<ThreadId>,
+    // the monitors of each thread with the threads they tell, see `monitor`
+    pub(super) monitors: HashMap<ThreadId, Vec<(MonitorRef, ThreadId)>>,
+    pub(super) next_monitor: u64,
+    // the supervisor of each supervised thread, told when it ends
+    pub(super) supervisors: HashMap<ThreadId, ThreadId>,
+    // the live threads registered by `spawn_named`
+    pub(super) names: HashMap<String, ThreadId>,
+    // the threads to wake at a deadline
+    pub(super) timers: TimerWheel,
+    // the threads parked in spawn until a thread ends, in order of arrival
+    pub(super) slot_waiters: VecDeque<ThreadId>,
+    // finished contexts kept with their guarded stacks, so that spawning does not allocate
+    // nor call mprotect; the stacks are only unprotected when freed at shutdown or eviction.
+    // Contexts stay boxed, so that they keep their address moving between queues and pool
+    #[allow(clippy::vec_box)]
+    pub(super) pool: Vec<Box<Context>>,
+    // finished contexts whose stacks are reclaimed in batches, once we have left them
+    #[allow(clippy::vec_box)]
+    pub(super) unused: Vec<Box<Context>>,
+    // the number of context switches since the last reclamation
+    pub(super) switches_since_reclaim: u64,
+    pub(super) pool_stats: PoolStats,
+    // the counters of the run, the times are filled at the end
+    pub(super) report: RunReport,
+    pub(super) default_stack_size: usize,
+    // the number of spins of an idle scheduler before parking the OS thread
+    pub(super) spin_budget: u32,
+    // the maximum number of live green threads, and what spawning beyond it does
+    pub(super) max_threads: Option<usize>,
+    pub(super) limit_policy: LimitPolicy,
+}
+
+// The runtime running on this OS thread, null outside of `RuntimeBuilder::run`;
+// each OS thread may run its own
+#[inline(always)]
+pub(super) fn runtime_ptr() -> *mut Runtime {
+    host_local(RUNTIME_SLOT) as *mut Runtime
+}
+
+// The runtime running on this OS thread, panics if none is, so that the public
+// functions built on it are safe to call anywhere.
+//
+// Safety: the reference is not really 'static, and every call makes a new one which
+// invalidates the previous ones. It must not be kept across a context switch, nor across
+// a call which may use the runtime itself (user code included); take one reference with
+// `let runtime = rt()` where borrows of several fields have to live together.
+#[inline(always)]
+pub(super) unsafe fn rt() -> &'static mut Runtime {
+    let runtime = runtime_ptr();
+    if runtime.is_null() {
+        outside_runtime();
+    }
+    &mut *runtime
+}
+
+#[cold]
+#[inline(never)]
+fn outside_runtime() -> ! {
+    panic!("green threads are used outside of a runtime");
+}
+
+// the context of the running green thread, null outside of green threads; a runtime only
+// runs user code in its green threads, so it is never null where `rt()` succeeded in them
+#[inline(always)]
+pub(super) fn current_ctx() -> *mut Context {
+    let runtime = runtime_ptr();
+    if runtime.is_null() {
+        return ptr::null_mut();
+    }
+    unsafe { (*runtime).current }
+}
+
+/// The configuration of a `Runtime`.
+#[derive(Debug, Clone)]
+pub struct RuntimeBuilder {
+    default_stack_size: usize,
+    spin_budget: u32,
+    max_threads: Option<usize>,
+    limit_policy: LimitPolicy,
+}
+
+impl Runtime {
+    pub fn builder() -> RuntimeBuilder {
+        RuntimeBuilder {
+            default_stack_size: DEFAULT_STACK_SIZE,
+            spin_budget: 1000,
+            max_threads: None,
+            limit_policy: LimitPolicy::Park,
+        }
+    }
+
+    fn new(config: RuntimeBuilder) -> Self {
+        Runtime {
+            main: new_registers(ptr::null_mut(), 0),
+            current: ptr::null_mut(),
+            contexts: VecDeque::new(),
+            waiting: HashMap::new(),
+            ids: HashSet::new(),
+            messages: MappedList::new(),
+            typed: HashMap::new(),
+            asks: HashMap::new(),
+            next_ask: 0,
+            links: HashMap::new(),
+            dead_letters: VecDeque::new(),
+            dead_letter_waiters: Vec::new(),
+            bounds: HashMap::new(),
+            remote_arrivals: HashMap::new(),
+            remote: Arc::new(Remote {
+                wakeups: MpscQueue::new(),
+                pending: AtomicBool::new(false),
+                senders: AtomicUsize::new(0),
+                thread: Mutex::new(thread::current()),
+            }),
+            exits: HashMap::new(),
+            exit_order: VecDeque::new(),
+            exit_waiters: HashMap::new(),
+            exit_observers: HashMap::new(),
+            children: HashMap::new(),
+            topics: HashMap::new(),
+            subscriptions: HashMap::new(),
+            groups: HashMap::new(),
+            memberships: HashMap::new(),
+            next_group: 0,
+            linked: HashMap::new(),
+            trapping: HashSet::new(),
+            monitors: HashMap::new(),
+            next_monitor: 0,
+            supervisors: HashMap::new(),
+            names: HashMap::new(),
+            timers: TimerWheel::new(),
+            slot_waiters: VecDeque::new(),
+            pool: Vec::with_capacity(MAX_POOLED_CONTEXTS),
+            unused: Vec::with_capacity(MAX_POOLED_CONTEXTS),
+            switches_since_reclaim: 0,
+            pool_stats: PoolStats {
+                capacity: MAX_POOLED_CONTEXTS,
+                ..PoolStats::default()
+            },
+            report: RunReport::default(),
+            default_stack_size: config.default_stack_size,
+            spin_budget: config.spin_budget,
+            max_threads: config.max_threads,
+            limit_policy: config.limit_policy,
+        }
+    }
+}
+
+impl Default for RuntimeBuilder {
+    fn default() -> Self {
+        Runtime::builder()
+    }
+}
+
+impl RuntimeBuilder {
+    /// The stack size of the first thread, and of the threads spawned by helpers
+    /// which do not take one, like `join`; `DEFAULT_STACK_SIZE` if not set.
+    pub fn default_stack_size(mut self, stack_size: usize) -> Self {
+        self.default_stack_size = stack_size;
+        self
+    }
+    /// How many times an idle scheduler polls for remote messages before parking the OS thread.
+    pub fn spin_budget(mut self, spins: u32) -> Self {
+        self.spin_budget = spins;
+        self
+    }
+    /// Cap the number of live green threads, see `set_max_threads`.
+    pub fn max_threads(mut self, max: Option<usize>, policy: LimitPolicy) -> Self {
+        assert!(
+            max != Some(0),
+            "the maximum number of threads must be positive"
+        );
+        self.max_threads = max;
+        self.limit_policy = policy;
+        self
+    }
+
+    /// Run `entry` as the first green thread on the calling OS thread, and return
+    /// once no thread is executable anymore; threads left waiting are dropped.
+    pub fn run<F: FnOnce() + 'static>(self, entry: F) -> RunReport {
+        unsafe {
+            assert!(
+                runtime_ptr().is_null(),
+                "a runtime is already running on this OS thread"
+            );
+            install_overflow_handler();
+            let started = Instant::now();
+            let cpu_started = thread_cpu_time();
+
+            let stack_size = self.default_stack_size;
+            // leaked for the run, so that the slot holds the only pointer to it
+            let runtime = Box::into_raw(Box::new(Runtime::new(self)));
+            set_host_local(RUNTIME_SLOT, runtime as *mut ());
+
+            let mut first = match alloc_context(
+                Box::new(entry),
+                TypeId::of::<F>(),
+                stack_size,
+                ThreadId(0),
+                true,
+            ) {
+                Ok(first) => first,
+                Err(err) => {
+                    set_host_local(RUNTIME_SLOT, ptr::null_mut());
+                    drop(Box::from_raw(runtime));
+                    panic!("cannot spawn the first green thread: {}", err);
+                }
+            };
+            first.id = get_id();
+            rt().contexts.push_back(first);
+            let first = next_context();
+            swap_context(
+                &mut rt().main,
+                (*first).get_regs(),
+                fp_flags(true, (*first).uses_fp),
+            );
+            profile::end();
+
+            reclaim_unused_stacks();
+            set_host_local(RUNTIME_SLOT, ptr::null_mut());
+
+            // the threads left waiting are dropped with it, outside of the runtime
+            let mut runtime = Box::from_raw(runtime);
+            let report = std::mem::take(&mut runtime.report);
+            drop(runtime);
+            RunReport {
+                wall_time: started.elapsed(),
+                cpu_time: thread_cpu_time().saturating_sub(cpu_started),
+                ..report
+            }
+        }
+    }
+}
+
+/// The stack size for threads spawned without one: the default of the running runtime,
+/// or `DEFAULT_STACK_SIZE` outside of a runtime.
+pub fn default_stack_size() -> usize {
+    unsafe {
+        if runtime_ptr().is_null() {
+            return DEFAULT_STACK_SIZE;
+        }
+        rt().default_stack_size
+    }
+}
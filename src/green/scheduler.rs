@@ -0,0 +1,1461 @@
+// Green threads: their contexts and stacks, the run queue and the waiting queue,
+// spawning, exiting and killing, and the entry point of the runtime.
+
+use super::*;
+#[cfg(unix)]
+use nix::time::{clock_gettime, ClockId};
+use std::alloc::{alloc, dealloc, Layout};
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+#[cfg(windows)]
+use std::ffi::c_void;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// the function a green thread runs, with whatever it captured
+pub(super) type BoxEntry = Box<dyn FnOnce()>;
+
+pub(super) type BoxFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// The identity of a green thread, unique among the live threads of its runtime.
+///
+/// Returned by `spawn` and taken by `send`, so that it cannot be mixed up with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ThreadId(pub(super) u64);
+
+impl std::fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The stack size of the threads spawned by helpers which do not take one, like `join`.
+pub const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// The number of ended threads whose `exit_status` is kept, the oldest one forgotten
+/// when another thread ends.
+pub const MAX_EXITS: usize = 4096;
+
+// The maximum number of finished contexts kept for reuse by `spawn`
+pub(super) const MAX_POOLED_CONTEXTS: usize = 64;
+
+// The number of context switches between two reclamations of finished contexts
+pub(super) const RECLAIM_INTERVAL: u64 = 64;
+
+// registers starting a green thread at `entry_point`
+pub(super) fn new_registers(stack: *mut u8, stack_size: usize) -> Registers {
+    with_entry(stack, stack_size, entry_point)
+}
+
+// hot fields touched on every switch come first, right after the registers
+#[repr(C, align(64))]
+pub(super) struct Context {
+    regs: Registers,
+    pub(super) id: ThreadId,
+    // false if the thread never uses the floating-point registers across a switch
+    pub(super) uses_fp: bool,
+    // taken by `entry_point` when the thread starts
+    entry: Option<BoxEntry>,
+    // the type of the spawned function, which names its factory in snapshots
+    kind: TypeId,
+    stack: *mut u8,
+    stack_layout: Layout,
+    // the future driven by the thread, if spawned by spawn_future
+    pub(super) future: Option<BoxFuture>,
+    // closures registered by at_exit, run in reverse order when the thread ends
+    at_exit: Vec<Box<dyn FnOnce()>>,
+    // the thread which spawned this one, None for the first thread
+    parent: Option<ThreadId>,
+    // set by cancel_children_on_exit
+    cancel_children: bool,
+    // the name the thread is registered under, see `spawn_named`
+    name: Option<String>,
+    // the earliest deadline of the `timeout`s the thread is in
+    pub(super) deadline: Option<Instant>,
+}
+
+// the layout the assembly and the cache-line grouping rely on
+const _: () = assert!(std::mem::align_of::<Registers>() == CACHE_LINE_SIZE);
+const _: () = assert!(std::mem::align_of::<Context>() == CACHE_LINE_SIZE);
+
+impl Context {
+    fn get_regs_mut(&mut self) -> *mut Registers {
+        &mut self.regs as *mut Registers
+    }
+    pub(super) fn get_regs(&self) -> *const Registers {
+        &self.regs as *const Registers
+    }
+    fn new(
+        func: BoxEntry,
+        kind: TypeId,
+        stack_size: usize,
+        id: ThreadId,
+        uses_fp: bool,
+    ) -> Result<Self, SpawnError> {
+        if stack_size < MIN_STACK_SIZE {
+            return Err(SpawnError::InvalidStackSize(stack_size));
+        }
+        let layout = Layout::from_size_align(stack_size, PAGE_SIZE)
+            .map_err(|_| SpawnError::InvalidStackSize(stack_size))?;
+        let stack = unsafe { alloc(layout) };
+        if stack.is_null() {
+            return Err(SpawnError::OutOfMemory);
+        }
+
+        // the kernel may fail to split the mapping, which is running out of memory too
+        if unsafe { protect_guard_page(stack) }.is_err() {
+            unsafe { dealloc(stack, layout) };
+            return Err(SpawnError::OutOfMemory);
+        }
+
+        let regs = new_registers(stack, stack_size);
+
+        Ok(Context {
+            regs,
+            stack,
+            stack_layout: layout,
+            entry: Some(func),
+            kind,
+            id,
+            uses_fp,
+            future: None,
+            at_exit: Vec::new(),
+            parent: None,
+            cancel_children: false,
+            name: None,
+            deadline: None,
+        })
+    }
+
+    // reinitialize a pooled context in place so that it runs `func` from the top of its stack;
+    // the guard page is still protected, so this needs no system call
+    fn reset(&mut self, func: BoxEntry, kind: TypeId, id: ThreadId, uses_fp: bool) {
+        let stack = self.stack;
+        let stack_size = self.stack_layout.size();
+
+        self.regs = new_registers(stack, stack_size);
+        self.entry = Some(func);
+        self.kind = kind;
+        self.id = id;
+        self.uses_fp = uses_fp;
+        self.future = None;
+        self.at_exit.clear();
+        self.parent = None;
+        self.cancel_children = false;
+        self.name = None;
+        self.deadline = None;
+    }
+
+    // whether `addr` is in the guard page of the stack, see `overflow`
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64"),
+        not(feature = "software")
+    ))]
+    pub(super) fn in_guard_page(&self, addr: usize) -> bool {
+        let guard = self.stack as usize;
+        (guard..guard + PAGE_SIZE).contains(&addr)
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64"),
+        not(feature = "software")
+    ))]
+    pub(super) fn stack_top(&self) -> usize {
+        self.stack as usize + self.stack_layout.size()
+    }
+
+    // a panicking hook does not prevent the others from running
+    pub(super) fn run_exit_hooks(&mut self) {
+        while let Some(hook) = self.at_exit.pop() {
+            let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(hook));
+        }
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe {
+            unprotect_guard_page(self.stack);
+            dealloc(self.stack, self.stack_layout);
+        }
+    }
+}
+
+// an entry function threads can be restored from, by name, with its type
+pub(super) type Factory = (&'static str, TypeId, Arc<dyn Fn() + Send + Sync>);
+
+// shared by the runtimes of every OS thread
+pub(super) static FACTORIES: Mutex<Vec<Factory>> = Mutex::new(Vec::new());
+
+/// Occupancy and effectiveness of the context pool.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolStats {
+    /// contexts ready to be reused by `spawn`
+    pub pooled: usize,
+    /// finished contexts waiting to be reclaimed into the pool
+    pub pending: usize,
+    /// the maximum number of pooled contexts
+    pub capacity: usize,
+    /// spawns which reused a pooled context, and so did not allocate
+    pub hits: u64,
+    /// spawns which had to allocate a new context and stack
+    pub misses: u64,
+    /// finished contexts freed because the pool was full
+    pub evictions: u64,
+}
+
+/// Statistics of the context pool of the running runtime, all zero outside of one.
+pub fn pool_stats() -> PoolStats {
+    unsafe {
+        if runtime_ptr().is_null() {
+            return PoolStats::default();
+        }
+        PoolStats {
+            pooled: rt().pool.len(),
+            pending: rt().unused.len(),
+            ..rt().pool_stats
+        }
+    }
+}
+
+/// What happened during a run of a `Runtime`.
+#[derive(Debug, Clone, Default)]
+pub struct RunReport {
+    /// threads created, including the first one
+    pub spawned: u64,
+    /// the highest number of live threads at once
+    pub peak_threads: usize,
+    /// messages delivered by green threads (messages of remote senders are not counted)
+    pub delivered: u64,
+    /// messages dropped by full mailboxes, see `OverflowPolicy`, and typed messages sent to
+    /// threads which had ended
+    pub dropped: u64,
+    /// threads ended by `kill`, `cancel_tree` or a simulated crash
+    pub killed: u64,
+    pub wall_time: Duration,
+    /// the CPU time of the OS thread running the green threads
+    pub cpu_time: Duration,
+}
+
+#[cfg(unix)]
+pub(super) fn thread_cpu_time() -> Duration {
+    clock_gettime(ClockId::CLOCK_THREAD_CPUTIME_ID).map_or(Duration::ZERO, Duration::from)
+}
+
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetCurrentThread() -> *mut c_void;
+    fn GetThreadTimes(
+        thread: *mut c_void,
+        creation: *mut u64,
+        exit: *mut u64,
+        kernel: *mut u64,
+        user: *mut u64,
+    ) -> i32;
+}
+
+// the kernel and user time of the OS thread, counted by Windows in units of 100ns
+#[cfg(windows)]
+pub(super) fn thread_cpu_time() -> Duration {
+    let (mut creation, mut exit, mut kernel, mut user) = (0, 0, 0, 0);
+    let ok = unsafe {
+        GetThreadTimes(
+            GetCurrentThread(),
+            &mut creation,
+            &mut exit,
+            &mut kernel,
+            &mut user,
+        )
+    };
+    if ok == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos((kernel + user) * 100)
+}
+
+/// Set how many times the idle scheduler of the running runtime polls for remote messages
+/// before parking the OS thread; see `RuntimeBuilder::spin_budget` to set it up front.
+pub fn set_spin_budget(spins: u32) {
+    unsafe {
+        assert!(
+            !runtime_ptr().is_null(),
+            "set_spin_budget is called outside of a runtime"
+        );
+        rt().spin_budget = spins;
+    }
+}
+
+/// What `spawn` does when the maximum number of live threads is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// park the spawning thread until another thread ends
+    Park,
+    /// panic
+    Fail,
+}
+
+/// Cap the number of live green threads (running, executable or waiting) of the running
+/// runtime to `max`, or remove the cap with None; see `RuntimeBuilder::max_threads`.
+/// `try_spawn` never blocks at the cap, whatever the policy.
+pub fn set_max_threads(max: Option<usize>, policy: LimitPolicy) {
+    assert!(
+        max != Some(0),
+        "the maximum number of threads must be positive"
+    );
+    unsafe {
+        assert!(
+            !runtime_ptr().is_null(),
+            "set_max_threads is called outside of a runtime"
+        );
+        rt().max_threads = max;
+        rt().limit_policy = policy;
+    }
+}
+
+pub(super) unsafe fn at_thread_limit() -> bool {
+    let runtime = rt();
+    runtime
+        .max_threads
+        .is_some_and(|max| runtime.ids.len() >= max)
+}
+
+// block spawning as the limit policy says until a new thread may be created
+pub(super) unsafe fn wait_for_slot() {
+    // a slot handed over to a thread leaving by a timeout would be lost for the others,
+    // so spawning is not cut short; the timer firing is only a spurious wakeup here
+    let ctx = current_ctx();
+    let id = (*ctx).id;
+    let deadline = (*ctx).deadline.take();
+    // woken otherwise and finding a slot free, it no longer waits for the next one
+    let _registered = Deregister(|| rt().slot_waiters.retain(|&other| other != id));
+    while at_thread_limit() {
+        match rt().limit_policy {
+            LimitPolicy::Park => {
+                if !rt().slot_waiters.contains(&id) {
+                    rt().slot_waiters.push_back(id);
+                }
+                wait();
+            }
+            LimitPolicy::Fail => panic!("the maximum number of green threads is reached"),
+        }
+    }
+    (*ctx).deadline = deadline;
+}
+
+/// Error returned when a green thread cannot be spawned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpawnError {
+    /// the maximum number of live threads is reached, see `set_max_threads`
+    WouldBlock,
+    /// no runtime is running on the calling OS thread
+    NoRuntime,
+    /// the stack size is below `MIN_STACK_SIZE`, or too large to be laid out
+    InvalidStackSize(usize),
+    /// the stack could not be allocated, or its guard page could not be protected
+    OutOfMemory,
+    /// a live thread is already registered under this name, see `spawn_named`
+    NameInUse(String),
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnError::WouldBlock => write!(f, "the maximum number of green threads is reached"),
+            SpawnError::NoRuntime => write!(f, "no runtime is running on this OS thread"),
+            SpawnError::InvalidStackSize(size) => write!(f, "invalid stack size: {} bytes", size),
+            SpawnError::OutOfMemory => write!(f, "cannot allocate the stack of a green thread"),
+            SpawnError::NameInUse(name) => write!(f, "a green thread is named {:?} already", name),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+// called when no thread is executable: spin, then park until a thread is woken.
+// returns false if no thread can be woken anymore
+pub(super) unsafe fn idle() -> bool {
+    rt().remote.parks_here();
+    let mut spins = 0;
+    loop {
+        poll_remote();
+        if rt().contexts.is_empty() {
+            model::deliver_delayed();
+        }
+        if !rt().contexts.is_empty() {
+            return true;
+        }
+        if !has_remote_senders() {
+            // the last senders may have sent something before leaving
+            poll_remote();
+            return !rt().contexts.is_empty();
+        }
+        if spins < rt().spin_budget {
+            spins += 1;
+            std::hint::spin_loop();
+        } else if let Some(timeout) = until_next_timer() {
+            thread::park_timeout(timeout);
+        } else {
+            thread::park();
+        }
+    }
+}
+
+// Runs its closure when dropped, to take the calling thread off what it registered on to be
+// woken, e.g. a list of waiters, when a `timeout` unwinds out of its wait; otherwise the
+// wakeup meant for another thread could go to it
+pub(super) struct Deregister<F: FnMut()>(pub(super) F);
+
+impl<F: FnMut()> Drop for Deregister<F> {
+    fn drop(&mut self) {
+        (self.0)()
+    }
+}
+
+// move the running thread to the waiting queue and switch to the next thread,
+// returns once another thread (or OS thread) has woken it
+pub(super) unsafe fn wait() {
+    check_deadline();
+    if rt().contexts.len() == 1 && !has_remote_senders() {
+        panic!("dead lock!");
+    }
+
+    let mut ctx = rt().contexts.pop_front().unwrap();
+    let key = ctx.id;
+    let regs = ctx.get_regs_mut();
+    let uses_fp = ctx.uses_fp;
+    rt().waiting.insert(key, ctx);
+
+    // wait for other OS threads, this may wake ourselves
+    if rt().contexts.is_empty() && !idle() {
+        panic!("dead lock!");
+    }
+
+    // if we were woken while idle, this switches to ourselves
+    let next = next_context();
+    profile::begin();
+    swap_context(regs, (*next).get_regs(), fp_flags(uses_fp, (*next).uses_fp));
+    profile::end();
+
+    rm_unused_stack();
+    check_deadline();
+}
+
+pub(super) fn get_id() -> ThreadId {
+    loop {
+        let rnd = ThreadId(model::random_id());
+        let runtime = unsafe { rt() };
+        if runtime.ids.insert(rnd) {
+            runtime.report.spawned += 1;
+            runtime.report.peak_threads = runtime.report.peak_threads.max(runtime.ids.len());
+            return rnd;
+        }
+    }
+}
+
+/// The id of the calling green thread, to register it or hand it to peers for replies;
+/// panics outside of green threads, see `is_green_thread`.
+pub fn current() -> ThreadId {
+    let ctx = current_ctx();
+    assert!(!ctx.is_null(), "current is called outside of green threads");
+    unsafe { (*ctx).id }
+}
+
+/// Whether the caller runs on a green thread, rather than on a plain OS thread.
+pub fn is_green_thread() -> bool {
+    !current_ctx().is_null()
+}
+
+/// Spawn a green thread running `func`, which may capture its configuration and channels,
+/// and return its id.
+///
+/// Fails if no runtime is running, if `stack_size` is below `MIN_STACK_SIZE`, or if the
+/// stack cannot be allocated; at the thread limit, it blocks or panics as the policy says.
+pub fn spawn<F: FnOnce() + 'static>(func: F, stack_size: usize) -> Result<ThreadId, SpawnError> {
+    spawn_inner(func, stack_size, true, None, None)
+}
+
+/// Spawn a green thread running `f` with `arg`, which is kept in its context until it starts.
+pub fn spawn_with<T: Send + 'static>(
+    f: fn(T),
+    arg: T,
+    stack_size: usize,
+) -> Result<ThreadId, SpawnError> {
+    spawn(move || f(arg), stack_size)
+}
+
+/// Spawn a thread whose floating-point registers (d8-d15, fs0-fs11 on riscv64) are not saved
+/// nor restored when switching, which makes its context switches cheaper.
+///
+/// The thread must not keep floating-point or SIMD values alive across
+/// `yield_now`, `send`, `recv` or `spawn`, since they may be clobbered by other threads.
+/// On x86_64 it is the floating-point control words (rounding mode, exception masks)
+/// that are not kept, so the thread must not change them.
+pub fn spawn_no_fp<F: FnOnce() + 'static>(
+    func: F,
+    stack_size: usize,
+) -> Result<ThreadId, SpawnError> {
+    spawn_inner(func, stack_size, false, None, None)
+}
+
+/// Spawn a thread like `spawn`, or fail instead of blocking if the maximum number of
+/// live threads is reached.
+pub fn try_spawn<F: FnOnce() + 'static>(
+    func: F,
+    stack_size: usize,
+) -> Result<ThreadId, SpawnError> {
+    if runtime_ptr().is_null() {
+        return Err(SpawnError::NoRuntime);
+    }
+    unsafe {
+        if at_thread_limit() {
+            return Err(SpawnError::WouldBlock);
+        }
+    }
+    spawn_inner(func, stack_size, true, None, None)
+}
+
+/// Spawn a thread like `spawn`, registered under `name` until it ends so that other
+/// threads can find it with `lookup`; fails if a live thread already has the name.
+pub fn spawn_named<F: FnOnce() + 'static>(
+    name: &str,
+    func: F,
+    stack_size: usize,
+) -> Result<ThreadId, SpawnError> {
+    spawn_inner(func, stack_size, true, None, Some(name))
+}
+
+/// The live thread registered under `name` by `spawn_named` or `register`, if any.
+pub fn lookup(name: &str) -> Option<ThreadId> {
+    if runtime_ptr().is_null() {
+        return None;
+    }
+    unsafe { rt().names.get(name).copied() }
+}
+
+/// Error returned by `register`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    /// a live thread is already registered under this name
+    NameInUse(String),
+    /// the thread is already registered, under this name
+    AlreadyNamed(String),
+    /// no live thread has this id
+    NoSuchThread(ThreadId),
+}
+
+impl std::fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegisterError::NameInUse(name) => {
+                write!(f, "a green thread is named {:?} already", name)
+            }
+            RegisterError::AlreadyNamed(name) => {
+                write!(f, "the green thread is named {:?} already", name)
+            }
+            RegisterError::NoSuchThread(id) => write!(f, "no green thread has the id {}", id),
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+/// Register the live thread `id` under `name`, as `spawn_named` does, until it ends or the
+/// name is unregistered: a name belongs to a single thread, and a thread has a single name.
+pub fn register(name: &str, id: ThreadId) -> Result<(), RegisterError> {
+    if runtime_ptr().is_null() {
+        return Err(RegisterError::NoSuchThread(id));
+    }
+    unsafe {
+        if let Some(&owner) = rt().names.get(name) {
+            if owner != id {
+                return Err(RegisterError::NameInUse(name.to_string()));
+            }
+        }
+        let ctx = match live_context_mut(id) {
+            Some(ctx) => ctx,
+            None => return Err(RegisterError::NoSuchThread(id)),
+        };
+        if let Some(other) = &ctx.name {
+            return Err(RegisterError::AlreadyNamed(other.clone()));
+        }
+        ctx.name = Some(name.to_string());
+        rt().names.insert(name.to_string(), id);
+        Ok(())
+    }
+}
+
+/// Remove the registration of `name`, and return the thread it belonged to.
+pub fn unregister(name: &str) -> Option<ThreadId> {
+    if runtime_ptr().is_null() {
+        return None;
+    }
+    unsafe {
+        let id = rt().names.remove(name)?;
+        if let Some(ctx) = live_context_mut(id) {
+            ctx.name = None;
+        }
+        Some(id)
+    }
+}
+
+/// The registered names with their threads, in the order of the names.
+pub fn registered() -> Vec<(String, ThreadId)> {
+    if runtime_ptr().is_null() {
+        return Vec::new();
+    }
+    let mut names: Vec<(String, ThreadId)> = unsafe {
+        rt().names
+            .iter()
+            .map(|(name, &id)| (name.clone(), id))
+            .collect()
+    };
+    names.sort();
+    names
+}
+
+// the context of the live thread `id`, running, executable or waiting
+unsafe fn live_context_mut(id: ThreadId) -> Option<&'static mut Context> {
+    let runtime = rt();
+    match runtime.contexts.iter_mut().find(|ctx| ctx.id == id) {
+        Some(ctx) => Some(&mut **ctx),
+        None => runtime.waiting.get_mut(&id).map(|ctx| &mut **ctx),
+    }
+}
+
+pub(super) fn spawn_inner<F: FnOnce() + 'static>(
+    func: F,
+    stack_size: usize,
+    uses_fp: bool,
+    future: Option<BoxFuture>,
+    name: Option<&str>,
+) -> Result<ThreadId, SpawnError> {
+    if runtime_ptr().is_null() {
+        return Err(SpawnError::NoRuntime);
+    }
+    unsafe {
+        wait_for_slot();
+        // checked once a slot is free, since the name may have been taken while parked
+        if let Some(name) = name.filter(|&name| rt().names.contains_key(name)) {
+            return Err(SpawnError::NameInUse(name.to_string()));
+        }
+        // the id is only taken once the context exists, so that a failure leaves no trace
+        let mut ctx = alloc_context(
+            Box::new(func),
+            TypeId::of::<F>(),
+            stack_size,
+            ThreadId(0),
+            uses_fp,
+        )?;
+        let id = get_id();
+        ctx.id = id;
+        ctx.future = future;
+        if let Some(name) = name {
+            rt().names.insert(name.to_string(), id);
+            ctx.name = Some(name.to_string());
+        }
+        if !current_ctx().is_null() {
+            let parent = (*current_ctx()).id;
+            ctx.parent = Some(parent);
+            rt().children.entry(parent).or_default().push(id);
+        }
+        rt().contexts.push_back(ctx);
+        yield_now();
+        Ok(id)
+    }
+}
+
+/// Register `hook` to run when the calling green thread ends, whether it returns, panics
+/// or is killed; hooks run in the reverse order of their registration.
+///
+/// Hooks of threads abandoned in the waiting queue when the runtime ends are not run.
+pub fn at_exit<F: FnOnce() + 'static>(hook: F) {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "at_exit is called outside of green threads"
+        );
+        (*current_ctx()).at_exit.push(Box::new(hook));
+    }
+}
+
+/// Which thread a yielding thread would rather see run next, see `yield_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldHint {
+    /// the thread the caller just unblocked, e.g. by sending it a message, so that it
+    /// handles it right away instead of after every other executable thread
+    AfterSend(ThreadId),
+}
+
+/// Let the other executable threads run, in turn, before the calling thread continues.
+pub fn yield_now() {
+    yield_inner(None)
+}
+
+/// Yield like `yield_now`, but run the thread of the hint first if it is executable;
+/// otherwise the hint is ignored.
+pub fn yield_with(hint: YieldHint) {
+    match hint {
+        YieldHint::AfterSend(id) => yield_inner(Some(id)),
+    }
+}
+
+/// Switch straight to the executable thread `id`, leaving the calling thread right behind it
+/// rather than behind every other executable thread, e.g. for request/response ping-pong.
+///
+/// Returns false, without switching, if `id` is not an executable thread other than the caller.
+pub fn yield_to(id: ThreadId) -> bool {
+    unsafe {
+        poll_remote();
+        let runtime = rt();
+        let i = match runtime.contexts.iter().position(|ctx| ctx.id == id) {
+            Some(i) if i > 0 => i,
+            _ => return false,
+        };
+        let target = runtime.contexts.remove(i).unwrap();
+        let regs = runtime.contexts[0].get_regs_mut();
+        let uses_fp = runtime.contexts[0].uses_fp;
+        runtime.contexts.push_front(target);
+
+        let next = next_context();
+        profile::begin();
+        swap_context(regs, (*next).get_regs(), fp_flags(uses_fp, (*next).uses_fp));
+        profile::end();
+
+        rm_unused_stack();
+        true
+    }
+}
+
+#[deprecated(note = "renamed to `yield_now`")]
+pub fn schedule() {
+    yield_now()
+}
+
+pub(super) fn yield_inner(next: Option<ThreadId>) {
+    // 1. Move the current context(that is in the front of the queue) to the back of the queue
+    // 2. Save this thread's registers to the current context and switch to the next context
+    // 3. Reclaim the unused stacks once in a while after the context switch
+    unsafe {
+        poll_remote();
+
+        // the self is the only executable process, so immediately return
+        if rt().contexts.len() == 1 {
+            // nothing else to run, so it is a good time to reclaim stacks
+            reclaim_unused_stacks();
+            return;
+        }
+        // move the self context to the back of the queue
+        let mut ctx = rt().contexts.pop_front().unwrap();
+        let regs = ctx.get_regs_mut();
+        let uses_fp = ctx.uses_fp;
+        rt().contexts.push_back(ctx);
+
+        // the preferred thread jumps the queue
+        if let Some(i) = next.and_then(|id| rt().contexts.iter().position(|ctx| ctx.id == id)) {
+            let ctx = rt().contexts.remove(i).unwrap();
+            rt().contexts.push_front(ctx);
+        }
+
+        // store registers to the current context and switch to the next context
+        let next = next_context();
+        profile::begin();
+        swap_context(regs, (*next).get_regs(), fp_flags(uses_fp, (*next).uses_fp));
+        profile::end();
+
+        rm_unused_stack();
+    }
+}
+
+pub(super) extern "C-unwind" fn entry_point() {
+    unsafe {
+        profile::end();
+
+        // execute the designated function, a panic ends the thread as a return does
+        let entry = (*current_ctx()).entry.take().unwrap();
+        let run = std::panic::AssertUnwindSafe(|| model::run_entry(entry));
+        let status = match std::panic::catch_unwind(run) {
+            Ok(()) => ExitStatus::Normal,
+            Err(payload) => ExitStatus::Panicked(panic_message(payload)),
+        };
+
+        // the hooks still run as the current thread, so they may send messages
+        (*current_ctx()).run_exit_hooks();
+
+        exit_current(status);
+    }
+}
+
+// end the running thread and switch to the next one, or to main if none is left
+pub(super) unsafe fn exit_current(status: ExitStatus) -> ! {
+    // while still in the front of the queue, in case hooks of cancelled children switch
+    leave_tree(&*current_ctx());
+    propagate_exit((*current_ctx()).id, &status);
+
+    // remove self context from the queue
+    let ctx = rt().contexts.pop_front().unwrap();
+
+    rt().ids.remove(&ctx.id);
+    close_mailbox(ctx.id);
+    leave_topics(ctx.id);
+    leave_groups(ctx.id);
+    release_name(&ctx);
+    record_exit(ctx.id, status);
+
+    rt().unused.push(ctx);
+
+    poll_remote();
+    if rt().contexts.is_empty() && !rt().waiting.is_empty() && has_remote_senders() {
+        // the waiting threads may still be woken by other OS threads
+        // (if not, they are abandoned by switching to the main context)
+        idle();
+    }
+
+    if !rt().contexts.is_empty() {
+        let next = next_context();
+        profile::begin();
+        switch_context((*next).get_regs(), fp_flags(false, (*next).uses_fp));
+    } else {
+        // if there is no context, switch to the main context
+        rt().current = ptr::null_mut();
+        profile::begin();
+        switch_context(&rt().main, RESTORE_FP);
+    }
+}
+
+// free the name of an ending thread for the threads spawned after it
+pub(super) unsafe fn release_name(ctx: &Context) {
+    if let Some(name) = &ctx.name {
+        rt().names.remove(name);
+    }
+}
+
+// the message of a panic payload, for the exit status
+pub(super) fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
+
+// record how `id` ended and wake the threads waiting for it
+pub(super) unsafe fn record_exit(id: ThreadId, status: ExitStatus) {
+    if status == ExitStatus::Killed {
+        rt().report.killed += 1;
+    }
+    notify_supervisor(id, &status);
+    notify_monitors(id, &status);
+    if !rt().exit_observers.is_empty() {
+        for observer in rt().exit_observers.remove(&id).unwrap_or_default() {
+            observer(&status);
+        }
+    }
+    let runtime = rt();
+    runtime.exits.insert(id, status);
+    runtime.exit_order.push_back(id);
+    if runtime.exit_order.len() > MAX_EXITS {
+        let oldest = runtime.exit_order.pop_front().unwrap();
+        runtime.exits.remove(&oldest);
+    }
+    // a slot is free for the first thread still parked in spawn
+    while let Some(waiter) = rt().slot_waiters.pop_front() {
+        if let Some(ctx) = rt().waiting.remove(&waiter) {
+            rt().contexts.push_back(ctx);
+            break;
+        }
+    }
+    for waiter in rt().exit_waiters.remove(&id).unwrap_or_default() {
+        if let Some(ctx) = rt().waiting.remove(&waiter) {
+            rt().contexts.push_back(ctx);
+        }
+    }
+}
+
+// A callback of `observe_exit`
+pub(super) type ExitObserver = Box<dyn FnOnce(&ExitStatus)>;
+
+// call `observer` with how `id` ends, without switching; right away if it has already ended
+#[cfg(feature = "sync")]
+pub(super) unsafe fn observe_exit<F: FnOnce(&ExitStatus) + 'static>(id: ThreadId, observer: F) {
+    match rt().exits.get(&id) {
+        Some(status) => observer(&status.clone()),
+        None => rt()
+            .exit_observers
+            .entry(id)
+            .or_default()
+            .push(Box::new(observer)),
+    }
+}
+
+// end a thread which is not running, without resuming it
+pub(super) unsafe fn kill_context(mut ctx: Box<Context>) {
+    ctx.run_exit_hooks();
+    ctx.entry = None;
+    ctx.future = None;
+    leave_tree(&ctx);
+    rt().ids.remove(&ctx.id);
+    close_mailbox(ctx.id);
+    leave_topics(ctx.id);
+    leave_groups(ctx.id);
+    release_name(&ctx);
+    record_exit(ctx.id, ExitStatus::Killed);
+    let id = ctx.id;
+    rt().unused.push(ctx);
+    // last, since a linked thread killed may be the running one
+    propagate_exit(id, &ExitStatus::Killed);
+}
+
+/// Kill the green thread `id`: it is never resumed again, and its stack is reused
+/// without being unwound, so the values it owns are leaked.
+///
+/// Its at_exit hooks run on the calling thread; killing the calling thread ends it.
+/// Returns false if `id` is not a live thread.
+pub fn kill(id: ThreadId) -> bool {
+    unsafe {
+        if current_ctx().is_null() {
+            return false;
+        }
+        if (*current_ctx()).id == id {
+            (*current_ctx()).run_exit_hooks();
+            exit_current(ExitStatus::Killed);
+        }
+        let ctx = match rt().contexts.iter().position(|ctx| ctx.id == id) {
+            Some(i) => rt().contexts.remove(i).unwrap(),
+            None => match rt().waiting.remove(&id) {
+                Some(ctx) => ctx,
+                None => return false,
+            },
+        };
+        kill_context(ctx);
+        true
+    }
+}
+
+// detach an ending thread from its parent, and cancel its children if it asked so
+pub(super) unsafe fn leave_tree(ctx: &Context) {
+    if let Some(siblings) = ctx.parent.and_then(|parent| rt().children.get_mut(&parent)) {
+        siblings.retain(|&child| child != ctx.id);
+    }
+    let children = rt().children.remove(&ctx.id).unwrap_or_default();
+    if ctx.cancel_children {
+        for child in children {
+            cancel_tree(child);
+        }
+    }
+}
+
+/// Make the children of the calling thread be cancelled, with their own descendants,
+/// when it ends; otherwise they outlive it as orphans.
+pub fn cancel_children_on_exit() {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "cancel_children_on_exit is called outside of green threads"
+        );
+        (*current_ctx()).cancel_children = true;
+    }
+}
+
+/// Kill the thread `id` and every thread it spawned, directly or not, which is still alive.
+///
+/// Returns how many threads were killed; if the calling thread is one of them,
+/// it is killed last and this does not return.
+pub fn cancel_tree(id: ThreadId) -> usize {
+    unsafe {
+        if current_ctx().is_null() {
+            return 0;
+        }
+        // collect the whole tree first, since killing a thread detaches its children
+        let mut tree = vec![id];
+        let mut i = 0;
+        while i < tree.len() {
+            if let Some(children) = rt().children.get(&tree[i]) {
+                tree.extend_from_slice(children);
+            }
+            i += 1;
+        }
+
+        let current = (*current_ctx()).id;
+        let mut killed = 0;
+        for &id in tree.iter().filter(|&&id| id != current) {
+            if kill(id) {
+                killed += 1;
+            }
+        }
+        if tree.contains(&current) {
+            kill(current);
+        }
+        killed
+    }
+}
+
+/// How a green thread ended, see `exit_status` and `wait_for_exit`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// the thread function returned
+    Normal,
+    /// the thread panicked, with the panic message
+    Panicked(String),
+    /// the thread was killed
+    Killed,
+    /// the thread overflowed its stack, and was ended without being unwound;
+    /// only detected on Linux on x86_64 and aarch64
+    StackOverflow,
+}
+
+/// How the thread `id` ended, or None if it is still alive (or never existed, or ended
+/// before the last `MAX_EXITS` threads to end).
+pub fn exit_status(id: ThreadId) -> Option<ExitStatus> {
+    unsafe { rt().exits.get(&id).cloned() }
+}
+
+/// Whether a live thread is running, ready to run or waiting for a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Executable,
+    Waiting,
+}
+
+/// A live thread as listed by `threads`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub state: ThreadState,
+    /// the number of messages not received yet
+    pub mailbox_depth: usize,
+    pub stack_size: usize,
+    /// the name given to `spawn_named`
+    pub name: Option<String>,
+}
+
+/// List the live threads of the runtime, the running one first, then the executable ones
+/// in the order they will run, then the waiting ones; empty outside of green threads.
+pub fn threads() -> Vec<ThreadInfo> {
+    unsafe {
+        if current_ctx().is_null() {
+            return Vec::new();
+        }
+        poll_remote();
+        let runtime = &*rt();
+        live_threads(runtime)
+            .map(|(ctx, state)| ThreadInfo {
+                id: ctx.id,
+                state,
+                mailbox_depth: count_messages(runtime, ctx.id),
+                stack_size: ctx.stack_layout.size(),
+                name: ctx.name.clone(),
+            })
+            .collect()
+    }
+}
+
+// the contexts of the live threads with their state, from the execution queue (whose
+// front is running) to the waiting ones
+pub(super) fn live_threads(runtime: &Runtime) -> impl Iterator<Item = (&Context, ThreadState)> {
+    let executable = runtime.contexts.iter().enumerate().map(|(i, ctx)| {
+        let state = if i == 0 {
+            ThreadState::Running
+        } else {
+            ThreadState::Executable
+        };
+        (&**ctx, state)
+    });
+    let waiting = runtime
+        .waiting
+        .values()
+        .map(|ctx| (&**ctx, ThreadState::Waiting));
+    executable.chain(waiting)
+}
+
+/// A live thread in a `Snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadSnapshot {
+    pub id: ThreadId,
+    pub parent: Option<ThreadId>,
+    pub state: ThreadState,
+    /// the name of the factory the thread runs, see `register_factory`
+    pub factory: Option<String>,
+    /// the messages not received yet, in order
+    pub mailbox: Vec<u64>,
+}
+
+/// The logical state of the runtime: its live threads and their mailboxes.
+///
+/// Registers and stacks are not part of it, so restoring a thread starts it over
+/// from its factory with its pending messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub threads: Vec<ThreadSnapshot>,
+}
+
+/// Name `entry` so that the threads spawned with it can be restored from a `Snapshot`.
+///
+/// Threads are recognized by the type of their function, so a named function matches
+/// wherever it is spawned, while a closure only matches the threads of that same closure.
+pub fn register_factory<F: Fn() + Send + Sync + 'static>(name: &'static str, entry: F) {
+    let mut factories = FACTORIES.lock().unwrap();
+    factories.retain(|(other, _, _)| *other != name);
+    factories.push((name, TypeId::of::<F>(), Arc::new(entry)));
+}
+
+pub(super) fn factory_name(kind: TypeId) -> Option<&'static str> {
+    FACTORIES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, factory, _)| *factory == kind)
+        .map(|&(name, _, _)| name)
+}
+
+/// Capture the live threads of the runtime and their mailboxes;
+/// empty if called outside of green threads.
+pub fn snapshot() -> Snapshot {
+    let mut threads = Vec::new();
+    unsafe {
+        if current_ctx().is_null() {
+            return Snapshot { threads };
+        }
+        poll_remote();
+        let runtime = &*rt();
+        for (ctx, state) in live_threads(runtime) {
+            threads.push(ThreadSnapshot {
+                id: ctx.id,
+                parent: ctx.parent,
+                state,
+                factory: factory_name(ctx.kind).map(str::to_string),
+                mailbox: mailbox_contents(runtime, ctx.id),
+            });
+        }
+    }
+    Snapshot { threads }
+}
+
+impl Snapshot {
+    /// Write the snapshot as text, one thread per line.
+    ///
+    /// Factory names are written after their length in bytes, so that they may contain spaces;
+    /// a name with a newline cannot be written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = String::from("green-snapshot 2\n");
+        for thread in &self.threads {
+            let parent = thread.parent.map_or("-".to_string(), |id| id.to_string());
+            let state = match thread.state {
+                ThreadState::Running => "running",
+                ThreadState::Executable => "executable",
+                ThreadState::Waiting => "waiting",
+            };
+            let factory = match &thread.factory {
+                Some(name) if name.contains('\n') => {
+                    let message = format!("factory name with a newline: {:?}", name);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+                }
+                Some(name) => format!("{}:{}", name.len(), name),
+                None => "-".to_string(),
+            };
+            out += &format!("thread {} {} {} {}", thread.id, parent, state, factory);
+            for msg in &thread.mailbox {
+                out += &format!(" {}", msg);
+            }
+            out.push('\n');
+        }
+        fs::write(path, out)
+    }
+
+    /// Read a snapshot written by `save`; snapshots of the first version, whose factory
+    /// names had no length, are read too.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, line.to_string());
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        let prefixed = match lines.next() {
+            Some("green-snapshot 1") => false,
+            Some("green-snapshot 2") => true,
+            _ => return Err(invalid("not a snapshot")),
+        };
+        let mut threads = Vec::new();
+        for line in lines {
+            let fields: Vec<&str> = line.splitn(5, ' ').collect();
+            if fields.len() < 5 || fields[0] != "thread" {
+                return Err(invalid(line));
+            }
+            let number = |field: &str| field.parse::<u64>().map_err(|_| invalid(line));
+            let state = match fields[3] {
+                "running" => ThreadState::Running,
+                "executable" => ThreadState::Executable,
+                "waiting" => ThreadState::Waiting,
+                _ => return Err(invalid(line)),
+            };
+            // the factory, then the messages after it
+            let rest = fields[4];
+            let (factory, rest) = match rest.split_once(' ').unwrap_or((rest, "")) {
+                ("-", rest) => (None, rest),
+                (name, rest) if !prefixed => (Some(name), rest),
+                _ => {
+                    let (len, rest) = rest.split_once(':').ok_or_else(|| invalid(line))?;
+                    let len = number(len)? as usize;
+                    let name = rest.get(..len).ok_or_else(|| invalid(line))?;
+                    match &rest[len..] {
+                        "" => (Some(name), ""),
+                        rest => (
+                            Some(name),
+                            rest.strip_prefix(' ').ok_or_else(|| invalid(line))?,
+                        ),
+                    }
+                }
+            };
+            let mailbox = match rest {
+                "" => Vec::new(),
+                rest => rest.split(' ').map(number).collect::<Result<_, _>>()?,
+            };
+            threads.push(ThreadSnapshot {
+                id: ThreadId(number(fields[1])?),
+                parent: match fields[2] {
+                    "-" => None,
+                    parent => Some(ThreadId(number(parent)?)),
+                },
+                state,
+                factory: factory.map(str::to_string),
+                mailbox,
+            });
+        }
+        Ok(Snapshot { threads })
+    }
+
+    /// Spawn a new thread from the factory of every thread of the snapshot which has one,
+    /// with the same pending messages, and return the new ids by the ids of the snapshot.
+    ///
+    /// Restored threads keep their parent if it is restored too; the others become children
+    /// of the calling thread. None of them runs before all of them are restored, and none
+    /// is spawned if one of them cannot be.
+    pub fn restore(&self, stack_size: usize) -> Result<HashMap<ThreadId, ThreadId>, SpawnError> {
+        unsafe {
+            assert!(
+                !current_ctx().is_null(),
+                "restore is called outside of green threads"
+            );
+            let restorable: Vec<(&ThreadSnapshot, Factory)> = self
+                .threads
+                .iter()
+                .filter_map(|thread| {
+                    let factories = FACTORIES.lock().unwrap();
+                    let name = thread.factory.as_deref()?;
+                    let factory = factories.iter().find(|(other, _, _)| *other == name)?;
+                    Some((thread, factory.clone()))
+                })
+                .collect();
+            // every context is allocated before any id is taken, so that a failure leaves no trace
+            let mut contexts = Vec::with_capacity(restorable.len());
+            for (thread, (_, kind, entry)) in restorable {
+                let func = Box::new(move || entry());
+                let ctx = alloc_context(func, kind, stack_size, ThreadId(0), true)?;
+                contexts.push((thread, ctx));
+            }
+            let ids: HashMap<ThreadId, ThreadId> = contexts
+                .iter()
+                .map(|(thread, _)| (thread.id, get_id()))
+                .collect();
+
+            let current = (*current_ctx()).id;
+            for (thread, mut ctx) in contexts {
+                let id = ids[&thread.id];
+                ctx.id = id;
+                let parent = thread
+                    .parent
+                    .and_then(|parent| ids.get(&parent).copied())
+                    .unwrap_or(current);
+                ctx.parent = Some(parent);
+                rt().children.entry(parent).or_default().push(id);
+                for &msg in &thread.mailbox {
+                    let envelope = Envelope {
+                        sender: current,
+                        msg,
+                        expires: None,
+                    };
+                    rt().messages.push_back(id, envelope);
+                }
+                rt().contexts.push_back(ctx);
+            }
+            yield_now();
+            Ok(ids)
+        }
+    }
+}
+
+/// Render the live threads as the tree of who spawned whom, with their state,
+/// factory and number of pending messages, one thread per line.
+///
+/// Threads whose parent has ended are shown as roots.
+pub fn tree() -> String {
+    let snapshot = snapshot();
+    let live: HashSet<ThreadId> = snapshot.threads.iter().map(|thread| thread.id).collect();
+    let mut children: HashMap<Option<ThreadId>, Vec<&ThreadSnapshot>> = HashMap::new();
+    for thread in &snapshot.threads {
+        let parent = thread.parent.filter(|parent| live.contains(parent));
+        children.entry(parent).or_default().push(thread);
+    }
+
+    fn render(
+        out: &mut String,
+        thread: &ThreadSnapshot,
+        children: &HashMap<Option<ThreadId>, Vec<&ThreadSnapshot>>,
+        prefix: &str,
+        branch: &str,
+    ) {
+        let factory = thread
+            .factory
+            .as_ref()
+            .map_or(String::new(), |name| format!(" {}", name));
+        out.push_str(&format!(
+            "{}{}{} {:?}{} mailbox={}\n",
+            prefix,
+            branch,
+            thread.id,
+            thread.state,
+            factory,
+            thread.mailbox.len()
+        ));
+        let below = match branch {
+            "" => prefix.to_string(),
+            "├─ " => format!("{}│  ", prefix),
+            _ => format!("{}   ", prefix),
+        };
+        let kids = children
+            .get(&Some(thread.id))
+            .map_or(&[][..], |kids| &kids[..]);
+        for (i, child) in kids.iter().enumerate() {
+            let branch = if i + 1 == kids.len() {
+                "└─ "
+            } else {
+                "├─ "
+            };
+            render(out, child, children, &below, branch);
+        }
+    }
+
+    let mut out = String::new();
+    for root in children.get(&None).map_or(&[][..], |roots| &roots[..]) {
+        render(&mut out, root, &children, "", "");
+    }
+    out
+}
+
+/// Wait until the thread `id` ends, letting the other threads run, and return how it ended.
+pub fn wait_for_exit(id: ThreadId) -> ExitStatus {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "wait_for_exit is called outside of green threads"
+        );
+        assert!(
+            (*current_ctx()).id != id,
+            "a green thread cannot wait for itself"
+        );
+        let me = (*current_ctx()).id;
+        let _registered = Deregister(|| forget_exit_waiter(id, me));
+        loop {
+            if let Some(status) = rt().exits.get(&id) {
+                return status.clone();
+            }
+            assert!(rt().ids.contains(&id), "no green thread has the id {}", id);
+            add_exit_waiter(id, me);
+            wait();
+        }
+    }
+}
+
+// wake `waiter` when `id` ends
+pub(super) unsafe fn add_exit_waiter(id: ThreadId, waiter: ThreadId) {
+    let waiters = rt().exit_waiters.entry(id).or_default();
+    if !waiters.contains(&waiter) {
+        waiters.push(waiter);
+    }
+}
+
+// undo `add_exit_waiter`, if `id` has not ended meanwhile
+pub(super) unsafe fn forget_exit_waiter(id: ThreadId, waiter: ThreadId) {
+    let runtime = rt();
+    if let Some(waiters) = runtime.exit_waiters.get_mut(&id) {
+        waiters.retain(|&other| other != waiter);
+        if waiters.is_empty() {
+            runtime.exit_waiters.remove(&id);
+        }
+    }
+}
+
+// the front of the execution queue, which is about to run
+pub(super) unsafe fn next_context() -> *mut Context {
+    model::pick_front();
+    rt().current = &mut **rt().contexts.front_mut().unwrap() as *mut Context;
+    current_ctx()
+}
+
+// take a context out of the pool if one with the same stack size is available
+pub(super) fn alloc_context(
+    func: BoxEntry,
+    kind: TypeId,
+    stack_size: usize,
+    id: ThreadId,
+    uses_fp: bool,
+) -> Result<Box<Context>, SpawnError> {
+    unsafe {
+        if !rt().unused.is_empty() {
+            reclaim_unused_stacks();
+        }
+        let runtime = rt();
+        if let Some(i) = runtime
+            .pool
+            .iter()
+            .rposition(|ctx| ctx.stack_layout.size() == stack_size)
+        {
+            // reuse both the allocation of the context and its stack
+            let mut ctx = runtime.pool.swap_remove(i);
+            ctx.reset(func, kind, id, uses_fp);
+            runtime.pool_stats.hits += 1;
+            return Ok(ctx);
+        }
+        runtime.pool_stats.misses += 1;
+    }
+    Context::new(func, kind, stack_size, id, uses_fp).map(Box::new)
+}
+
+// called after every context switch, reclaims in batches to keep syscalls off the hot path
+pub(super) unsafe fn rm_unused_stack() {
+    rt().switches_since_reclaim += 1;
+    if rt().switches_since_reclaim >= RECLAIM_INTERVAL || rt().unused.len() >= MAX_POOLED_CONTEXTS {
+        reclaim_unused_stacks();
+    }
+}
+
+// must not be called on a finished context's stack, i.e. in `entry_point` before switching
+pub(super) unsafe fn reclaim_unused_stacks() {
+    let runtime = rt();
+    runtime.switches_since_reclaim = 0;
+    for ctx in runtime.unused.drain(..) {
+        // return the context to the pool with its guard page intact,
+        // or free it if the pool is full
+        if runtime.pool.len() < MAX_POOLED_CONTEXTS {
+            runtime.pool.push(ctx);
+        } else {
+            runtime.pool_stats.evictions += 1;
+        }
+    }
+}
+
+/// Run `func` as the first green thread of a new runtime with the default configuration
+/// and stacks of `stack_size` bytes, see `Runtime::builder`.
+pub fn spawn_from_main<F: FnOnce() + 'static>(func: F, stack_size: usize) -> RunReport {
+    Runtime::builder().default_stack_size(stack_size).run(func)
+}
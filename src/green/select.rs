@@ -0,0 +1,167 @@
+// Select: waiting on several channels, the mailbox and a timer at once.
+
+use super::*;
+use std::time::{Duration, Instant};
+
+// A source of a select with the handler of its value
+trait Branch<R> {
+    // take the value of the source if it is ready and run the handler on it
+    fn try_run(&mut self) -> Option<R>;
+    // make `id` the thread woken by the source, or forget it with None
+    fn set_waiter(&self, waiter: Option<ThreadId>);
+}
+
+struct RecvBranch<'a, T, F> {
+    receiver: &'a Receiver<T>,
+    handler: Option<F>,
+}
+
+impl<R, T, F: FnOnce(Option<T>) -> R> Branch<R> for RecvBranch<'_, T, F> {
+    fn try_run(&mut self) -> Option<R> {
+        let value = self.receiver.poll()?;
+        Some((self.handler.take().unwrap())(value))
+    }
+    fn set_waiter(&self, waiter: Option<ThreadId>) {
+        self.receiver.set_waiter(waiter);
+    }
+}
+
+struct OneshotBranch<'a, T, F> {
+    receiver: &'a OneshotReceiver<T>,
+    handler: Option<F>,
+}
+
+impl<R, T, F: FnOnce(Option<T>) -> R> Branch<R> for OneshotBranch<'_, T, F> {
+    fn try_run(&mut self) -> Option<R> {
+        let value = self.receiver.poll()?;
+        Some((self.handler.take().unwrap())(value))
+    }
+    fn set_waiter(&self, waiter: Option<ThreadId>) {
+        self.receiver.set_waiter(waiter);
+    }
+}
+
+struct MailboxBranch<F> {
+    handler: Option<F>,
+}
+
+impl<R, F: FnOnce(u64) -> R> Branch<R> for MailboxBranch<F> {
+    fn try_run(&mut self) -> Option<R> {
+        let msg = unsafe {
+            poll_remote();
+            pop_message((*current_ctx()).id)?
+        };
+        Some((self.handler.take().unwrap())(msg))
+    }
+    // every message wakes its receiver
+    fn set_waiter(&self, _waiter: Option<ThreadId>) {}
+}
+
+/// Wait for the first of several channels, the mailbox of the calling thread or a timeout
+/// to be ready, and run the handler of that one only; the sources are added with `recv`,
+/// `oneshot`, `mailbox` and `after`, then `wait` parks until one is ready.
+///
+/// The sources are checked in the order they were added, so the first ones win when
+/// several are ready at once.
+pub struct Select<'a, R> {
+    branches: Vec<Box<dyn Branch<R> + 'a>>,
+    timeout: Option<(Instant, Box<dyn FnOnce() -> R + 'a>)>,
+}
+
+impl<R> Default for Select<'_, R> {
+    fn default() -> Self {
+        Select::new()
+    }
+}
+
+impl<'a, R> Select<'a, R> {
+    pub fn new() -> Self {
+        Select {
+            branches: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Run `handler` on the next value of `receiver`, or on None if the channel is closed.
+    pub fn recv<T, F>(mut self, receiver: &'a Receiver<T>, handler: F) -> Self
+    where
+        F: FnOnce(Option<T>) -> R + 'a,
+    {
+        self.branches.push(Box::new(RecvBranch {
+            receiver,
+            handler: Some(handler),
+        }));
+        self
+    }
+
+    /// Run `handler` on the value of `receiver`, or on None if its sender is dropped.
+    pub fn oneshot<T, F>(mut self, receiver: &'a OneshotReceiver<T>, handler: F) -> Self
+    where
+        F: FnOnce(Option<T>) -> R + 'a,
+    {
+        self.branches.push(Box::new(OneshotBranch {
+            receiver,
+            handler: Some(handler),
+        }));
+        self
+    }
+
+    /// Run `handler` on the next message of the calling thread's mailbox, as `recv` would.
+    pub fn mailbox<F: FnOnce(u64) -> R + 'a>(mut self, handler: F) -> Self {
+        self.branches.push(Box::new(MailboxBranch {
+            handler: Some(handler),
+        }));
+        self
+    }
+
+    /// Run `handler` if no other source is ready once `timeout` has elapsed;
+    /// replaces the timeout set before, if any.
+    pub fn after<F: FnOnce() -> R + 'a>(mut self, timeout: Duration, handler: F) -> Self {
+        self.timeout = Some((Instant::now() + timeout, Box::new(handler)));
+        self
+    }
+
+    /// Park the calling green thread until a source is ready, and return what its handler
+    /// returns; waits forever if there is no source.
+    pub fn wait(mut self) -> R {
+        unsafe {
+            assert!(
+                !current_ctx().is_null(),
+                "select is called outside of green threads"
+            );
+            let id = (*current_ctx()).id;
+            let timer = std::cell::Cell::new(None);
+            let _timer = Deregister(|| {
+                if let Some(timer) = timer.take() {
+                    rt().timers.cancel(timer);
+                }
+            });
+            loop {
+                for branch in &mut self.branches {
+                    if let Some(result) = branch.try_run() {
+                        return result;
+                    }
+                }
+                if let Some((deadline, _)) = self.timeout {
+                    if Instant::now() >= deadline {
+                        let (_, handler) = self.timeout.take().unwrap();
+                        return handler();
+                    }
+                    if timer.get().is_none() {
+                        timer.set(Some(rt().timers.insert(deadline, id)));
+                    }
+                }
+                // one waiter on every source, whichever is ready first wakes it
+                for branch in &self.branches {
+                    branch.set_waiter(Some(id));
+                }
+                let _waiting = Deregister(|| {
+                    for branch in &self.branches {
+                        branch.set_waiter(None);
+                    }
+                });
+                wait();
+            }
+        }
+    }
+}
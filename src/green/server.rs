@@ -0,0 +1,119 @@
+// Servers: actors telling the requests answered to their caller (`call`) from the
+// messages only handled (`cast`), with callbacks at their start and end.
+
+use super::*;
+use std::time::Duration;
+
+/// The behavior of a server run by `spawn_server`, handling its calls and casts one at a time.
+pub trait Server: 'static {
+    /// A request whose caller waits for a `Reply`.
+    type Call: Send + 'static;
+    type Reply: 'static;
+    /// A message handled without a reply.
+    type Cast: Send + 'static;
+
+    /// Called on the thread of the server before it handles anything.
+    fn init(&mut self) {}
+    fn handle_call(&mut self, call: Self::Call, from: ThreadId) -> Self::Reply;
+    fn handle_cast(&mut self, cast: Self::Cast);
+    /// Called on the thread of the server once it is stopped by `ServerRef::stop`,
+    /// not if it is killed or panics.
+    fn terminate(&mut self) {}
+}
+
+// What the `ServerRef` of a server sends to it
+enum ServerMsg<S: Server> {
+    Call(S::Call, Request<S::Reply>),
+    Cast(S::Cast),
+}
+
+// A server as the actor handling its messages
+struct ServerActor<S>(S);
+
+impl<S: Server> Actor for ServerActor<S> {
+    type Msg = ServerMsg<S>;
+
+    fn handle(&mut self, msg: ServerMsg<S>) {
+        match msg {
+            ServerMsg::Call(call, request) => {
+                let reply = self.0.handle_call(call, request.sender());
+                // the caller may have timed out
+                let _ = request.reply(reply);
+            }
+            ServerMsg::Cast(cast) => self.0.handle_cast(cast),
+        }
+    }
+}
+
+/// The address of a server spawned by `spawn_server`.
+pub struct ServerRef<S: Server> {
+    addr: Addr<ServerActor<S>>,
+}
+
+impl<S: Server> Clone for ServerRef<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S: Server> Copy for ServerRef<S> {}
+
+impl<S: Server> std::fmt::Debug for ServerRef<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ServerRef({})", self.addr.id())
+    }
+}
+
+/// Spawn a green thread running `server`: `init` first, then the calls and casts sent to
+/// the returned address until it is stopped, and `terminate` last.
+pub fn spawn_server<S: Server>(
+    mut server: S,
+    stack_size: usize,
+) -> Result<ServerRef<S>, SpawnError> {
+    let id = spawn(
+        move || {
+            server.init();
+            let mut actor = ServerActor(server);
+            run_actor(&mut actor);
+            actor.0.terminate();
+        },
+        stack_size,
+    )?;
+    Ok(ServerRef {
+        addr: Addr::from_id(id),
+    })
+}
+
+impl<S: Server> ServerRef<S> {
+    pub fn id(&self) -> ThreadId {
+        self.addr.id()
+    }
+
+    /// Send `call` to the server and wait for its reply, at most `timeout` if there is one.
+    pub fn call(&self, call: S::Call, timeout: Option<Duration>) -> Result<S::Reply, AskError> {
+        let id = self.id();
+        let ask = move || {
+            ask_with(id, 0, |request| {
+                send_typed(id, Mail::Msg(ServerMsg::<S>::Call(call, request)))
+            })
+        };
+        match timeout {
+            None => ask(),
+            Some(duration) => super::timeout(duration, ask).unwrap_or(Err(AskError::Timeout)),
+        }
+    }
+
+    /// Send `cast` to the server without waiting for it to be handled.
+    pub fn cast(&self, cast: S::Cast) {
+        self.addr.send(ServerMsg::Cast(cast))
+    }
+
+    /// Make the server end, calling `terminate`, once it has handled what was sent before.
+    pub fn stop(&self) {
+        self.addr.stop()
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.addr.is_alive()
+    }
+}
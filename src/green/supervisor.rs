@@ -0,0 +1,249 @@
+// Supervisors: threads starting a set of children and restarting them when they end.
+
+use super::*;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Which children a supervisor restarts when one of them ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// only the child which ended
+    OneForOne,
+    /// every child: the others are killed, then all are started again in order
+    OneForAll,
+}
+
+/// When a child of a supervisor is restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// whenever it ends
+    Permanent,
+    /// only if it panicked or was killed
+    Transient,
+    /// never, it is forgotten once it ends
+    Temporary,
+}
+
+// The message telling a supervisor that one of its children ended
+pub(super) struct ChildExit(ThreadId, ExitStatus);
+
+// How to start a child, and start it again
+struct ChildSpec {
+    name: String,
+    start: Rc<dyn Fn()>,
+    stack_size: usize,
+    restart: Restart,
+}
+
+/// A supervisor of child threads, see `Supervisor::run`.
+pub struct Supervisor {
+    strategy: Strategy,
+    children: Vec<ChildSpec>,
+    // the most restarts allowed within the period
+    max_restarts: usize,
+    period: Duration,
+    // the delay before the first restart of a period, doubled for each next one up to the most
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Supervisor {
+    /// A supervisor allowing 3 restarts in 5 seconds, without delay.
+    pub fn new(strategy: Strategy) -> Self {
+        Supervisor {
+            strategy,
+            children: Vec::new(),
+            max_restarts: 3,
+            period: Duration::from_secs(5),
+            backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+        }
+    }
+
+    /// Add a permanent child running `start`, registered under `name` like with
+    /// `spawn_named`, so that `lookup` finds it across its restarts.
+    pub fn child<F: Fn() + 'static>(self, name: &str, start: F, stack_size: usize) -> Self {
+        self.child_with(name, start, stack_size, Restart::Permanent)
+    }
+
+    /// Add a child like `child`, restarted as `restart` says.
+    pub fn child_with<F: Fn() + 'static>(
+        mut self,
+        name: &str,
+        start: F,
+        stack_size: usize,
+        restart: Restart,
+    ) -> Self {
+        self.children.push(ChildSpec {
+            name: name.to_string(),
+            start: Rc::new(start),
+            stack_size,
+            restart,
+        });
+        self
+    }
+
+    /// Add a permanent child running the actor made by `make`, see `spawn_actor`; a new one
+    /// is made at each restart, with `Actor::on_restart` called instead of `on_start`.
+    pub fn actor<A: Actor, F: Fn() -> A + 'static>(
+        self,
+        name: &str,
+        make: F,
+        stack_size: usize,
+    ) -> Self {
+        self.actor_with(name, make, stack_size, Restart::Permanent)
+    }
+
+    /// Add a child like `actor`, restarted as `restart` says.
+    pub fn actor_with<A: Actor, F: Fn() -> A + 'static>(
+        self,
+        name: &str,
+        make: F,
+        stack_size: usize,
+        restart: Restart,
+    ) -> Self {
+        let started = Cell::new(false);
+        let start = move || start_actor(make(), started.replace(true));
+        self.child_with(name, start, stack_size, restart)
+    }
+
+    /// Allow at most `max_restarts` restarts within `period`; beyond it the supervisor
+    /// gives up by panicking, which its own supervisor sees as a failure.
+    pub fn intensity(mut self, max_restarts: usize, period: Duration) -> Self {
+        self.max_restarts = max_restarts;
+        self.period = period;
+        self
+    }
+
+    /// Wait `initial` before restarting, doubled for each other restart within the period
+    /// of `intensity`, up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Start the children in order on the calling green thread, then restart them as the
+    /// strategy and their `Restart` say whenever one ends, by returning, panicking
+    /// or being killed.
+    ///
+    /// Never returns; the children are cancelled when the supervisor ends.
+    pub fn run(self) {
+        assert!(
+            !current_ctx().is_null(),
+            "a supervisor is run outside of green threads"
+        );
+        let me = current();
+        cancel_children_on_exit();
+        // the children cancelled with the supervisor do not tell it
+        at_exit(move || unsafe { rt().supervisors.retain(|_, supervisor| *supervisor != me) });
+
+        // the live children, None once a child is not to be restarted
+        let mut ids: Vec<Option<ThreadId>> = self
+            .children
+            .iter()
+            .map(|child| Some(child.start()))
+            .collect();
+        // the times of the restarts within the period
+        let mut restarts = VecDeque::new();
+        while let Some(ChildExit(id, status)) = recv_typed::<ChildExit>() {
+            // a child killed by a restart of all may have ended meanwhile
+            let i = match ids.iter().position(|&child| child == Some(id)) {
+                Some(i) => i,
+                None => continue,
+            };
+            let restart = match self.children[i].restart {
+                Restart::Permanent => true,
+                Restart::Transient => status != ExitStatus::Normal,
+                Restart::Temporary => false,
+            };
+            if !restart {
+                ids[i] = None;
+                continue;
+            }
+            self.wait_to_restart(&mut restarts, &self.children[i].name);
+            match self.strategy {
+                Strategy::OneForOne => ids[i] = Some(self.children[i].start()),
+                Strategy::OneForAll => {
+                    for &child in ids.iter().flatten() {
+                        unsafe { rt().supervisors.remove(&child) };
+                        kill(child);
+                    }
+                    // the temporary children killed are not restarted
+                    for (j, child) in self.children.iter().enumerate() {
+                        ids[j] = match child.restart {
+                            Restart::Temporary if j != i => None,
+                            _ => Some(child.start()),
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Spawn a green thread running the supervisor, see `run`.
+    pub fn spawn(self, stack_size: usize) -> Result<ThreadId, SpawnError> {
+        spawn(move || self.run(), stack_size)
+    }
+
+    // count a restart of `name`, giving up beyond the intensity, and wait for its backoff
+    fn wait_to_restart(&self, restarts: &mut VecDeque<Instant>, name: &str) {
+        let now = Instant::now();
+        while restarts
+            .front()
+            .is_some_and(|&restart| now.duration_since(restart) > self.period)
+        {
+            restarts.pop_front();
+        }
+        if restarts.len() >= self.max_restarts {
+            panic!(
+                "the child {} is restarted more than {} times in {:?}",
+                name, self.max_restarts, self.period
+            );
+        }
+        if !self.backoff.is_zero() {
+            let doubling = restarts.len().min(31) as u32;
+            sleep(
+                self.backoff
+                    .saturating_mul(1 << doubling)
+                    .min(self.max_backoff),
+            );
+        }
+        restarts.push_back(Instant::now());
+    }
+}
+
+impl ChildSpec {
+    // spawn the child, supervised by the calling thread
+    fn start(&self) -> ThreadId {
+        let supervisor = current();
+        let start = self.start.clone();
+        let id = match spawn_named(&self.name, move || start(), self.stack_size) {
+            Ok(id) => id,
+            Err(err) => panic!("cannot start the child {}: {}", self.name, err),
+        };
+        unsafe {
+            let runtime = rt();
+            if runtime.ids.contains(&id) {
+                // before the child runs, so that its end is seen even if it never does
+                runtime.supervisors.insert(id, supervisor);
+            } else if let Some(status) = exit_status(id) {
+                // it ran and ended while spawning switched
+                deliver_typed(supervisor, ChildExit(id, status));
+            }
+        }
+        id
+    }
+}
+
+// tell the supervisor of an ending thread, without switching
+pub(super) unsafe fn notify_supervisor(id: ThreadId, status: &ExitStatus) {
+    if rt().supervisors.is_empty() {
+        return;
+    }
+    if let Some(supervisor) = rt().supervisors.remove(&id) {
+        deliver_typed(supervisor, ChildExit(id, status.clone()));
+    }
+}
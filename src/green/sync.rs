@@ -0,0 +1,878 @@
+// Futures on green threads, and channels and pools shared with OS threads.
+
+use super::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvError, SendError, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Waker of a green thread blocked on a future
+pub(super) struct WakeTarget {
+    id: ThreadId,
+    // set when woken since the last poll
+    woken: AtomicBool,
+    remote: Arc<Remote>,
+}
+
+impl WakeTarget {
+    fn new(id: ThreadId, remote: Arc<Remote>) -> Self {
+        // a live waker may wake the thread from anywhere, like a RemoteSender
+        remote.senders.fetch_add(1, Ordering::Relaxed);
+        WakeTarget {
+            id,
+            woken: AtomicBool::new(false),
+            remote,
+        }
+    }
+}
+
+impl Wake for WakeTarget {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.remote.wake(self.id);
+    }
+}
+
+impl Drop for WakeTarget {
+    fn drop(&mut self) {
+        self.remote.senders.fetch_sub(1, Ordering::Release);
+        self.remote.unpark();
+    }
+}
+
+/// Drive `fut` to completion on the calling green thread.
+///
+/// While the future is pending, the thread waits like in `recv` and other green threads run;
+/// the future's waker makes it executable again, and may be used from any OS thread.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "block_on is called outside of green threads"
+        );
+        let target = Arc::new(WakeTarget::new((*current_ctx()).id, remote()));
+        let waker = Waker::from(target.clone());
+        let mut cx = TaskContext::from_waker(&waker);
+        let mut fut = pin!(fut);
+        loop {
+            target.woken.store(false, Ordering::Release);
+            if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                return output;
+            }
+            if !target.woken.load(Ordering::Acquire) {
+                wait();
+            }
+        }
+    }
+}
+
+// The result of a thread shared with its JoinHandle
+pub(super) struct JoinState<T> {
+    result: Option<T>,
+    // how the thread ended, set when it does
+    status: Option<ExitStatus>,
+    // the waker of the task awaiting the JoinHandle
+    waker: Option<Waker>,
+}
+
+/// A handle to the result of a thread spawned by `spawn_joinable` or `spawn_future`.
+///
+/// It is a Future, so the result can be awaited from a green thread with `block_on`,
+/// or from any async runtime on any OS thread, as `Ok`, or how the thread ended if it did
+/// without producing it, as `Err`; `join` waits for it from a green thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The Thread ID of the spawned thread.
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+    /// Let the thread run to completion without waiting for its result.
+    pub fn detach(self) {}
+
+    /// Wait until the thread finishes, letting the other threads run, and return its result.
+    ///
+    /// Panics if the thread panicked, overflowed its stack or was killed before producing it.
+    pub fn join(self) -> T {
+        self.try_join().unwrap_or_else(ended_without_result)
+    }
+
+    /// Wait until the thread finishes like `join`, and return its result, or how it ended
+    /// if it did without producing it.
+    pub fn try_join(self) -> Result<T, ExitStatus> {
+        unsafe {
+            assert!(
+                !current_ctx().is_null(),
+                "join is called outside of green threads"
+            );
+            assert!(
+                (*current_ctx()).id != self.id,
+                "a green thread cannot join itself"
+            );
+            let me = (*current_ctx()).id;
+            let _registered = Deregister(|| forget_exit_waiter(self.id, me));
+            loop {
+                if let Some(finished) = self.try_finished() {
+                    return finished;
+                }
+                // the exit of the thread wakes us up
+                add_exit_waiter(self.id, me);
+                wait();
+            }
+        }
+    }
+
+    /// Wait for the result like `join`, but at most `timeout`; on timeout the handle is given
+    /// back, so that joining can be retried or the thread killed.
+    pub fn join_timeout(self, timeout: Duration) -> Result<T, JoinHandle<T>> {
+        unsafe {
+            assert!(
+                !current_ctx().is_null(),
+                "join_timeout is called outside of green threads"
+            );
+            assert!(
+                (*current_ctx()).id != self.id,
+                "a green thread cannot join itself"
+            );
+            let (id, me) = (self.id, (*current_ctx()).id);
+            let deadline = Instant::now() + timeout;
+            // the exit of the thread or the timer wakes us up
+            let timer = rt().timers.insert(deadline, me);
+            let _registered = Deregister(|| {
+                forget_exit_waiter(id, me);
+                rt().timers.cancel(timer);
+            });
+            loop {
+                if let Some(result) = self.try_result() {
+                    return Ok(result);
+                }
+                if Instant::now() >= deadline {
+                    return Err(self);
+                }
+                add_exit_waiter(id, me);
+                wait();
+            }
+        }
+    }
+
+    // the result if the thread has produced it, panics if it ended without
+    fn try_result(&self) -> Option<T> {
+        self.try_finished()
+            .map(|finished| finished.unwrap_or_else(ended_without_result))
+    }
+
+    // the result if the thread has produced it, how it ended if it did without
+    fn try_finished(&self) -> Option<Result<T, ExitStatus>> {
+        self.state.lock().unwrap().take_finished()
+    }
+}
+
+fn ended_without_result<T>(status: ExitStatus) -> T {
+    panic!(
+        "the joined green thread ended without a result: {:?}",
+        status
+    )
+}
+
+impl<T: 'static> JoinHandle<T> {
+    // the handle of the thread `id` just spawned, whose state is told when it ends however
+    // it does
+    fn observing(id: ThreadId, state: Arc<Mutex<JoinState<T>>>) -> Self {
+        let shared = state.clone();
+        unsafe { observe_exit(id, move |status| JoinState::finish(&shared, status)) };
+        JoinHandle { id, state }
+    }
+}
+
+impl<T> JoinState<T> {
+    fn new() -> Arc<Mutex<JoinState<T>>> {
+        Arc::new(Mutex::new(JoinState {
+            result: None,
+            status: None,
+            waker: None,
+        }))
+    }
+
+    fn complete(state: &Mutex<JoinState<T>>, result: T) {
+        state.lock().unwrap().result = Some(result);
+    }
+
+    // the thread has ended, so wake whoever awaits the handle
+    fn finish(state: &Mutex<JoinState<T>>, status: &ExitStatus) {
+        let mut state = state.lock().unwrap();
+        state.status = Some(status.clone());
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn take_finished(&mut self) -> Option<Result<T, ExitStatus>> {
+        if let Some(result) = self.result.take() {
+            return Some(Ok(result));
+        }
+        self.status.clone().map(Err)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, ExitStatus>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<T, ExitStatus>> {
+        let mut state = self.state.lock().unwrap();
+        match state.take_finished() {
+            Some(finished) => Poll::Ready(finished),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Spawn a green thread driving `fut` to completion with `block_on`,
+/// and return a handle to its output; panics if it cannot be spawned, see `spawn`.
+pub fn spawn_future<F>(fut: F, stack_size: usize) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    let state = JoinState::new();
+    let shared = state.clone();
+    let fut = async move { JoinState::complete(&shared, fut.await) };
+    let id = spawn_inner(run_future, stack_size, true, Some(Box::pin(fut)), None)
+        .expect("cannot spawn a green thread");
+    JoinHandle::observing(id, state)
+}
+
+/// Spawn a green thread running `f`, and return a handle to its result;
+/// panics if it cannot be spawned, see `spawn`.
+pub fn spawn_joinable<F, T>(f: F, stack_size: usize) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + 'static,
+    T: 'static,
+{
+    let state = JoinState::new();
+    let shared = state.clone();
+    let id = spawn(move || JoinState::complete(&shared, f()), stack_size)
+        .expect("cannot spawn a green thread");
+    JoinHandle::observing(id, state)
+}
+
+/// Wait for every handle, letting the other threads run, and return the results
+/// in the order of the handles.
+pub fn join_all<T>(handles: Vec<JoinHandle<T>>) -> Vec<T> {
+    handles.into_iter().map(JoinHandle::join).collect()
+}
+
+/// Green threads whose results are collected as they complete.
+pub struct JoinSet<T> {
+    handles: Vec<JoinHandle<T>>,
+}
+
+impl<T> Default for JoinSet<T> {
+    fn default() -> Self {
+        JoinSet::new()
+    }
+}
+
+impl<T> JoinSet<T> {
+    pub fn new() -> Self {
+        JoinSet {
+            handles: Vec::new(),
+        }
+    }
+    /// Spawn a green thread running `f` in the set, and return its id.
+    pub fn spawn<F>(&mut self, f: F, stack_size: usize) -> ThreadId
+    where
+        F: FnOnce() -> T + 'static,
+        T: 'static,
+    {
+        let handle = spawn_joinable(f, stack_size);
+        let id = handle.id();
+        self.handles.push(handle);
+        id
+    }
+    /// Add a thread spawned elsewhere to the set.
+    pub fn push(&mut self, handle: JoinHandle<T>) {
+        self.handles.push(handle);
+    }
+    /// The number of threads whose result has not been returned yet.
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Wait until a thread of the set finishes, letting the other threads run,
+    /// and return its result; None once the set is empty.
+    ///
+    /// Panics if a thread panicked or was killed before producing its result.
+    pub fn join_next(&mut self) -> Option<T> {
+        unsafe {
+            assert!(
+                !current_ctx().is_null(),
+                "join_next is called outside of green threads"
+            );
+            let me = (*current_ctx()).id;
+            let ids: Vec<ThreadId> = self.handles.iter().map(JoinHandle::id).collect();
+            let _registered = Deregister(|| {
+                for &id in &ids {
+                    forget_exit_waiter(id, me);
+                }
+            });
+            loop {
+                if self.handles.is_empty() {
+                    return None;
+                }
+                for i in 0..self.handles.len() {
+                    if let Some(result) = self.handles[i].try_result() {
+                        self.handles.swap_remove(i);
+                        return Some(result);
+                    }
+                }
+                // the exit of any thread of the set wakes us up
+                for handle in &self.handles {
+                    add_exit_waiter(handle.id, me);
+                }
+                wait();
+            }
+        }
+    }
+
+    /// Wait for every thread of the set, and return the results in the order they completed.
+    pub fn join_all(mut self) -> Vec<T> {
+        let mut results = Vec::with_capacity(self.len());
+        while let Some(result) = self.join_next() {
+            results.push(result);
+        }
+        results
+    }
+}
+
+/// An executor with the shape of smol's `Executor`/`LocalExecutor`, running every task
+/// on its own green thread, so that code written against those abstractions can be hosted here.
+#[derive(Debug, Clone, Copy)]
+pub struct Executor {
+    stack_size: usize,
+}
+
+/// Tasks of an `Executor` are not required to be Send, like smol's `LocalExecutor`.
+pub type LocalExecutor = Executor;
+
+/// A spawned task; awaiting it gives its output, or how it ended without one, and `detach`
+/// lets it run in the background.
+pub type Task<T> = JoinHandle<T>;
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            stack_size: default_stack_size(),
+        }
+    }
+    /// Use `stack_size` for the green threads of the spawned tasks.
+    pub fn with_stack_size(stack_size: usize) -> Self {
+        Executor { stack_size }
+    }
+    pub fn spawn<F>(&self, fut: F) -> Task<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        spawn_future(fut, self.stack_size)
+    }
+    /// Drive `fut` on the calling green thread while the spawned tasks make progress.
+    pub fn run<F: Future>(&self, fut: F) -> F::Output {
+        block_on(fut)
+    }
+    /// Same as `run`, for code calling `block_on` on an executor.
+    pub fn block_on<F: Future>(&self, fut: F) -> F::Output {
+        block_on(fut)
+    }
+}
+
+/// Run `a` on the calling green thread and `b` on a new one, and return both results;
+/// a panic of `b` is resumed on the calling thread once `a` has returned.
+pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA,
+    B: FnOnce() -> RB + 'static,
+    RB: 'static,
+{
+    // caught there, so that its payload is passed on as it is
+    let b = async move { std::panic::catch_unwind(std::panic::AssertUnwindSafe(b)) };
+    let handle = spawn_future(b, default_stack_size());
+    let ra = a();
+    match block_on(handle) {
+        Ok(Ok(rb)) => (ra, rb),
+        Ok(Err(payload)) => std::panic::resume_unwind(payload),
+        Err(status) => ended_without_result(status),
+    }
+}
+
+/// Map `f` over `items`, splitting them in halves run by `join` down to chunks of `min_len` items;
+/// a panic of `f` is resumed on the calling thread.
+pub fn par_map<T, R, F>(mut items: Vec<T>, min_len: usize, f: F) -> Vec<R>
+where
+    T: 'static,
+    R: 'static,
+    F: Fn(T) -> R + Clone + 'static,
+{
+    if items.len() <= min_len.max(1) {
+        return items.into_iter().map(f).collect();
+    }
+    let right = items.split_off(items.len() / 2);
+    let g = f.clone();
+    let (mut left, right) = join(
+        move || par_map(items, min_len, f),
+        move || par_map(right, min_len, g),
+    );
+    left.extend(right);
+    left
+}
+
+/// Why a nursery failed: the first of its children which returned an error or panicked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NurseryError<E> {
+    Failed(E),
+    /// a child panicked, with the panic message
+    Panicked(String),
+}
+
+/// A scope whose children are all finished (or cancelled) when `nursery` returns.
+pub struct Nursery<E> {
+    children: RefCell<Vec<ThreadId>>,
+    // the first failure of a child
+    failure: Rc<RefCell<Option<NurseryError<E>>>>,
+}
+
+impl<E> Nursery<E> {
+    // kill the children still alive, with whatever they spawned
+    fn cancel(&self) {
+        for &child in self.children.borrow().iter() {
+            if is_live(child) {
+                cancel_tree(child);
+            }
+        }
+    }
+}
+
+// whether `id` has not ended, which `exit_status` does not tell once it is forgotten
+fn is_live(id: ThreadId) -> bool {
+    unsafe { rt().ids.contains(&id) }
+}
+
+impl<E: 'static> Nursery<E> {
+    /// Spawn a child of the nursery; its error, or its panic, cancels its siblings.
+    pub fn spawn<F>(&self, f: F, stack_size: usize) -> ThreadId
+    where
+        F: FnOnce() -> Result<(), E> + 'static,
+    {
+        let failure = self.failure.clone();
+        let handle = spawn_future(
+            async move {
+                let error = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+                    Ok(Ok(())) => return,
+                    Ok(Err(error)) => NurseryError::Failed(error),
+                    Err(payload) => NurseryError::Panicked(panic_message(payload)),
+                };
+                failure.borrow_mut().get_or_insert(error);
+            },
+            stack_size,
+        );
+        let id = handle.id();
+        handle.detach();
+        self.children.borrow_mut().push(id);
+        id
+    }
+}
+
+impl<E> Drop for Nursery<E> {
+    // only the children of a nursery whose body panicked can still be alive here
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Run `body` with a nursery to spawn children in, then wait for all of them.
+///
+/// As soon as a child returns an error or panics, the remaining children are cancelled
+/// (see `cancel_tree`) and the first failure is returned; otherwise returns what `body` did.
+/// Must be called on a green thread.
+pub fn nursery<E, R, F>(body: F) -> Result<R, NurseryError<E>>
+where
+    E: 'static,
+    F: FnOnce(&Nursery<E>) -> R,
+{
+    assert!(
+        !current_ctx().is_null(),
+        "nursery is called outside of green threads"
+    );
+    let nursery = Nursery {
+        children: RefCell::new(Vec::new()),
+        failure: Rc::new(RefCell::new(None)),
+    };
+    let result = body(&nursery);
+
+    unsafe {
+        // the exit of any child wakes us up
+        let me = (*current_ctx()).id;
+        let children: Vec<ThreadId> = nursery.children.borrow().clone();
+        for &child in &children {
+            if is_live(child) {
+                add_exit_waiter(child, me);
+            }
+        }
+        let _registered = Deregister(|| {
+            for &child in &children {
+                forget_exit_waiter(child, me);
+            }
+        });
+        loop {
+            if let Some(failure) = nursery.failure.borrow_mut().take() {
+                nursery.cancel();
+                return Err(failure);
+            }
+            let children = nursery.children.borrow();
+            if !children.iter().any(|&child| is_live(child)) {
+                return Ok(result);
+            }
+            drop(children);
+            wait();
+        }
+    }
+}
+
+// How long the OS thread sleeps between retries when a channel adapter has nothing else to run
+pub(super) const RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+// let the other green threads run before retrying a non-blocking operation
+pub(super) fn retry_later() {
+    yield_now();
+    unsafe {
+        if current_ctx().is_null() || rt().contexts.len() == 1 {
+            // nobody else to run, so do not burn the CPU
+            thread::park_timeout(RETRY_INTERVAL);
+        }
+    }
+}
+
+/// Adapter for a `std::sync::mpsc::Receiver` whose `recv` yields to the other green threads
+/// instead of blocking the OS thread (and so every green thread) until a value arrives.
+pub struct YieldingReceiver<T> {
+    inner: mpsc::Receiver<T>,
+}
+
+impl<T> YieldingReceiver<T> {
+    pub fn new(inner: mpsc::Receiver<T>) -> Self {
+        YieldingReceiver { inner }
+    }
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            match self.inner.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Empty) => retry_later(),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+            }
+        }
+    }
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv()
+    }
+    pub fn into_inner(self) -> mpsc::Receiver<T> {
+        self.inner
+    }
+}
+
+/// Adapter for a `std::sync::mpsc::SyncSender` whose `send` yields to the other green threads
+/// while the channel is full, instead of blocking the OS thread.
+pub struct YieldingSyncSender<T> {
+    inner: mpsc::SyncSender<T>,
+}
+
+impl<T> YieldingSyncSender<T> {
+    pub fn new(inner: mpsc::SyncSender<T>) -> Self {
+        YieldingSyncSender { inner }
+    }
+    pub fn send(&self, mut value: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.inner.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(v)) => {
+                    value = v;
+                    retry_later();
+                }
+                Err(TrySendError::Disconnected(v)) => return Err(SendError(v)),
+            }
+        }
+    }
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.inner.try_send(value)
+    }
+    pub fn into_inner(self) -> mpsc::SyncSender<T> {
+        self.inner
+    }
+}
+
+impl<T> Clone for YieldingSyncSender<T> {
+    fn clone(&self) -> Self {
+        YieldingSyncSender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+pub(super) fn run_future() {
+    let fut = unsafe { (*current_ctx()).future.take().unwrap() };
+    block_on(fut);
+}
+
+// The maximum number of OS threads running closures of block_in_place
+pub(super) const MAX_BLOCKING_THREADS: usize = 16;
+
+pub(super) type Job = Box<dyn FnOnce() + Send>;
+
+// OS threads running the closures of block_in_place, started on demand
+pub(super) struct BlockingPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+    threads: AtomicUsize,
+    idle: AtomicUsize,
+}
+
+pub(super) static BLOCKING_POOL: OnceLock<BlockingPool> = OnceLock::new();
+
+impl BlockingPool {
+    fn get() -> &'static BlockingPool {
+        BLOCKING_POOL.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel();
+            BlockingPool {
+                sender: Mutex::new(sender),
+                receiver: Arc::new(Mutex::new(receiver)),
+                threads: AtomicUsize::new(0),
+                idle: AtomicUsize::new(0),
+            }
+        })
+    }
+    fn execute(&'static self, job: Job) {
+        self.sender.lock().unwrap().send(job).unwrap();
+
+        // start another thread if all of them are busy, otherwise the job waits for one
+        if self.idle.load(Ordering::Acquire) == 0 {
+            let started = self
+                .threads
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                    (n < MAX_BLOCKING_THREADS).then_some(n + 1)
+                });
+            if started.is_ok() {
+                thread::Builder::new()
+                    .name("green-blocking".into())
+                    .spawn(move || self.work())
+                    .unwrap();
+            }
+        }
+    }
+    fn work(&self) {
+        loop {
+            self.idle.fetch_add(1, Ordering::AcqRel);
+            let job = self.receiver.lock().unwrap().recv();
+            self.idle.fetch_sub(1, Ordering::AcqRel);
+            match job {
+                Ok(job) => job(),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+/// Run a blocking closure (e.g. a call into a blocking C library) on a dedicated OS thread,
+/// and let the other green threads run until it returns.
+///
+/// A panic in the closure is propagated to the calling green thread.
+/// Outside of green threads the closure just runs on the calling thread.
+pub fn block_in_place<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    if current_ctx().is_null() {
+        return f();
+    }
+    let (sender, receiver) = bridge();
+    BlockingPool::get().execute(Box::new(move || {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        let _ = sender.send(result);
+    }));
+    match receiver.recv().unwrap() {
+        Ok(result) => result,
+        Err(payload) => std::panic::resume_unwind(payload),
+    }
+}
+
+// The state shared by both ends of a bridge channel
+pub(super) struct Bridge<T> {
+    queue: Mutex<BridgeQueue<T>>,
+}
+
+pub(super) struct BridgeQueue<T> {
+    values: VecDeque<T>,
+    // the waker of the receiver waiting for a value
+    waker: Option<Waker>,
+    senders: usize,
+    receiver_alive: bool,
+}
+
+/// The sending end of a bridge channel, usable from green threads, async tasks and OS threads.
+pub struct BridgeSender<T> {
+    shared: Arc<Bridge<T>>,
+}
+
+/// The receiving end of a bridge channel.
+///
+/// A green thread receives with `recv`, which lets other green threads run while waiting;
+/// an async task of another runtime (e.g. tokio) awaits `recv_async`.
+pub struct BridgeReceiver<T> {
+    shared: Arc<Bridge<T>>,
+}
+
+/// Create an unbounded channel to pass values between green threads and async tasks
+/// running on another runtime, in either direction; use two channels for both directions.
+///
+/// Waiting green threads are woken through the cross-OS-thread wakeup path,
+/// and waiting async tasks through their Waker.
+pub fn bridge<T>() -> (BridgeSender<T>, BridgeReceiver<T>) {
+    let shared = Arc::new(Bridge {
+        queue: Mutex::new(BridgeQueue {
+            values: VecDeque::new(),
+            waker: None,
+            senders: 1,
+            receiver_alive: true,
+        }),
+    });
+    (
+        BridgeSender {
+            shared: shared.clone(),
+        },
+        BridgeReceiver { shared },
+    )
+}
+
+impl<T> BridgeSender<T> {
+    /// Send a value without blocking, or give it back if the receiver is dropped.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if !queue.receiver_alive {
+            return Err(value);
+        }
+        queue.values.push_back(value);
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for BridgeSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.queue.lock().unwrap().senders += 1;
+        BridgeSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BridgeSender<T> {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            // let the receiver see the disconnection
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The future returned by `BridgeReceiver::recv_async`.
+pub struct BridgeRecv<'a, T> {
+    receiver: &'a BridgeReceiver<T>,
+}
+
+impl<T> Future for BridgeRecv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<T>> {
+        let mut queue = self.receiver.shared.queue.lock().unwrap();
+        if let Some(value) = queue.values.pop_front() {
+            return Poll::Ready(Some(value));
+        }
+        if queue.senders == 0 {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> BridgeReceiver<T> {
+    /// Receive a value on a green thread, or None once all the senders are dropped.
+    pub fn recv(&self) -> Option<T> {
+        block_on(self.recv_async())
+    }
+    /// Receive a value from an async task, or None once all the senders are dropped.
+    pub fn recv_async(&self) -> BridgeRecv<'_, T> {
+        BridgeRecv { receiver: self }
+    }
+    pub fn try_recv(&self) -> Option<T> {
+        self.shared.queue.lock().unwrap().values.pop_front()
+    }
+    // like `recv`, but gives up once `deadline` passes
+    #[cfg(feature = "net")]
+    pub(super) fn recv_until(&self, deadline: Instant) -> Result<Option<T>, Elapsed> {
+        unsafe {
+            assert!(
+                !current_ctx().is_null(),
+                "recv is called outside of green threads"
+            );
+            // the timer wakes us up to see the deadline passed
+            let timer = rt().timers.insert(deadline, (*current_ctx()).id);
+            let _registered = Deregister(|| rt().timers.cancel(timer));
+            let mut recv = self.recv_async();
+            block_on(std::future::poll_fn(|cx| {
+                match Pin::new(&mut recv).poll(cx) {
+                    Poll::Ready(value) => Poll::Ready(Ok(value)),
+                    Poll::Pending if Instant::now() >= deadline => Poll::Ready(Err(Elapsed)),
+                    Poll::Pending => Poll::Pending,
+                }
+            }))
+        }
+    }
+}
+
+impl<T> Drop for BridgeReceiver<T> {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.receiver_alive = false;
+        queue.values.clear();
+    }
+}
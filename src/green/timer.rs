@@ -0,0 +1,271 @@
+// Timers: a hashed timer wheel per runtime waking green threads at their deadlines,
+// and the sleeping and delayed spawning built on it.
+
+use super::*;
+use std::time::{Duration, Instant};
+
+// The resolution of the wheel; deadlines are rounded up to the next tick
+const TICK: Duration = Duration::from_millis(1);
+
+// The number of slots of the wheel, a timer lands in the slot of its tick modulo this
+const WHEEL_SLOTS: usize = 256;
+
+// A thread to wake at the tick `due`
+struct Timer {
+    due: u64,
+    key: u64,
+    id: ThreadId,
+}
+
+// What cancels a timer, its tick telling in which slot it is
+#[derive(Debug, Clone, Copy)]
+pub(super) struct TimerKey {
+    due: u64,
+    key: u64,
+}
+
+// Timers hashed by their tick into the slots, so that adding and cancelling do not sort and
+// a pass of the scheduler only looks at the slots of the ticks elapsed since the last one.
+// Timers more than a turn of the wheel away wait in their slot for the later turns.
+pub(super) struct TimerWheel {
+    slots: Vec<Vec<Timer>>,
+    // the instant of tick 0
+    origin: Instant,
+    // the last tick whose slot has been expired
+    tick: u64,
+    len: usize,
+    next_key: u64,
+}
+
+impl TimerWheel {
+    pub(super) fn new() -> Self {
+        TimerWheel {
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            origin: Instant::now(),
+            tick: 0,
+            len: 0,
+            next_key: 0,
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // the first tick at or after `deadline`
+    fn due_tick(&self, deadline: Instant) -> u64 {
+        let nanos = deadline.saturating_duration_since(self.origin).as_nanos();
+        nanos.div_ceil(TICK.as_nanos()) as u64
+    }
+
+    // the last tick reached at `now`
+    fn elapsed_tick(&self, now: Instant) -> u64 {
+        let nanos = now.saturating_duration_since(self.origin).as_nanos();
+        (nanos / TICK.as_nanos()) as u64
+    }
+
+    // wake `id` once `deadline` has passed, returns the key to cancel it with
+    pub(super) fn insert(&mut self, deadline: Instant, id: ThreadId) -> TimerKey {
+        // a deadline already passed fires on the next pass
+        let due = self.due_tick(deadline).max(self.tick + 1);
+        let key = self.next_key;
+        self.next_key += 1;
+        self.slots[due as usize % WHEEL_SLOTS].push(Timer { due, key, id });
+        self.len += 1;
+        TimerKey { due, key }
+    }
+
+    // forget a timer if it has not fired, e.g. because its thread was woken otherwise
+    pub(super) fn cancel(&mut self, key: TimerKey) {
+        let slot = &mut self.slots[key.due as usize % WHEEL_SLOTS];
+        if let Some(i) = slot.iter().position(|timer| timer.key == key.key) {
+            slot.swap_remove(i);
+            self.len -= 1;
+        }
+    }
+
+    // remove the timers due at `now` and return the threads to wake
+    fn expire(&mut self, now: Instant) -> Vec<ThreadId> {
+        let now = self.elapsed_tick(now);
+        let mut woken = Vec::new();
+        if now <= self.tick {
+            return woken;
+        }
+        // past a whole turn every slot is visited once
+        let first = self.tick + 1;
+        let last = now.min(self.tick + WHEEL_SLOTS as u64);
+        for tick in first..=last {
+            let slot = &mut self.slots[tick as usize % WHEEL_SLOTS];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].due <= now {
+                    woken.push(slot.swap_remove(i).id);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.len -= woken.len();
+        self.tick = now;
+        woken
+    }
+
+    // the instant of the earliest timer, for the idle scheduler to park until
+    fn next_deadline(&self) -> Option<Instant> {
+        let due = self.slots.iter().flatten().map(|timer| timer.due).min()?;
+        Some(self.origin + Duration::from_nanos(due * TICK.as_nanos() as u64))
+    }
+}
+
+// wake the threads whose timers have expired, called on every pass of the scheduler
+pub(super) unsafe fn expire_timers() {
+    if rt().timers.is_empty() {
+        return;
+    }
+    let runtime = rt();
+    for id in runtime.timers.expire(Instant::now()) {
+        if let Some(ctx) = runtime.waiting.remove(&id) {
+            runtime.contexts.push_back(ctx);
+        }
+    }
+}
+
+// how long an idle scheduler may park before the next timer expires, None if there is none
+pub(super) unsafe fn until_next_timer() -> Option<Duration> {
+    let deadline = rt().timers.next_deadline()?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Park the calling green thread for at least `duration`, letting the other threads run.
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Park the calling green thread until `deadline`, letting the other threads run.
+pub fn sleep_until(deadline: Instant) {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "sleep is called outside of green threads"
+        );
+        let key = rt().timers.insert(deadline, (*current_ctx()).id);
+        let _registered = Deregister(|| rt().timers.cancel(key));
+        // a message may wake the thread before its timer
+        while Instant::now() < deadline {
+            wait();
+        }
+    }
+}
+
+/// Spawn a green thread which starts running `func` once `delay` has elapsed,
+/// and return its id right away.
+pub fn spawn_after<F: FnOnce() + 'static>(
+    delay: Duration,
+    func: F,
+    stack_size: usize,
+) -> Result<ThreadId, SpawnError> {
+    let deadline = Instant::now() + delay;
+    spawn(
+        move || {
+            sleep_until(deadline);
+            func()
+        },
+        stack_size,
+    )
+}
+
+/// Ticks at the boundaries of a period, see `interval`.
+#[derive(Debug, Clone)]
+pub struct Interval {
+    period: Duration,
+    // the next boundary, a whole number of periods after the creation of the interval
+    next: Instant,
+}
+
+/// An interval ticking every `period`, the first time one period from now;
+/// for heartbeats and other periodic work of a green thread.
+pub fn interval(period: Duration) -> Interval {
+    assert!(
+        !period.is_zero(),
+        "the period of an interval must be positive"
+    );
+    Interval {
+        period,
+        next: Instant::now() + period,
+    }
+}
+
+impl Interval {
+    /// Park the calling green thread until the next boundary, and return it.
+    ///
+    /// The boundaries are fixed when the interval is created, so the time spent between
+    /// ticks does not make them drift; the ones missed while the caller was busy are skipped.
+    pub fn tick(&mut self) -> Instant {
+        sleep_until(self.next);
+        let boundary = self.next;
+        self.next += self.period;
+        let now = Instant::now();
+        if self.next <= now {
+            let missed = (now - self.next).as_nanos() / self.period.as_nanos() + 1;
+            self.next += Duration::from_nanos((missed * self.period.as_nanos()) as u64);
+        }
+        boundary
+    }
+
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// The error of `timeout` when its deadline passes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Run `f` on the calling green thread, or give up with `Elapsed` if it is still parked
+/// (in `recv`, `sleep`, `join`, `wait_for_exit`...) once `duration` has elapsed.
+///
+/// The operation is abandoned by unwinding out of it from where it parks, so the values
+/// it owns are dropped; `f` is never interrupted while it runs, only while it is parked.
+/// Timeouts nest, each one cutting short what it runs at its own deadline.
+pub fn timeout<R, F: FnOnce() -> R>(duration: Duration, f: F) -> Result<R, Elapsed> {
+    unsafe {
+        assert!(
+            !current_ctx().is_null(),
+            "timeout is called outside of green threads"
+        );
+        let deadline = Instant::now() + duration;
+        let ctx = current_ctx();
+        let outer = (*ctx).deadline;
+        (*ctx).deadline = Some(outer.map_or(deadline, |outer| outer.min(deadline)));
+        let key = rt().timers.insert(deadline, (*ctx).id);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+
+        rt().timers.cancel(key);
+        (*ctx).deadline = outer;
+        match result {
+            Ok(value) => Ok(value),
+            // the deadline of an outer timeout may have passed first
+            Err(payload) if payload.is::<Elapsed>() && Instant::now() >= deadline => Err(Elapsed),
+            Err(payload) => std::panic::resume_unwind(payload),
+        }
+    }
+}
+
+// abandon the operation parking the running thread if the deadline of its timeout passed,
+// the unwinding is caught by `timeout`
+pub(super) unsafe fn check_deadline() {
+    if let Some(deadline) = (*current_ctx()).deadline {
+        if Instant::now() >= deadline {
+            std::panic::resume_unwind(Box::new(Elapsed));
+        }
+    }
+}
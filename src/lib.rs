@@ -0,0 +1,12 @@
+//! Green threads switching contexts in user space, with mailboxes of `u64` messages
+//! between them.
+//!
+//! Everything is in `green`; `prelude` has what a producer/consumer needs.
+
+pub mod green;
+
+/// The entry point of the runtime and the functions of almost every green thread.
+#[cfg(feature = "scheduler")]
+pub mod prelude {
+    pub use crate::green::{recv, send, spawn, spawn_from_main, yield_now, ThreadId};
+}
@@ -1,47 +1,125 @@
-mod green;
+use green_thread_rs::green;
+
+use std::env;
+use std::process;
+use std::time::Instant;
+
+const STACK_SIZE: usize = 2 * 1024 * 1024;
+const BENCH_STACK_SIZE: usize = 64 * 1024;
+
+const USAGE: &str =
+    "usage: green_thread_rs [demo | bench [--threads N] [--msgs M] | chaos [--seed S]]";
+
+const DEMO_MSGS: u64 = 10;
 
 fn producer() {
-    let id = green::spawn(consumer, 2 * 1024 * 1024);
-    for i in 0..10 {
+    let id = green::spawn_with(consumer, DEMO_MSGS, STACK_SIZE).unwrap();
+    for i in 0..DEMO_MSGS {
         println!("Produce: {}", i);
         green::send(id, i);
     }
 }
 
-fn consumer() {
-    for _ in 0..10 {
+fn consumer(msgs: u64) {
+    for _ in 0..msgs {
         let msg = green::recv().unwrap();
         println!("Consume: {}", msg);
     }
 }
 
-fn actor() {
-    green::spawn_from_main(producer, 2 * 1024 * 1024);
+fn bench_producer(threads: u64, msgs: u64) {
+    let ids: Vec<green::ThreadId> = (0..threads)
+        .map(|_| green::spawn_no_fp(move || bench_consumer(msgs), BENCH_STACK_SIZE).unwrap())
+        .collect();
+    for _ in 0..msgs {
+        green::send_all(&ids, 1);
+    }
 }
 
-// fn mash() {
-//     green::spawn(ortega, 2 * 1024 * 1024);
-//     for i in 0..10 {
-//         println!("Mash: {}", i);
-//         green::schedule();
-//     }
-// }
+fn bench_consumer(msgs: u64) {
+    for _ in 0..msgs {
+        green::recv().unwrap();
+    }
+}
 
-// fn ortega() {
-//     for _ in 0..10 {
-//         println!("Ortega");
-//         green::schedule();
-//     }
-// }
+fn bench(threads: u64, msgs: u64) {
+    let started = Instant::now();
+    let report = green::spawn_from_main(move || bench_producer(threads, msgs), STACK_SIZE);
+    let elapsed = started.elapsed();
+    let rate = report.delivered as f64 / elapsed.as_secs_f64();
+    println!(
+        "{} threads, {} messages in {:?} ({:.0} msgs/s)",
+        report.spawned, report.delivered, elapsed, rate
+    );
+    println!("{:?}", report);
+}
 
-// fn gaia() {
-//     green::spawn(mash, 2 * 1024 * 1024);
-//     for i in 0..10 {
-//         println!("Gaia: {}", i);
-//         green::schedule();
-//     }
-// }
+#[cfg(feature = "model")]
+fn chaos_root() {
+    let collector = green::spawn(chaos_collector, BENCH_STACK_SIZE).unwrap();
+    for _ in 0..8 {
+        green::spawn_with(chaos_worker, collector, BENCH_STACK_SIZE).unwrap();
+    }
+}
+
+#[cfg(feature = "model")]
+fn chaos_collector() {
+    for received in 1..=8 {
+        green::recv();
+        println!("collected {} at t={}", received, green::sim_now());
+    }
+}
+
+#[cfg(feature = "model")]
+fn chaos_worker(collector: green::ThreadId) {
+    green::send(collector, 1);
+}
+
+#[cfg(feature = "model")]
+fn chaos(seed: u64) {
+    let mut config = green::SimConfig::new(seed);
+    config.drop_rate = 0.05;
+    config.duplicate_rate = 0.05;
+    config.delay_rate = 0.3;
+    config.max_delay = 50;
+    config.crash_rate = 0.02;
+    println!(
+        "{:?}",
+        green::simulate(chaos_root, BENCH_STACK_SIZE, config)
+    );
+}
+
+#[cfg(not(feature = "model"))]
+fn chaos(_seed: u64) {
+    eprintln!("chaos needs the `model` feature: cargo run --features model -- chaos");
+    process::exit(2);
+}
+
+// the value of `--name N` in `args`, or `default`
+fn flag(args: &[String], name: &str, default: u64) -> u64 {
+    match args.iter().position(|arg| arg == name) {
+        None => default,
+        Some(i) => match args.get(i + 1).and_then(|value| value.parse().ok()) {
+            Some(value) => value,
+            None => {
+                eprintln!("{} needs a number\n{}", name, USAGE);
+                process::exit(2);
+            }
+        },
+    }
+}
 
 fn main() {
-    actor();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None | Some("demo") => {
+            green::spawn_from_main(producer, STACK_SIZE);
+        }
+        Some("bench") => bench(flag(&args, "--threads", 100), flag(&args, "--msgs", 10_000)),
+        Some("chaos") => chaos(flag(&args, "--seed", 0)),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
 }
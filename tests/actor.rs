@@ -0,0 +1,657 @@
+// Actors and what is built around them: requests, topics, servers, supervisors, links and
+// monitors, groups, names and routers.
+#![cfg(feature = "scheduler")]
+
+mod common;
+
+use common::{run, STACK};
+use green_thread_rs::green::*;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+type Log = Rc<RefCell<Vec<String>>>;
+
+// yield until `name` is registered to a thread other than `other`
+fn wait_for_name(name: &str, other: Option<ThreadId>) -> ThreadId {
+    let started = Instant::now();
+    loop {
+        match lookup(name) {
+            Some(id) if Some(id) != other => return id,
+            _ if started.elapsed() > Duration::from_secs(5) => panic!("{} never started", name),
+            _ => sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
+// yield until `starts` reaches `count`
+fn wait_for_starts(starts: &Cell<usize>, count: usize) {
+    while starts.get() < count {
+        yield_now();
+    }
+}
+
+#[test]
+fn ask_waits_for_the_reply_of_the_request() {
+    let (replied, dropped, ended) = run(|| {
+        let answering = spawn(
+            || {
+                let request = recv_request::<String>().unwrap();
+                let reply = format!("{} from {}", request.msg(), request.sender());
+                request.reply(reply).unwrap();
+            },
+            STACK,
+        )
+        .unwrap();
+        let me = current();
+        let replied = ask::<String>(answering, 7);
+
+        let dropping = spawn(|| drop(recv_request::<String>()), STACK).unwrap();
+        let dropped = ask::<String>(dropping, 1);
+
+        // ends with the request left in its mailbox, which drops it
+        let ending = spawn(wait_for_message, STACK).unwrap();
+        spawn(move || send(ending, 0), STACK).unwrap();
+        let left = ask::<String>(ending, 2);
+        let ended = ask::<String>(ending, 3);
+        (
+            replied.map(|reply| reply == format!("7 from {}", me)),
+            (dropped, left),
+            ended,
+        )
+    });
+    assert_eq!(replied, Ok(true));
+    assert_eq!(dropped, (Err(AskError::Dropped), Err(AskError::Dropped)));
+    assert_eq!(ended, Err(AskError::NoReceiver));
+}
+
+fn wait_for_message() {
+    recv();
+}
+
+#[test]
+fn published_messages_reach_the_subscribers_of_the_topic() {
+    let (delivered, received, left) = run(|| {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let ids: Vec<_> = (0..2)
+            .map(|i| {
+                let out = received.clone();
+                spawn(
+                    move || {
+                        assert!(subscribe("news"));
+                        assert!(!subscribe("news"));
+                        let msg = recv().unwrap();
+                        out.borrow_mut().push((i, msg));
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        assert_eq!(subscribers("news"), ids);
+        assert_eq!(topics(), ["news"]);
+        let delivered = (publish("news", 5), publish("sports", 6));
+        for id in ids {
+            wait_for_exit(id);
+        }
+        // the subscribers which ended are unsubscribed
+        (delivered, received.take(), (subscribers("news"), topics()))
+    });
+    assert_eq!(delivered, (2, 0));
+    assert_eq!(received, [(0, 5), (1, 5)]);
+    assert_eq!(left, (vec![], Vec::<String>::new()));
+}
+
+struct Summer {
+    sum: u64,
+    log: Log,
+}
+
+impl Actor for Summer {
+    type Msg = u64;
+
+    fn handle(&mut self, msg: u64) {
+        assert!(msg != 0, "cannot add zero");
+        self.sum += msg;
+    }
+
+    fn on_start(&mut self) {
+        self.log.borrow_mut().push("start".to_string());
+    }
+
+    fn on_restart(&mut self) {
+        self.log.borrow_mut().push("restart".to_string());
+    }
+
+    fn on_stop(&mut self) {
+        self.log.borrow_mut().push(format!("stop {}", self.sum));
+    }
+}
+
+#[test]
+fn an_actor_handles_its_messages_between_its_start_and_stop() {
+    let log = run(|| {
+        let log = Log::default();
+        let addr = spawn_actor(
+            Summer {
+                sum: 0,
+                log: log.clone(),
+            },
+            STACK,
+        )
+        .unwrap();
+        for msg in 1..=3 {
+            addr.send(msg);
+        }
+        addr.stop();
+        wait_for_exit(addr.id());
+        assert!(!addr.is_alive());
+        assert_eq!(Addr::<Summer>::from_id(addr.id()), addr);
+
+        // a panic in a handler ends the actor, still stopping it
+        let failing = spawn_actor(
+            Summer {
+                sum: 0,
+                log: log.clone(),
+            },
+            STACK,
+        )
+        .unwrap();
+        failing.send(4);
+        failing.send(0);
+        let status = wait_for_exit(failing.id());
+        log.borrow_mut().push(format!("{:?}", status));
+        log.take()
+    });
+    assert_eq!(
+        log,
+        [
+            "start",
+            "stop 6",
+            "start",
+            "stop 4",
+            "Panicked(\"cannot add zero\")"
+        ]
+    );
+}
+
+struct Counter {
+    count: u64,
+    log: Log,
+}
+
+enum Call {
+    Get,
+    Slow,
+}
+
+impl Server for Counter {
+    type Call = Call;
+    type Reply = u64;
+    type Cast = u64;
+
+    fn init(&mut self) {
+        self.log.borrow_mut().push("init".to_string());
+    }
+
+    fn handle_call(&mut self, call: Call, _from: ThreadId) -> u64 {
+        if let Call::Slow = call {
+            sleep(Duration::from_millis(50));
+        }
+        self.count
+    }
+
+    fn handle_cast(&mut self, cast: u64) {
+        self.count += cast;
+    }
+
+    fn terminate(&mut self) {
+        self.log
+            .borrow_mut()
+            .push(format!("terminate {}", self.count));
+    }
+}
+
+#[test]
+fn a_server_answers_calls_and_handles_casts() {
+    let (replies, log) = run(|| {
+        let log = Log::default();
+        let server = spawn_server(
+            Counter {
+                count: 0,
+                log: log.clone(),
+            },
+            STACK,
+        )
+        .unwrap();
+        server.cast(2);
+        server.cast(3);
+        let got = server.call(Call::Get, None);
+        let slow = server.call(Call::Slow, Some(Duration::from_millis(5)));
+        let in_time = server.call(Call::Get, Some(Duration::from_secs(5)));
+        server.stop();
+        wait_for_exit(server.id());
+        let stopped = server.call(Call::Get, None);
+        ((got, slow, in_time, stopped), log.take())
+    });
+    assert_eq!(
+        replies,
+        (
+            Ok(5),
+            Err(AskError::Timeout),
+            Ok(5),
+            Err(AskError::NoReceiver)
+        )
+    );
+    assert_eq!(log, ["init", "terminate 5"]);
+}
+
+#[test]
+fn a_supervisor_restarts_the_child_which_ended() {
+    let (starts, restarted) = run(|| {
+        let starts = Rc::new(Cell::new(0));
+        let counted = starts.clone();
+        let supervisor = Supervisor::new(Strategy::OneForOne)
+            .child(
+                "worker",
+                move || {
+                    counted.set(counted.get() + 1);
+                    recv();
+                    panic!("asked to fail");
+                },
+                STACK,
+            )
+            .spawn(STACK)
+            .unwrap();
+        let first = wait_for_name("worker", None);
+        send(first, 0);
+        let second = wait_for_name("worker", Some(first));
+        wait_for_starts(&starts, 2);
+        kill(supervisor);
+        // the children are cancelled with their supervisor
+        let restarted = (exit_status(first), wait_for_exit(second));
+        (starts.get(), restarted)
+    });
+    assert_eq!(starts, 2);
+    assert_eq!(
+        restarted,
+        (
+            Some(ExitStatus::Panicked("asked to fail".to_string())),
+            ExitStatus::Killed
+        )
+    );
+}
+
+#[test]
+fn a_child_killed_before_it_first_runs_is_restarted() {
+    let (ran_before, starts) = run(|| {
+        let starts = Rc::new(Cell::new(0));
+        let counted = starts.clone();
+        let supervisor = Supervisor::new(Strategy::OneForOne)
+            .child(
+                "worker",
+                move || {
+                    counted.set(counted.get() + 1);
+                    recv();
+                },
+                STACK,
+            )
+            .spawn(STACK)
+            .unwrap();
+        // the supervisor spawned the child, which waits behind this thread to run
+        let first = lookup("worker").unwrap();
+        let ran_before = starts.get();
+        kill(first);
+        wait_for_name("worker", Some(first));
+        wait_for_starts(&starts, 1);
+        kill(supervisor);
+        (ran_before, starts.get())
+    });
+    assert_eq!(ran_before, 0);
+    assert_eq!(starts, 1);
+}
+
+#[test]
+fn a_supervisor_restarts_all_children_with_one_for_all() {
+    let (others, actor_log) = run(|| {
+        let log = Log::default();
+        let make_log = log.clone();
+        let supervisor = Supervisor::new(Strategy::OneForAll)
+            .child("other", wait_for_message, STACK)
+            .actor(
+                "summer",
+                move || Summer {
+                    sum: 0,
+                    log: make_log.clone(),
+                },
+                STACK,
+            )
+            .spawn(STACK)
+            .unwrap();
+        let other = wait_for_name("other", None);
+        let summer = wait_for_name("summer", None);
+        Addr::<Summer>::from_id(summer).send(0);
+        wait_for_name("summer", Some(summer));
+        let restarted_other = wait_for_name("other", Some(other));
+        kill(supervisor);
+        ((exit_status(other), restarted_other != other), log.take())
+    });
+    assert_eq!(others, (Some(ExitStatus::Killed), true));
+    assert_eq!(actor_log, ["start", "stop 0", "restart", "stop 0"]);
+}
+
+#[test]
+fn restart_policies_tell_which_ends_are_restarted() {
+    let starts = run(|| {
+        let starts = Rc::new(RefCell::new(Vec::new()));
+        let child = |name: &'static str, fail: bool| {
+            let starts = starts.clone();
+            move || {
+                starts.borrow_mut().push(name);
+                assert!(!fail, "{} fails", name);
+            }
+        };
+        let supervisor = Supervisor::new(Strategy::OneForOne)
+            .child_with(
+                "ends",
+                child("transient ends", false),
+                STACK,
+                Restart::Transient,
+            )
+            .child_with(
+                "fails",
+                child("temporary fails", true),
+                STACK,
+                Restart::Temporary,
+            )
+            .spawn(STACK)
+            .unwrap();
+        sleep(Duration::from_millis(10));
+        kill(supervisor);
+        starts.take()
+    });
+    assert_eq!(starts, ["transient ends", "temporary fails"]);
+}
+
+#[test]
+fn a_supervisor_restarting_too_often_gives_up_after_its_backoff() {
+    let (status, starts) = run(|| {
+        let starts = Rc::new(RefCell::new(Vec::new()));
+        let counted = starts.clone();
+        let supervisor = Supervisor::new(Strategy::OneForOne)
+            .child(
+                "crashing",
+                move || {
+                    counted.borrow_mut().push(Instant::now());
+                    panic!("crash");
+                },
+                STACK,
+            )
+            .intensity(3, Duration::from_secs(5))
+            .backoff(Duration::from_millis(10), Duration::from_millis(25))
+            .spawn(STACK)
+            .unwrap();
+        (wait_for_exit(supervisor), starts.take())
+    });
+    assert_eq!(
+        status,
+        ExitStatus::Panicked("the child crashing is restarted more than 3 times in 5s".to_string())
+    );
+    let gaps: Vec<_> = starts.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    assert_eq!(gaps.len(), 3);
+    // 10ms, doubled to 20ms, then capped at 25ms
+    for (gap, least) in gaps.iter().zip([10, 20, 25]) {
+        assert!(*gap >= Duration::from_millis(least), "{:?}", gaps);
+    }
+}
+
+#[test]
+fn a_linked_thread_ending_abnormally_kills_the_other() {
+    let (killed, survived, links_left) = run(|| {
+        let failing = spawn(
+            || {
+                recv();
+                panic!("linked");
+            },
+            STACK,
+        )
+        .unwrap();
+        let linked = spawn(
+            move || {
+                assert!(link(failing));
+                recv();
+            },
+            STACK,
+        )
+        .unwrap();
+        assert_eq!(links(failing), [linked]);
+        send(failing, 0);
+        let killed = wait_for_exit(linked);
+
+        // a normal end leaves the linked thread running
+        let returning = spawn(wait_for_message, STACK).unwrap();
+        let survivor = spawn(
+            move || {
+                link(returning);
+                sleep(Duration::from_millis(10));
+            },
+            STACK,
+        )
+        .unwrap();
+        send(returning, 0);
+        let survived = wait_for_exit(survivor);
+
+        let unlinked = spawn(wait_for_message, STACK).unwrap();
+        link(unlinked);
+        assert!(unlink(unlinked));
+        kill(unlinked);
+        (killed, survived, links(current()))
+    });
+    assert_eq!(killed, ExitStatus::Killed);
+    assert_eq!(survived, ExitStatus::Normal);
+    assert_eq!(links_left, []);
+}
+
+#[test]
+fn a_thread_trapping_exits_is_told_instead_of_killed() {
+    let exit = run(|| {
+        let failing = spawn(
+            || {
+                recv();
+                panic!("linked");
+            },
+            STACK,
+        )
+        .unwrap();
+        let trapped_before = trap_exit(true);
+        link(failing);
+        send(failing, 0);
+        let exit = recv_typed::<LinkExit>().unwrap();
+        (
+            exit.id == failing,
+            exit.reason,
+            trapped_before,
+            trap_exit(false),
+        )
+    });
+    assert_eq!(
+        exit,
+        (
+            true,
+            ExitStatus::Panicked("linked".to_string()),
+            false,
+            true
+        )
+    );
+}
+
+#[test]
+fn a_monitor_receives_down_when_the_thread_ends() {
+    let (down, at_once, removed) = run(|| {
+        let watched = spawn(wait_for_message, STACK).unwrap();
+        let watching = monitor(watched);
+        kill(watched);
+        let down = recv_typed::<Down>().unwrap();
+        let down = (down.monitor == watching, down.id == watched, down.reason);
+
+        // a thread which already ended is reported right away
+        let at_once = monitor(watched);
+        let at_once = recv_typed::<Down>().map(|down| down.monitor == at_once);
+
+        let other = spawn(wait_for_message, STACK).unwrap();
+        let removed = demonitor(monitor(other));
+        kill(other);
+        (down, at_once, (removed, try_recv_typed::<Down>().is_none()))
+    });
+    assert_eq!(down, (true, true, ExitStatus::Killed));
+    assert_eq!(at_once, Some(true));
+    assert_eq!(removed, (true, true));
+}
+
+#[test]
+fn a_group_is_sent_to_and_killed_at_once() {
+    let (sent, received, killed, left) = run(|| {
+        let gid = create_group();
+        let received = Rc::new(Cell::new(0));
+        let members: Vec<_> = (0..3)
+            .map(|_| {
+                let received = received.clone();
+                spawn(
+                    move || {
+                        assert!(join_group(gid));
+                        assert!(!join_group(gid));
+                        while recv().is_some() {
+                            received.set(received.get() + 1);
+                        }
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        assert_eq!(group_members(gid), members);
+        let sent = group_send(gid, 1);
+        yield_now();
+        let killed = group_kill(gid);
+        (sent, received.get(), killed, group_members(gid))
+    });
+    assert_eq!(sent, 3);
+    assert_eq!(received, 3);
+    assert_eq!(killed, 3);
+    assert_eq!(left, []);
+}
+
+#[test]
+fn names_are_registered_to_live_threads_only() {
+    let (results, listed, after) = run(|| {
+        let a = spawn(wait_for_message, STACK).unwrap();
+        let b = spawn(wait_for_message, STACK).unwrap();
+        let results = (
+            register("b", b),
+            register("a", a),
+            register("a", b),
+            register("c", a),
+            lookup("a") == Some(a),
+        );
+        let listed = registered() == [("a".to_string(), a), ("b".to_string(), b)];
+        assert_eq!(unregister("b"), Some(b));
+        send(a, 0);
+        wait_for_exit(a);
+        (results, listed, (registered(), register("a", a)))
+    });
+    assert_eq!(
+        results,
+        (
+            Ok(()),
+            Ok(()),
+            Err(RegisterError::NameInUse("a".to_string())),
+            Err(RegisterError::AlreadyNamed("a".to_string())),
+            true
+        )
+    );
+    assert!(listed);
+    assert!(after.0.is_empty());
+    assert!(matches!(after.1, Err(RegisterError::NoSuchThread(_))));
+}
+
+// spawn threads pushing `(index, msg)` to the log for each message they receive
+fn spawn_targets(count: usize, log: &Rc<RefCell<Vec<(usize, u64)>>>) -> Vec<ThreadId> {
+    (0..count)
+        .map(|i| {
+            let log = log.clone();
+            spawn(
+                move || {
+                    while let Some(msg) = recv() {
+                        log.borrow_mut().push((i, msg));
+                    }
+                },
+                STACK,
+            )
+            .unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn routers_choose_a_target_by_their_policy() {
+    let (round_robin, hashed, least, dropped) = run(|| {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let targets = spawn_targets(3, &log);
+        let mut router = Router::new(targets.clone(), Routing::RoundRobin);
+        for msg in 0..4 {
+            router.send(msg);
+        }
+        let round_robin = log.take();
+
+        let mut router = Router::new(targets.clone(), Routing::ConsistentHash);
+        let first = router.route("key");
+        let same = (0..10).all(|_| router.route("key") == first);
+        let other = targets.iter().copied().find(|&id| Some(id) != first);
+        router.remove(other.unwrap());
+        let hashed = (same, router.route("key") == first);
+
+        let idle = spawn(|| sleep(Duration::from_millis(5)), STACK).unwrap();
+        let busy = spawn(|| sleep(Duration::from_millis(5)), STACK).unwrap();
+        send(busy, 1);
+        let mut router = Router::new(vec![busy, idle], Routing::LeastMailbox);
+        let least = router.route(&()) == Some(idle);
+
+        for &id in &targets {
+            kill(id);
+        }
+        let mut router = Router::new(targets, Routing::RoundRobin);
+        (
+            round_robin,
+            hashed,
+            least,
+            (router.send(9), router.targets().len()),
+        )
+    });
+    assert_eq!(round_robin, [(0, 0), (1, 1), (2, 2), (0, 3)]);
+    assert_eq!(hashed, (true, true));
+    assert!(least);
+    assert_eq!(dropped, (None, 0));
+}
+
+#[test]
+fn a_router_thread_forwards_until_its_targets_end() {
+    let (received, status) = run(|| {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let targets = spawn_targets(2, &log);
+        let router =
+            spawn_router(Router::new(targets.clone(), Routing::RoundRobin), STACK).unwrap();
+        for msg in 1..=4 {
+            send(router, msg);
+        }
+        while log.borrow().len() < 4 {
+            yield_now();
+        }
+        for &id in &targets {
+            kill(id);
+        }
+        send(router, 5);
+        (log.take(), wait_for_exit(router))
+    });
+    assert_eq!(received, [(0, 1), (1, 2), (0, 3), (1, 4)]);
+    assert_eq!(status, ExitStatus::Normal);
+}
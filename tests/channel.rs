@@ -0,0 +1,253 @@
+// Channels between the green threads of a runtime: mpsc, oneshot, broadcast, watch and select.
+#![cfg(feature = "scheduler")]
+
+mod common;
+
+use common::{run, STACK};
+use green_thread_rs::green::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[test]
+fn a_receiver_moves_to_another_thread_and_sees_the_senders_leave() {
+    let (received, refused) = run(|| {
+        let (tx, rx) = channel::<u64>();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let out = received.clone();
+        let receiver = spawn(
+            move || {
+                while let Some(value) = rx.recv() {
+                    out.borrow_mut().push(value);
+                }
+            },
+            STACK,
+        )
+        .unwrap();
+        for value in 1..=3 {
+            tx.send(value).unwrap();
+        }
+        drop(tx);
+        wait_for_exit(receiver);
+
+        let (tx, rx) = channel::<u64>();
+        drop(rx);
+        (received.take(), tx.send(4))
+    });
+    assert_eq!(received, [1, 2, 3]);
+    assert_eq!(refused, Err(4));
+}
+
+#[test]
+fn cloned_senders_are_served_in_turn() {
+    let (received, lens) = run(|| {
+        let (a, rx) = channel::<u64>();
+        let b = a.clone();
+        for value in 1..=3 {
+            a.send(value).unwrap();
+        }
+        for value in 11..=13 {
+            b.send(value).unwrap();
+        }
+        let lens = (rx.len(), rx.is_empty());
+        let mut received = Vec::new();
+        while let Some(value) = rx.try_recv() {
+            received.push(value);
+        }
+        (received, lens)
+    });
+    assert_eq!(received, [1, 11, 2, 12, 3, 13]);
+    assert_eq!(lens, (6, false));
+}
+
+#[test]
+fn a_oneshot_delivers_one_value_or_its_sender_drop() {
+    let (value, dropped, refused) = run(|| {
+        let (tx, rx) = oneshot::<&str>();
+        spawn(move || tx.send("reply").unwrap(), STACK).unwrap();
+        let value = rx.recv();
+
+        let (tx, rx) = oneshot::<&str>();
+        spawn(move || drop(tx), STACK).unwrap();
+        let dropped = rx.recv();
+
+        let (tx, rx) = oneshot::<&str>();
+        drop(rx);
+        (value, dropped, tx.send("nobody"))
+    });
+    assert_eq!(value, Some("reply"));
+    assert_eq!(dropped, None);
+    assert_eq!(refused, Err("nobody"));
+}
+
+#[test]
+fn broadcast_copies_every_value_to_every_receiver() {
+    let received = run(|| {
+        let (tx, first) = broadcast::<u64>(8, LagPolicy::Skip);
+        let second = tx.subscribe();
+        let ids: Vec<_> = [first, second]
+            .into_iter()
+            .map(|rx| {
+                let me = current();
+                spawn(
+                    move || {
+                        let mut values = Vec::new();
+                        while let Ok(value) = rx.recv() {
+                            values.push(value);
+                        }
+                        send_typed(me, values);
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        for value in 1..=3 {
+            assert_eq!(tx.send(value), Ok(2));
+        }
+        drop(tx);
+        let received: Vec<_> = ids
+            .iter()
+            .map(|_| recv_typed::<Vec<u64>>().unwrap())
+            .collect();
+        received
+    });
+    assert_eq!(received, [vec![1, 2, 3], vec![1, 2, 3]]);
+}
+
+#[test]
+fn a_lagging_broadcast_receiver_skips_or_holds_back_the_senders() {
+    let (skipped, parked) = run(|| {
+        let (tx, rx) = broadcast::<u64>(2, LagPolicy::Skip);
+        for value in 1..=5 {
+            tx.send(value).unwrap();
+        }
+        let skipped = (rx.try_recv(), rx.try_recv(), rx.try_recv(), rx.try_recv());
+
+        let (tx, rx) = broadcast::<u64>(2, LagPolicy::Park);
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let out = sent.clone();
+        let sender = spawn(
+            move || {
+                for value in 1..=4 {
+                    tx.send(value).unwrap();
+                    out.borrow_mut().push(value);
+                }
+            },
+            STACK,
+        )
+        .unwrap();
+        // the sender parks with two values waiting for the receiver
+        sleep(Duration::from_millis(5));
+        let held = sent.borrow().clone();
+        let received: Vec<_> = (0..4).map(|_| rx.recv().unwrap()).collect();
+        wait_for_exit(sender);
+        (skipped, (held, received, rx.recv()))
+    });
+    assert_eq!(
+        skipped,
+        (
+            Err(BroadcastRecvError::Lagged(3)),
+            Ok(4),
+            Ok(5),
+            Err(BroadcastRecvError::Empty)
+        )
+    );
+    assert_eq!(
+        parked,
+        (
+            vec![1, 2],
+            vec![1, 2, 3, 4],
+            Err(BroadcastRecvError::Closed)
+        )
+    );
+}
+
+#[test]
+fn watch_receivers_see_the_latest_value() {
+    let seen = run(|| {
+        let (tx, mut rx) = watch::<u64>(0);
+        let me = current();
+        let watcher = spawn(
+            move || {
+                let mut seen = Vec::new();
+                while let Some(value) = rx.changed() {
+                    seen.push(value);
+                }
+                send_typed(me, seen);
+            },
+            STACK,
+        )
+        .unwrap();
+        tx.send(1);
+        // replaced before the watcher runs again, so it only sees the last one
+        let updates = tx.clone();
+        spawn(move || updates.send(2), STACK).unwrap();
+        tx.send(3);
+        let current = (tx.get(), tx.subscribe().get());
+        drop(tx);
+        let seen = recv_typed::<Vec<u64>>().unwrap();
+        wait_for_exit(watcher);
+        (seen, current)
+    });
+    assert_eq!(seen.0.first(), Some(&1));
+    assert_eq!(seen.0.last(), Some(&3));
+    assert!(seen.0.len() <= 3);
+    assert_eq!(seen.1, (3, 3));
+}
+
+#[test]
+fn select_runs_the_first_ready_source() {
+    let picked = run(|| {
+        let (tx, rx) = channel::<u64>();
+        let (once_tx, once_rx) = oneshot::<u64>();
+        let me = current();
+
+        // kept open, so that the channel is not ready with its close
+        let sender = tx.clone();
+        spawn(move || sender.send(1).unwrap(), STACK).unwrap();
+        let from_channel = Select::new()
+            .oneshot(&once_rx, |value| format!("oneshot {:?}", value))
+            .recv(&rx, |value| format!("channel {:?}", value))
+            .mailbox(|msg| format!("mailbox {}", msg))
+            .wait();
+
+        spawn(move || send(me, 2), STACK).unwrap();
+        let from_mailbox = Select::new()
+            .recv(&rx, |value| format!("channel {:?}", value))
+            .mailbox(|msg| format!("mailbox {}", msg))
+            .wait();
+
+        spawn(move || once_tx.send(3).unwrap(), STACK).unwrap();
+        let from_oneshot = Select::new()
+            .oneshot(&once_rx, |value| format!("oneshot {:?}", value))
+            .wait();
+
+        let (_tx, idle) = channel::<u64>();
+        let timed_out = Select::new()
+            .recv(&idle, |value| format!("channel {:?}", value))
+            .after(Duration::from_millis(5), || "timeout".to_string())
+            .wait();
+        drop(tx);
+        [from_channel, from_mailbox, from_oneshot, timed_out]
+    });
+    assert_eq!(
+        picked,
+        ["channel Some(1)", "mailbox 2", "oneshot Some(3)", "timeout"]
+    );
+}
+
+#[test]
+fn a_receive_cut_short_leaves_the_channel_to_the_next_receiver() {
+    let received = run(|| {
+        let (tx, rx) = channel::<u64>();
+        let rx = Rc::new(rx);
+        let gave_up = timeout(Duration::from_millis(5), || rx.recv());
+        let receiver = rx.clone();
+        let me = current();
+        spawn(move || send_typed(me, receiver.recv()), STACK).unwrap();
+        tx.send(7).unwrap();
+        (gave_up, recv_typed::<Option<u64>>().unwrap())
+    });
+    assert_eq!(received, (Err(Elapsed), Some(7)));
+}
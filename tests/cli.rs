@@ -0,0 +1,58 @@
+// The subcommands of the binary.
+#![cfg(feature = "scheduler")]
+
+use std::process::{Command, Output};
+
+fn cli(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_green_thread_rs"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn demo_runs_the_producer_and_the_consumer() {
+    for args in [&[][..], &["demo"][..]] {
+        let output = cli(args);
+        assert!(output.status.success());
+        let out = stdout(&output);
+        for i in 0..10 {
+            assert!(out.contains(&format!("Produce: {}\n", i)), "{}", out);
+            assert!(out.contains(&format!("Consume: {}\n", i)), "{}", out);
+        }
+    }
+}
+
+#[test]
+fn bench_reports_the_messages_delivered() {
+    let output = cli(&["bench", "--threads", "3", "--msgs", "20"]);
+    assert!(output.status.success());
+    let out = stdout(&output);
+    assert!(out.contains(" 60 messages in "), "{}", out);
+    assert!(out.contains("delivered: 60"), "{}", out);
+}
+
+#[test]
+fn bad_arguments_print_the_usage() {
+    for args in [&["nope"][..], &["bench", "--threads", "many"][..]] {
+        let output = cli(args);
+        assert_eq!(output.status.code(), Some(2));
+        assert!(String::from_utf8_lossy(&output.stderr).contains("usage: green_thread_rs"));
+    }
+}
+
+#[test]
+fn chaos_runs_the_same_simulation_for_a_seed() {
+    let output = cli(&["chaos", "--seed", "3"]);
+    if cfg!(feature = "model") {
+        assert!(output.status.success());
+        assert!(stdout(&output).contains("SimReport"));
+        assert_eq!(stdout(&cli(&["chaos", "--seed", "3"])), stdout(&output));
+    } else {
+        assert_eq!(output.status.code(), Some(2));
+    }
+}
@@ -0,0 +1,24 @@
+// Shared by the integration tests: run a body as the first green thread of a runtime of
+// its own, and hand its result, or its panic, back to the test.
+
+use green_thread_rs::green::Runtime;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+/// The stack size of the threads spawned by the tests.
+#[allow(dead_code)]
+pub const STACK: usize = 64 * 1024;
+
+/// Run `f` as the first green thread of a new runtime, and return what it returns;
+/// a panic of `f` fails the test, while one of another green thread only ends that thread.
+#[allow(dead_code)]
+pub fn run<R: 'static>(f: impl FnOnce() -> R + 'static) -> R {
+    let out = Rc::new(RefCell::new(None));
+    let slot = out.clone();
+    Runtime::builder().run(move || {
+        *slot.borrow_mut() = Some(panic::catch_unwind(AssertUnwindSafe(f)));
+    });
+    let result = out.take().expect("the first green thread never finished");
+    result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
@@ -0,0 +1,137 @@
+// The context switch core through coroutines, which need neither the scheduler nor a runtime,
+// so these run on whichever backend the features select.
+
+use green_thread_rs::green::{Coroutine, CoroutineError, CoroutineState, MIN_STACK_SIZE};
+use std::hint::black_box;
+
+const STACK: usize = 64 * 1024;
+
+#[test]
+fn a_generator_yields_its_values_then_completes() {
+    let mut fib = Coroutine::new(
+        |yielder, ()| {
+            let (mut a, mut b) = (0u64, 1u64);
+            for _ in 0..10 {
+                yielder.suspend(a);
+                (a, b) = (b, a + b);
+            }
+            "done"
+        },
+        STACK,
+    )
+    .unwrap();
+    let mut values = Vec::new();
+    let result = loop {
+        match fib.resume(()) {
+            CoroutineState::Yielded(value) => values.push(value),
+            CoroutineState::Complete(result) => break result,
+        }
+    };
+    assert_eq!(values, [0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+    assert_eq!(result, "done");
+    assert!(fib.is_complete());
+}
+
+#[test]
+fn resume_hands_its_input_to_the_body() {
+    let mut sum = Coroutine::<u64, u64, u64>::new(
+        |yielder, first| {
+            let mut total = first;
+            while total < 100 {
+                total += yielder.suspend(total);
+            }
+            total
+        },
+        STACK,
+    )
+    .unwrap();
+    assert_eq!(sum.resume(10), CoroutineState::Yielded(10));
+    assert_eq!(sum.resume(20), CoroutineState::Yielded(30));
+    assert_eq!(sum.resume(80), CoroutineState::Complete(110));
+}
+
+#[test]
+fn registers_and_floats_survive_the_switches() {
+    // interleave two coroutines so that each switch restores the other's callee-saved state
+    let body = |scale: f64| {
+        move |yielder: &green_thread_rs::green::Yielder<f64, ()>, ()| {
+            let mut x = black_box(scale);
+            let mut n = black_box(0u64);
+            for _ in 0..100 {
+                x = black_box(x * 1.5 + 1.0);
+                n += 1;
+                yielder.suspend(x);
+            }
+            (x, n)
+        }
+    };
+    let mut a = Coroutine::new(body(1.0), STACK).unwrap();
+    let mut b = Coroutine::new(body(-3.0), STACK).unwrap();
+    let (result_a, result_b) = loop {
+        match (a.resume(()), b.resume(())) {
+            (CoroutineState::Complete(a), CoroutineState::Complete(b)) => break (a, b),
+            (CoroutineState::Yielded(_), CoroutineState::Yielded(_)) => {}
+            _ => panic!("the coroutines went out of step"),
+        }
+    };
+    let expect = |mut x: f64| {
+        for _ in 0..100 {
+            x = x * 1.5 + 1.0;
+        }
+        (x, 100)
+    };
+    assert_eq!(result_a, expect(1.0));
+    assert_eq!(result_b, expect(-3.0));
+}
+
+#[test]
+fn a_panic_in_the_body_goes_to_the_caller_of_resume() {
+    let mut coroutine = Coroutine::<(), (), ()>::new(|_, ()| panic!("in the body"), STACK).unwrap();
+    let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| coroutine.resume(())))
+        .unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"in the body"));
+}
+
+#[test]
+fn dropping_a_suspended_coroutine_frees_it() {
+    for _ in 0..100 {
+        let mut coroutine = Coroutine::<u64, (), ()>::new(
+            |yielder, ()| loop {
+                yielder.suspend(1);
+            },
+            STACK,
+        )
+        .unwrap();
+        assert_eq!(coroutine.resume(()), CoroutineState::Yielded(1));
+    }
+}
+
+#[test]
+fn small_stacks_are_rounded_up_to_the_minimum() {
+    for size in [0, 100, MIN_STACK_SIZE - 1] {
+        let mut coroutine = Coroutine::<(), u64, ()>::new(
+            |_, ()| {
+                // touches the stack well below its top
+                let frame = black_box([7u8; 1024]);
+                frame.iter().map(|&byte| byte as u64).sum()
+            },
+            size,
+        )
+        .unwrap();
+        assert_eq!(coroutine.resume(()), CoroutineState::Complete(7 * 1024));
+    }
+}
+
+#[test]
+fn an_unlayable_stack_size_is_an_error() {
+    let created = Coroutine::<(), (), ()>::new(|_, ()| {}, usize::MAX - 10);
+    assert!(matches!(created, Err(CoroutineError::InvalidStackSize(_))));
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn a_stack_which_cannot_be_allocated_is_an_error() {
+    // laid out, but larger than any memory to allocate it from
+    let created = Coroutine::<(), (), ()>::new(|_, ()| {}, 1 << 46);
+    assert!(matches!(created, Err(CoroutineError::OutOfMemory)));
+}
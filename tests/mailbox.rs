@@ -0,0 +1,520 @@
+// Mailboxes: ordering, links, remote senders, typed messages, bounds, selective and batch
+// receives, priorities, and the dead letters.
+#![cfg(feature = "scheduler")]
+
+mod common;
+
+use common::{run, STACK};
+use green_thread_rs::green::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// a thread which lets the messages pile up for a while, then hands them all to `to`
+fn spawn_collector(to: ThreadId) -> ThreadId {
+    spawn(
+        move || {
+            sleep(Duration::from_millis(10));
+            send_typed(to, drain_mailbox());
+        },
+        STACK,
+    )
+    .unwrap()
+}
+
+#[test]
+fn messages_of_each_sender_arrive_in_order() {
+    let received = run(|| {
+        let me = current();
+        let receiver = spawn(
+            move || {
+                let msgs: Vec<u64> = (0..2000).map(|_| recv().unwrap()).collect();
+                send_typed(me, msgs);
+            },
+            STACK,
+        )
+        .unwrap();
+        for base in [0, 1_000_000] {
+            spawn(
+                move || {
+                    for i in 0..1000 {
+                        send(receiver, base + i);
+                    }
+                },
+                STACK,
+            )
+            .unwrap();
+        }
+        recv_typed::<Vec<u64>>().unwrap()
+    });
+    let (low, high): (Vec<u64>, Vec<u64>) = received.into_iter().partition(|&msg| msg < 1000);
+    assert_eq!(low, (0..1000).collect::<Vec<_>>());
+    assert_eq!(high, (1_000_000..1_001_000).collect::<Vec<_>>());
+}
+
+#[test]
+fn a_link_keeps_the_order_when_its_ring_overflows() {
+    let (received, second_link) = run(|| {
+        let receiver = spawn_collector(current());
+        assert!(connect(receiver, 2));
+        for msg in 1..=5 {
+            send(receiver, msg);
+        }
+        // taken by the calling thread as long as it lives
+        let second_link = Rc::new(RefCell::new(None));
+        let out = second_link.clone();
+        spawn(
+            move || *out.borrow_mut() = Some(connect(receiver, 2)),
+            STACK,
+        )
+        .unwrap();
+        (recv_typed::<Vec<u64>>().unwrap(), second_link.take())
+    });
+    assert_eq!(received, [1, 2, 3, 4, 5]);
+    assert_eq!(second_link, Some(false));
+}
+
+#[test]
+fn mailboxes_filled_in_turns_keep_their_own_messages() {
+    let mut received = run(|| {
+        let me = current();
+        let (first, second) = (spawn_collector(me), spawn_collector(me));
+        for i in 0..500 {
+            send(first, i);
+            send(second, 1000 + i);
+        }
+        [
+            recv_typed::<Vec<u64>>().unwrap(),
+            recv_typed::<Vec<u64>>().unwrap(),
+        ]
+    });
+    received.sort();
+    assert_eq!(received[0], (0..500).collect::<Vec<_>>());
+    assert_eq!(received[1], (1000..1500).collect::<Vec<_>>());
+}
+
+#[test]
+fn remote_senders_wake_a_parked_runtime() {
+    let sum = run(|| {
+        let me = current();
+        let receiver = spawn(
+            move || {
+                let sum: u64 = (0..100).map(|_| recv().unwrap()).sum();
+                send(me, sum);
+            },
+            STACK,
+        )
+        .unwrap();
+        let senders: Vec<_> = (0..4)
+            .map(|_| {
+                let sender = remote_sender(receiver);
+                std::thread::spawn(move || {
+                    // long enough for the scheduler to stop spinning and park
+                    std::thread::sleep(Duration::from_millis(20));
+                    for i in 0..25 {
+                        sender.send(i);
+                    }
+                })
+            })
+            .collect();
+        let sum = recv();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        sum
+    });
+    assert_eq!(sum, Some(4 * 300));
+}
+
+#[test]
+fn woken_threads_receive_their_own_messages() {
+    let received = run(|| {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let ids: Vec<_> = (0..3)
+            .map(|i| {
+                let received = received.clone();
+                spawn(
+                    move || {
+                        let msg = recv().unwrap();
+                        received.borrow_mut().push((i, msg));
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        for (&id, msg) in ids.iter().zip([10, 20, 30]).rev() {
+            send(id, msg);
+        }
+        for id in ids {
+            wait_for_exit(id);
+        }
+        received.take()
+    });
+    assert_eq!(received, [(2, 30), (1, 20), (0, 10)]);
+    // no green thread runs
+    assert_eq!(recv(), None);
+}
+
+#[test]
+fn try_recv_does_not_park() {
+    let (empty, full) = run(|| {
+        let empty = try_recv();
+        let me = current();
+        spawn(move || send(me, 4), STACK).unwrap();
+        (empty, try_recv())
+    });
+    assert_eq!((empty, full), (None, Some(4)));
+    assert_eq!(try_recv(), None);
+}
+
+// counts its drops, to see that typed messages are not leaked
+struct Counted(Arc<AtomicUsize>);
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn typed_messages_are_received_by_type() {
+    let received = run(|| {
+        let me = current();
+        let receiver = spawn(
+            move || {
+                let number = recv_typed::<u32>().unwrap();
+                let text = recv_typed::<String>().unwrap();
+                send_typed(me, format!("{} {}", text, number));
+            },
+            STACK,
+        )
+        .unwrap();
+        send_typed(receiver, "text".to_string());
+        send_typed(receiver, 3u32);
+        recv_typed::<String>().unwrap()
+    });
+    assert_eq!(received, "text 3");
+}
+
+#[test]
+fn typed_messages_to_ended_threads_are_dropped() {
+    let drops = Arc::new(AtomicUsize::new(0));
+    let counter = drops.clone();
+    let report = Runtime::builder().run(move || {
+        let ended = spawn(|| {}, STACK).unwrap();
+        send_typed(ended, Counted(counter.clone()));
+        // queued but never received, then dropped with the mailbox of its receiver
+        let receiver = spawn(|| sleep(Duration::from_millis(5)), STACK).unwrap();
+        send_typed(receiver, Counted(counter.clone()));
+        wait_for_exit(receiver);
+    });
+    assert_eq!(drops.load(Ordering::Relaxed), 2);
+    assert_eq!(report.dropped, 1);
+}
+
+#[test]
+fn any_messages_share_a_mailbox_and_downcast_on_receive() {
+    let received = run(|| {
+        let me = current();
+        spawn(
+            move || {
+                send_any(me, Box::new(1u8));
+                send_any(me, Box::new("two"));
+            },
+            STACK,
+        )
+        .unwrap();
+        let wrong = recv_downcast::<String>().unwrap().unwrap_err();
+        let right = recv_downcast::<&str>().unwrap().unwrap();
+        (*wrong.downcast::<u8>().unwrap(), right)
+    });
+    assert_eq!(received, (1, "two"));
+}
+
+#[test]
+fn senders_park_on_a_full_bounded_mailbox() {
+    let (lens, received) = run(|| {
+        let me = current();
+        let receiver = spawn_bounded(
+            move || {
+                sleep(Duration::from_millis(10));
+                let msgs: Vec<u64> = (0..5).map(|_| recv().unwrap()).collect();
+                send_typed(me, msgs);
+            },
+            STACK,
+            2,
+            OverflowPolicy::Block,
+        )
+        .unwrap();
+        let mut lens = Vec::new();
+        for msg in 0..5 {
+            send(receiver, msg);
+            lens.push(mailbox_len(receiver));
+        }
+        (lens, recv_typed::<Vec<u64>>().unwrap())
+    });
+    assert!(lens.iter().all(|&len| len <= 2), "{:?}", lens);
+    assert_eq!(received, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn messages_of_remote_senders_take_room_in_a_bounded_mailbox() {
+    let refused = run(|| {
+        let receiver = spawn_bounded(
+            || sleep(Duration::from_millis(20)),
+            STACK,
+            2,
+            OverflowPolicy::Block,
+        )
+        .unwrap();
+        let sender = remote_sender(receiver);
+        std::thread::spawn(move || {
+            sender.send(1);
+            sender.send(2);
+        })
+        .join()
+        .unwrap();
+        try_send(receiver, 3)
+    });
+    assert_eq!(refused, Err(Full(3)));
+}
+
+#[test]
+fn overflow_policies_drop_the_newest_or_the_oldest() {
+    for (policy, kept) in [
+        (OverflowPolicy::DropNewest, [1, 2]),
+        (OverflowPolicy::DropOldest, [2, 3]),
+    ] {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let out = received.clone();
+        let report = Runtime::builder().run(move || {
+            let me = current();
+            let receiver = spawn_bounded(
+                move || {
+                    sleep(Duration::from_millis(5));
+                    send_typed(me, drain_mailbox());
+                },
+                STACK,
+                2,
+                policy,
+            )
+            .unwrap();
+            for msg in 1..=3 {
+                send(receiver, msg);
+            }
+            assert_eq!(try_send(receiver, 4), Err(Full(4)));
+            *out.borrow_mut() = recv_typed::<Vec<u64>>().unwrap();
+        });
+        assert_eq!(*received.borrow(), kept);
+        assert_eq!(report.dropped, 1);
+    }
+}
+
+#[test]
+fn recv_matching_keeps_the_skipped_messages_in_order() {
+    let received = run(|| {
+        let me = current();
+        let receiver = spawn(
+            move || {
+                let matched = recv_matching(|msg| msg % 2 == 0).unwrap();
+                let rest: Vec<u64> = (0..3).map(|_| recv().unwrap()).collect();
+                send_typed(me, (matched, rest));
+            },
+            STACK,
+        )
+        .unwrap();
+        for msg in [1, 3, 4, 5] {
+            send(receiver, msg);
+        }
+        recv_typed::<(u64, Vec<u64>)>().unwrap()
+    });
+    assert_eq!(received, (4, vec![1, 3, 5]));
+}
+
+#[test]
+fn recv_matching_is_woken_by_priority_and_linked_messages() {
+    let received = run(|| {
+        let me = current();
+        let receiver = spawn(
+            move || {
+                let urgent = recv_matching(|msg| msg == 10).unwrap();
+                let linked = recv_matching(|msg| msg == 20).unwrap();
+                send_typed(me, (urgent, linked, drain_mailbox()));
+            },
+            STACK,
+        )
+        .unwrap();
+        send(receiver, 1);
+        send_priority(receiver, 2);
+        // parked again after looking at both lanes, so only new priority messages wake it
+        send_priority(receiver, 10);
+        assert!(connect(receiver, 4));
+        send(receiver, 20);
+        timeout(Duration::from_secs(5), recv_typed::<(u64, u64, Vec<u64>)>)
+    });
+    assert_eq!(received, Ok(Some((10, 20, vec![2, 1]))));
+}
+
+#[test]
+fn recv_many_takes_what_is_queued_up_to_max() {
+    let batches = run(|| {
+        let me = current();
+        let receiver = spawn(
+            move || {
+                sleep(Duration::from_millis(5));
+                let first = recv_many(3);
+                let second = recv_many(3);
+                send_typed(me, (first, second, recv_many(0)));
+            },
+            STACK,
+        )
+        .unwrap();
+        for msg in 1..=5 {
+            send(receiver, msg);
+        }
+        recv_typed::<(Vec<u64>, Vec<u64>, Vec<u64>)>().unwrap()
+    });
+    assert_eq!(batches, (vec![1, 2, 3], vec![4, 5], vec![]));
+}
+
+#[test]
+fn peek_leaves_the_message_and_skips_the_stale_ones() {
+    let (peeked, received, letter) = run(|| {
+        let me = current();
+        let receiver = spawn(
+            move || {
+                sleep(Duration::from_millis(20));
+                let peeked = (peek(), peek());
+                send_typed(me, (peeked, recv()));
+            },
+            STACK,
+        )
+        .unwrap();
+        send_with_ttl(receiver, 1, Duration::from_millis(5));
+        send(receiver, 2);
+        let (peeked, received) = recv_typed::<((Option<u64>, Option<u64>), Option<u64>)>().unwrap();
+        (peeked, received, try_recv_dead_letter())
+    });
+    assert_eq!(peeked, (Some(2), Some(2)));
+    assert_eq!(received, Some(2));
+    let letter = letter.unwrap();
+    assert_eq!((letter.msg, letter.reason), (1, DeadLetterReason::Expired));
+    assert_eq!(peek(), None);
+}
+
+#[test]
+fn mailbox_lengths_and_capacities_are_visible_to_other_threads() {
+    let seen = run(|| {
+        let receiver = spawn_bounded(
+            || sleep(Duration::from_millis(5)),
+            STACK,
+            8,
+            OverflowPolicy::Block,
+        )
+        .unwrap();
+        let before = (mailbox_len(receiver), mailbox_is_empty(receiver));
+        send(receiver, 1);
+        send(receiver, 2);
+        let after = (mailbox_len(receiver), mailbox_is_empty(receiver));
+        (
+            before,
+            after,
+            mailbox_capacity(receiver),
+            mailbox_capacity(current()),
+        )
+    });
+    assert_eq!(seen, ((0, true), (2, false), Some(8), None));
+}
+
+#[test]
+fn drain_mailbox_takes_every_pending_message() {
+    let (drained, after) = run(|| {
+        let me = current();
+        let sender = spawn(
+            move || {
+                for msg in 1..=4 {
+                    send(me, msg);
+                }
+            },
+            STACK,
+        )
+        .unwrap();
+        wait_for_exit(sender);
+        (drain_mailbox(), drain_mailbox())
+    });
+    assert_eq!(drained, [1, 2, 3, 4]);
+    assert!(after.is_empty());
+}
+
+#[test]
+fn messages_carry_their_sender() {
+    let (first, from_b, rest) = run(|| {
+        let me = current();
+        let a = spawn(move || send(me, 1), STACK).unwrap();
+        let b = spawn(move || send(me, 2), STACK).unwrap();
+        let a_again = spawn(move || send(me, 3), STACK).unwrap();
+        let first = recv_with_sender().unwrap();
+        let from_b = recv_from(b).unwrap();
+        let rest = recv_with_sender().unwrap();
+        assert_eq!(first.0, a);
+        assert_eq!(rest.0, a_again);
+        (first.1, from_b, rest.1)
+    });
+    assert_eq!((first, from_b, rest), (1, 2, 3));
+}
+
+#[test]
+fn priority_messages_jump_the_queue() {
+    let received = run(|| {
+        let receiver = spawn_collector(current());
+        send(receiver, 1);
+        send(receiver, 2);
+        send_priority(receiver, 10);
+        send_priority(receiver, 11);
+        send(receiver, 3);
+        recv_typed::<Vec<u64>>().unwrap()
+    });
+    assert_eq!(received, [10, 11, 1, 2, 3]);
+}
+
+#[test]
+fn undeliverable_messages_go_to_the_dead_letters() {
+    let letters = run(|| {
+        let me = current();
+        let ended = spawn(|| {}, STACK).unwrap();
+        send(ended, 1);
+        let slow = spawn(
+            move || {
+                sleep(Duration::from_millis(20));
+                send(me, recv().unwrap());
+            },
+            STACK,
+        )
+        .unwrap();
+        send_with_ttl(slow, 2, Duration::from_millis(5));
+        send_with_ttl(slow, 3, Duration::from_secs(5));
+        let fresh = recv();
+        let letters: Vec<_> = (0..2).map(|_| recv_dead_letter().unwrap()).collect();
+        assert_eq!(letters[0].receiver, ended);
+        assert_eq!(letters[1].receiver, slow);
+        assert_eq!(letters[1].sender, me);
+        (
+            fresh,
+            letters
+                .iter()
+                .map(|letter| (letter.msg, letter.reason))
+                .collect::<Vec<_>>(),
+        )
+    });
+    assert_eq!(letters.0, Some(3));
+    assert_eq!(
+        letters.1,
+        [
+            (1, DeadLetterReason::NoReceiver),
+            (2, DeadLetterReason::Expired)
+        ]
+    );
+}
@@ -0,0 +1,198 @@
+// Exploring the interleavings of green threads, and seeded simulations with faults. What the
+// green threads see is kept in thread locals of the test, as they run on its OS thread, which
+// the `software` backend does not do.
+#![cfg(all(feature = "model", not(feature = "software")))]
+
+mod common;
+
+use common::STACK;
+use green_thread_rs::green::*;
+use std::cell::{Cell, RefCell};
+
+thread_local! {
+    static COUNTER: Cell<u64> = const { Cell::new(0) };
+    static FINISHED: Cell<u64> = const { Cell::new(0) };
+    static LOG: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+    static STOPPED: Cell<bool> = const { Cell::new(false) };
+    static SENTINELS: Cell<u64> = const { Cell::new(0) };
+    static STARTED: Cell<bool> = const { Cell::new(false) };
+    static CRASHED: Cell<bool> = const { Cell::new(false) };
+}
+
+// increment the counter, letting another thread run between the read and the write if `racy`;
+// the second thread to finish checks that both increments are counted
+fn increment(racy: bool) {
+    let read = COUNTER.get();
+    if racy {
+        yield_now();
+    }
+    COUNTER.set(read + 1);
+    FINISHED.set(FINISHED.get() + 1);
+    if FINISHED.get() == 2 {
+        assert!(COUNTER.get() == 2, "an increment is lost");
+    }
+}
+
+fn two_increments(racy: bool) {
+    COUNTER.set(0);
+    FINISHED.set(0);
+    let incrementing: fn() = if racy {
+        || increment(true)
+    } else {
+        || increment(false)
+    };
+    spawn(incrementing, STACK).unwrap();
+    spawn(incrementing, STACK).unwrap();
+}
+
+fn racy_increments() {
+    two_increments(true);
+}
+
+fn atomic_increments() {
+    two_increments(false);
+}
+
+#[test]
+fn model_check_finds_the_interleaving_losing_an_update() {
+    let failure = model_check(racy_increments, STACK, 1000, None).unwrap_err();
+    assert_eq!(failure.message, "an increment is lost");
+    assert!(failure.run > 1, "the first interleaving already fails");
+    // the same schedule fails the same way
+    let replayed = model_replay(racy_increments, STACK, &failure.schedule).unwrap_err();
+    assert_eq!(replayed.message, failure.message);
+}
+
+#[test]
+fn model_check_runs_every_interleaving_of_a_correct_program() {
+    let report = model_check(atomic_increments, STACK, 1000, None).unwrap();
+    assert!(report.complete);
+    assert!(report.runs > 1);
+    let cut_short = model_check(atomic_increments, STACK, 1, None).unwrap();
+    assert!(!cut_short.complete);
+    assert_eq!(cut_short.runs, 1);
+}
+
+// broken by the first increment
+fn checked_invariant() {
+    assert!(COUNTER.get() == 0, "the invariant is broken");
+}
+
+#[test]
+fn the_invariant_is_checked_at_every_decision() {
+    let failure = model_check(atomic_increments, STACK, 1000, Some(checked_invariant));
+    assert_eq!(failure.unwrap_err().message, "the invariant is broken");
+}
+
+fn deadlocking() {
+    recv();
+}
+
+#[test]
+fn a_deadlock_is_a_failure() {
+    let failure = model_check(deadlocking, STACK, 10, None).unwrap_err();
+    assert_eq!(failure.message, "dead lock!");
+}
+
+const SENTINEL: u64 = u64::MAX;
+
+fn logging_as(index: u64) {
+    for _ in 0..3 {
+        LOG.with_borrow_mut(|log| log.push(index));
+        yield_now();
+    }
+}
+
+// count the messages until the sentinel comes
+fn counting() {
+    let mut received = 0;
+    while recv() != Some(SENTINEL) {
+        received += 1;
+    }
+    STOPPED.set(true);
+    LOG.with_borrow_mut(|log| log.push(1000 + received));
+}
+
+// three threads logging their index in turns, and a fourth one counting the messages the main
+// one sends it; the sentinel is sent until it gets through, as the simulation may drop it
+fn logging() {
+    LOG.take();
+    STOPPED.set(false);
+    SENTINELS.set(0);
+    COUNTER.set(sim_now());
+    spawn(|| logging_as(0), STACK).unwrap();
+    spawn(|| logging_as(1), STACK).unwrap();
+    spawn(|| logging_as(2), STACK).unwrap();
+    let counter = spawn(counting, STACK).unwrap();
+    for msg in 0..100 {
+        send(counter, msg);
+    }
+    while !STOPPED.get() {
+        send(counter, SENTINEL);
+        SENTINELS.set(SENTINELS.get() + 1);
+        yield_now();
+    }
+}
+
+#[test]
+fn a_simulation_is_reproduced_by_its_seed() {
+    let simulated = |config: SimConfig| {
+        let report = simulate(logging, STACK, config).unwrap();
+        (report.ticks, report.dropped, LOG.take())
+    };
+    let first = simulated(SimConfig::new(7));
+    assert_eq!(simulated(SimConfig::new(7)), first);
+    assert_eq!(COUNTER.get(), 0, "the virtual time starts at zero");
+    // the threads run in another order with some other seed
+    assert!((8..20).any(|seed| simulated(SimConfig::new(seed)).2 != first.2));
+    assert_eq!(sim_now(), 0);
+}
+
+#[test]
+fn a_simulation_drops_and_duplicates_messages() {
+    let config = SimConfig {
+        drop_rate: 0.3,
+        duplicate_rate: 0.2,
+        ..SimConfig::new(1)
+    };
+    let report = simulate(logging, STACK, config).unwrap();
+    let received = LOG.take().pop().unwrap() - 1000;
+    assert!(report.dropped > 0 && report.duplicated > 0, "{:?}", report);
+    // the copies are delivered later, some after the counting thread has stopped receiving;
+    // the sentinels dropped on the way are counted too
+    let kept = 100 - report.dropped;
+    assert!(received >= kept && received <= kept + SENTINELS.get() + report.duplicated);
+}
+
+fn crashing() {
+    at_exit(|| CRASHED.set(true));
+    STARTED.set(true);
+    loop {
+        yield_now();
+    }
+}
+
+// give the other thread a while to be crashed
+fn waiting_for_the_crash() {
+    STARTED.set(false);
+    CRASHED.set(false);
+    spawn(crashing, STACK).unwrap();
+    for _ in 0..1000 {
+        if CRASHED.get() {
+            return;
+        }
+        yield_now();
+    }
+}
+
+#[test]
+fn the_hooks_of_crashed_threads_run() {
+    let config = SimConfig {
+        crash_rate: 0.1,
+        ..SimConfig::new(3)
+    };
+    let report = simulate(waiting_for_the_crash, STACK, config).unwrap();
+    assert_eq!(report.crashed.len(), 1);
+    // a thread crashed before it ran has no hook yet
+    assert_eq!(CRASHED.get(), STARTED.get());
+}
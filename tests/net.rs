@@ -0,0 +1,212 @@
+// Codecs, RPC within the process, and nodes of runtimes on other OS threads over TCP.
+#![cfg(feature = "net")]
+
+mod common;
+
+use common::{run, STACK};
+use green_thread_rs::green::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+#[test]
+fn codecs_decode_what_they_encode() {
+    let mut buf = Vec::new();
+    U64Codec.encode(&0x0102_0304_0506_0708, &mut buf);
+    assert_eq!(buf, [8, 7, 6, 5, 4, 3, 2, 1]);
+    assert_eq!(U64Codec.decode(&buf), Ok(0x0102_0304_0506_0708));
+    assert_eq!(
+        U64Codec.decode(&buf[..3]),
+        Err(CodecError::Length {
+            expected: 8,
+            found: 3
+        })
+    );
+
+    let mut buf = vec![9];
+    BytesCodec.encode(&b"bytes".to_vec(), &mut buf);
+    assert_eq!(buf, b"\x09bytes");
+    assert_eq!(BytesCodec.decode(&buf[1..]), Ok(b"bytes".to_vec()));
+    assert_ne!(
+        MessageCodec::<u64>::content_type(&U64Codec),
+        MessageCodec::<Vec<u8>>::content_type(&BytesCodec)
+    );
+}
+
+#[test]
+fn rpc_calls_are_answered_by_a_server_on_any_runtime() {
+    let (green, os) = run(|| {
+        let (client, server) = rpc::<u64, u64, _>(U64Codec);
+        spawn(move || server.serve(|x| x * 2), STACK).unwrap();
+        let green = (client.call(&21, None), client.clone().call(&5, None));
+
+        let (client, server) = rpc::<u64, u64, _>(U64Codec);
+        // on a runtime of another OS thread
+        let serving = std::thread::spawn(move || run(move || server.serve(|x| x + 1)));
+        let os = client.call(&1, Some(Duration::from_secs(5)));
+        drop(client);
+        serving.join().unwrap();
+        (green, os)
+    });
+    assert_eq!(green, (Ok(42), Ok(10)));
+    assert_eq!(os, Ok(2));
+}
+
+#[test]
+fn rpc_calls_time_out_are_cancelled_and_see_the_server_leave() {
+    let (timed_out, elapsed, handled, polled, disconnected) = run(|| {
+        let (client, server) = rpc::<u64, u64, _>(U64Codec);
+        // nobody serves yet: the call parks until its deadline
+        let started = Instant::now();
+        let timed_out = client.call(&1, Some(Duration::from_millis(10)));
+        let elapsed = started.elapsed();
+
+        let cancelled = client.start(&2, None).unwrap();
+        cancelled.cancel();
+        let handled = Rc::new(Cell::new(0));
+        let counted = handled.clone();
+        // both are received, and skipped
+        let polled = server.poll(move |x| {
+            counted.set(counted.get() + 1);
+            x
+        });
+        drop(server);
+        (
+            timed_out,
+            elapsed,
+            handled.get(),
+            polled,
+            client.call(&3, None),
+        )
+    });
+    assert_eq!(timed_out, Err(RpcError::Timeout));
+    assert!(elapsed >= Duration::from_millis(10) && elapsed < Duration::from_secs(1));
+    assert_eq!((handled, polled), (0, 2));
+    assert_eq!(disconnected, Err(RpcError::Disconnected));
+}
+
+// What the runtime of the serving node tells the test
+struct Served {
+    received: Option<u64>,
+    typed: Option<Vec<u8>>,
+}
+
+// Run a node on a runtime of its own OS thread, serving "echo" and receiving a message and
+// a typed one on a green thread, until `done` is told
+fn spawn_serving_node(
+    ready: mpsc::Sender<(std::net::SocketAddr, RemoteId)>,
+    done: mpsc::Receiver<()>,
+) -> std::thread::JoinHandle<Served> {
+    std::thread::spawn(move || {
+        run(move || {
+            let node = start_node("127.0.0.1:0").unwrap();
+            let (client, server) = rpc::<Vec<u8>, Vec<u8>, _>(BytesCodec);
+            node.serve_rpc("echo", &client);
+            node.accept_codec::<Vec<u8>, _>(BytesCodec);
+            let serving = spawn(
+                move || {
+                    server.serve(|req| match &req[..] {
+                        b"big" => vec![0; MAX_FRAME + 1],
+                        b"slow" => {
+                            sleep(Duration::from_millis(50));
+                            req
+                        }
+                        _ => req,
+                    })
+                },
+                STACK,
+            )
+            .unwrap();
+            let me = current();
+            let receiver = spawn(
+                move || {
+                    let received = recv();
+                    let typed = recv_typed::<Vec<u8>>();
+                    send_typed(me, Served { received, typed });
+                },
+                STACK,
+            )
+            .unwrap();
+            ready.send((node.addr(), node.id_of(receiver))).unwrap();
+            let served = recv_typed::<Served>().unwrap();
+            YieldingReceiver::new(done).recv().unwrap();
+            drop(node);
+            drop(client);
+            wait_for_exit(serving);
+            served
+        })
+    })
+}
+
+#[test]
+fn nodes_call_each_other_and_send_messages_over_tcp() {
+    let (ready, addr) = mpsc::channel();
+    let (finish, done) = mpsc::channel();
+    let serving = spawn_serving_node(ready, done);
+    let (addr, remote) = addr.recv().unwrap();
+
+    let (echoed, too_large, slow, unknown) = run(move || {
+        let node = start_node("127.0.0.1:0").unwrap();
+        let echo = node.rpc_client::<Vec<u8>, Vec<u8>, _>(addr, "echo", BytesCodec);
+        let timeout = Some(Duration::from_secs(5));
+        let echoed = echo.call(&b"hello".to_vec(), timeout);
+        let too_large = (
+            echo.call(&vec![0; MAX_FRAME], timeout),
+            echo.call(&b"big".to_vec(), timeout),
+        );
+        let slow = echo.call(&b"slow".to_vec(), Some(Duration::from_millis(5)));
+        let nobody = node.rpc_client::<Vec<u8>, Vec<u8>, _>(addr, "nobody", BytesCodec);
+        let unknown = nobody.call(&b"hello".to_vec(), timeout);
+
+        send_remote(remote, 7);
+        // larger than a node receives: refused before it is written
+        let big = vec![0; MAX_FRAME + 1];
+        let refused =
+            std::panic::catch_unwind(|| send_remote_typed(remote, big, &BytesCodec)).is_err();
+        assert!(refused);
+        send_remote_typed(remote, b"typed".to_vec(), &BytesCodec);
+        finish.send(()).unwrap();
+        (echoed, too_large, slow, unknown)
+    });
+    let served = serving.join().unwrap();
+    assert_eq!(echoed, Ok(b"hello".to_vec()));
+    assert_eq!(
+        too_large,
+        (
+            Err(RpcError::TooLarge(MAX_FRAME)),
+            Err(RpcError::TooLarge(MAX_FRAME + 1))
+        )
+    );
+    assert_eq!(slow, Err(RpcError::Timeout));
+    assert_eq!(unknown, Err(RpcError::Disconnected));
+    assert_eq!(served.received, Some(7));
+    assert_eq!(served.typed, Some(b"typed".to_vec()));
+}
+
+#[test]
+fn sending_to_a_local_node_is_sending_to_the_thread() {
+    let received = run(|| {
+        let node = start_node("127.0.0.1:0").unwrap();
+        let me = node.id_of(current());
+        me.send(1);
+        send_remote_typed(me, "local".to_string(), &NoCodec);
+        (recv(), recv_typed::<String>())
+    });
+    assert_eq!(received, (Some(1), Some("local".to_string())));
+}
+
+// A codec never used, since local messages are not encoded
+struct NoCodec;
+
+impl MessageCodec<String> for NoCodec {
+    fn content_type(&self) -> &'static str {
+        "application/x-never"
+    }
+    fn encode(&self, _msg: &String, _buf: &mut Vec<u8>) {
+        unreachable!("a local message is encoded")
+    }
+    fn decode(&self, _bytes: &[u8]) -> Result<String, CodecError> {
+        unreachable!("a local message is decoded")
+    }
+}
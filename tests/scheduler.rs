@@ -0,0 +1,872 @@
+// Green threads: spawning, switching, exits and the introspection of the runtime.
+#![cfg(feature = "scheduler")]
+
+mod common;
+
+use common::{run, STACK};
+use green_thread_rs::green::*;
+use std::cell::{Cell, RefCell};
+use std::hint::black_box;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// threads which wait for a message, then log their number around a yield; ready to run
+// in the order of their numbers once `send_all` has woken them
+fn spawn_gated(order: &Rc<RefCell<Vec<u64>>>, count: u64) -> Vec<ThreadId> {
+    (0..count)
+        .map(|i| {
+            let order = order.clone();
+            spawn(
+                move || {
+                    recv();
+                    yield_now();
+                    order.borrow_mut().push(i);
+                },
+                STACK,
+            )
+            .unwrap()
+        })
+        .collect()
+}
+
+fn wait_for_message() {
+    recv();
+}
+
+#[test]
+fn yield_now_alternates_the_executable_threads() {
+    let order = run(|| {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let ids: Vec<_> = (0..2u64)
+            .map(|n| {
+                let order = order.clone();
+                spawn(
+                    move || {
+                        recv();
+                        for i in 0..3 {
+                            order.borrow_mut().push(n * 10 + i);
+                            yield_now();
+                        }
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        send_all(&ids, 0);
+        for id in ids {
+            wait_for_exit(id);
+        }
+        order.take()
+    });
+    assert_eq!(order, [0, 10, 1, 11, 2, 12]);
+}
+
+#[test]
+fn values_live_across_switches_are_kept() {
+    let sums = run(|| {
+        let sums = Rc::new(RefCell::new(Vec::new()));
+        let ids: Vec<_> = (1..=8u64)
+            .map(|n| {
+                let sums = sums.clone();
+                spawn(
+                    move || {
+                        let (mut int, mut float) = (0u64, 0f64);
+                        for i in 0..100 {
+                            int += n * i;
+                            float += (n * i) as f64 * 0.5;
+                            yield_now();
+                        }
+                        sums.borrow_mut().push((n, int, float));
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        for id in ids {
+            wait_for_exit(id);
+        }
+        sums.take()
+    });
+    assert_eq!(sums.len(), 8);
+    for (n, int, float) in sums {
+        assert_eq!(int, n * 4950);
+        assert_eq!(float, (n * 4950) as f64 * 0.5);
+    }
+}
+
+#[test]
+fn threads_without_fp_do_not_disturb_the_floats_of_others() {
+    let result = run(|| {
+        let result = Rc::new(Cell::new(0.0));
+        let out = result.clone();
+        let keeper = spawn(
+            move || {
+                let mut x = black_box(1.5f64);
+                for _ in 0..50 {
+                    x = black_box(x * 1.0001);
+                    yield_now();
+                }
+                out.set(x);
+            },
+            STACK,
+        )
+        .unwrap();
+        let worker = spawn_no_fp(
+            || {
+                for i in 0..50 {
+                    black_box(i as u64 * 3);
+                    yield_now();
+                }
+            },
+            STACK,
+        )
+        .unwrap();
+        wait_for_exit(keeper);
+        wait_for_exit(worker);
+        result.get()
+    });
+    let mut expected = 1.5f64;
+    for _ in 0..50 {
+        expected *= 1.0001;
+    }
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn ended_threads_give_their_context_to_the_next_spawns() {
+    let stats = run(|| {
+        let before = pool_stats();
+        for _ in 0..10 {
+            let id = spawn(|| {}, STACK).unwrap();
+            wait_for_exit(id);
+            yield_now();
+        }
+        let after = pool_stats();
+        (before, after)
+    });
+    let (before, after) = stats;
+    assert!(after.hits >= before.hits + 9, "{:?}", after);
+    assert_eq!(after.pending, 0);
+    assert!(after.pooled >= 1 && after.pooled <= after.capacity);
+}
+
+#[test]
+fn pool_stats_are_zero_outside_of_a_runtime() {
+    let stats = pool_stats();
+    assert_eq!((stats.pooled, stats.hits, stats.misses), (0, 0, 0));
+}
+
+// the `software` backend runs each green thread on an OS thread of its own, whose stack
+// overflow aborts the process
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64"),
+    not(feature = "software")
+))]
+#[test]
+fn overflowing_a_reused_stack_ends_the_thread_only() {
+    // never returns, which the compiler cannot tell
+    fn recurse(depth: u64) -> u64 {
+        let frame = black_box([depth; 64]);
+        if black_box(frame[0]) == u64::MAX {
+            return 0;
+        }
+        frame[0] + recurse(depth + 1)
+    }
+
+    let statuses = run(|| {
+        // the second thread gets the stack of the first one from the pool
+        let first = spawn(|| {}, STACK).unwrap();
+        let first = wait_for_exit(first);
+        let overflowing = spawn(
+            || {
+                recurse(0);
+            },
+            STACK,
+        )
+        .unwrap();
+        (first, wait_for_exit(overflowing), pool_stats().hits)
+    });
+    assert_eq!(statuses.0, ExitStatus::Normal);
+    assert_eq!(statuses.1, ExitStatus::StackOverflow);
+    assert!(statuses.2 >= 1);
+}
+
+#[test]
+fn current_is_the_id_spawn_returns() {
+    let (spawned, seen, outside) = run(|| {
+        let seen = Rc::new(Cell::new(None));
+        let out = seen.clone();
+        let id = spawn(move || out.set(Some(current())), STACK).unwrap();
+        wait_for_exit(id);
+        (id, seen.get(), is_green_thread())
+    });
+    assert_eq!(Some(spawned), seen);
+    assert!(outside);
+    assert!(!is_green_thread());
+}
+
+#[test]
+fn stacks_past_the_pool_are_freed_writable() {
+    let total = run(|| {
+        // more threads end than the pool keeps
+        let ids: Vec<_> = (0..100)
+            .map(|_| spawn(wait_for_message, STACK).unwrap())
+            .collect();
+        for &id in &ids {
+            send(id, 0);
+        }
+        for id in ids {
+            wait_for_exit(id);
+        }
+        // the memory of the freed stacks, guard pages included, is handed out again
+        let buffers: Vec<Vec<u8>> = (0..100).map(|_| vec![1; STACK]).collect();
+        buffers.iter().map(|buffer| buffer.len()).sum::<usize>()
+    });
+    assert_eq!(total, 100 * STACK);
+}
+
+#[test]
+fn threads_ending_in_bursts_are_reclaimed() {
+    let ended = run(|| {
+        let ended = Rc::new(Cell::new(0));
+        let ids: Vec<_> = (0..1000)
+            .map(|_| {
+                let ended = ended.clone();
+                spawn(
+                    move || {
+                        yield_now();
+                        ended.set(ended.get() + 1);
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        for id in ids {
+            wait_for_exit(id);
+        }
+        ended.get()
+    });
+    assert_eq!(ended, 1000);
+}
+
+#[test]
+fn send_all_wakes_every_receiver() {
+    let (received, report) = {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let out = received.clone();
+        let report = Runtime::builder().run(move || {
+            let ids: Vec<_> = (0..3)
+                .map(|_| {
+                    let out = out.clone();
+                    spawn(
+                        move || {
+                            let msg = recv().unwrap();
+                            out.borrow_mut().push(msg);
+                        },
+                        STACK,
+                    )
+                    .unwrap()
+                })
+                .collect();
+            send_all(&ids, 7);
+            for id in ids {
+                wait_for_exit(id);
+            }
+        });
+        (received.take(), report)
+    };
+    assert_eq!(received, [7, 7, 7]);
+    assert_eq!(report.delivered, 3);
+}
+
+#[test]
+fn the_run_report_counts_the_threads_and_messages() {
+    let report = Runtime::builder().run(|| {
+        let a = spawn(wait_for_message, STACK).unwrap();
+        let b = spawn(wait_for_message, STACK).unwrap();
+        send(a, 1);
+        send(b, 2);
+        let c = spawn(wait_for_message, STACK).unwrap();
+        kill(c);
+    });
+    assert_eq!(report.spawned, 4);
+    assert_eq!(report.peak_threads, 3);
+    assert_eq!(report.delivered, 2);
+    assert_eq!(report.killed, 1);
+    assert!(report.wall_time >= report.cpu_time || report.cpu_time > Duration::ZERO);
+}
+
+#[test]
+fn spawn_from_main_runs_with_the_given_stack_size() {
+    let size = Rc::new(Cell::new(0));
+    let out = size.clone();
+    let report = spawn_from_main(move || out.set(default_stack_size()), 256 * 1024);
+    assert_eq!(size.get(), 256 * 1024);
+    assert_eq!(report.spawned, 1);
+    assert_eq!(default_stack_size(), DEFAULT_STACK_SIZE);
+}
+
+#[test]
+fn at_exit_hooks_run_in_reverse_on_return_panic_and_kill() {
+    let log = run(|| {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let hooked = |tag: &'static str, log: &Rc<RefCell<Vec<String>>>| {
+            let (first, second) = (log.clone(), log.clone());
+            at_exit(move || first.borrow_mut().push(format!("{} first", tag)));
+            at_exit(move || second.borrow_mut().push(format!("{} second", tag)));
+        };
+        let returns = {
+            let log = log.clone();
+            spawn(move || hooked("return", &log), STACK).unwrap()
+        };
+        wait_for_exit(returns);
+        let panics = {
+            let log = log.clone();
+            spawn(
+                move || {
+                    hooked("panic", &log);
+                    panic!("boom");
+                },
+                STACK,
+            )
+            .unwrap()
+        };
+        wait_for_exit(panics);
+        let killed = {
+            let log = log.clone();
+            spawn(
+                move || {
+                    hooked("kill", &log);
+                    recv();
+                },
+                STACK,
+            )
+            .unwrap()
+        };
+        kill(killed);
+        log.take()
+    });
+    assert_eq!(
+        log,
+        [
+            "return second",
+            "return first",
+            "panic second",
+            "panic first",
+            "kill second",
+            "kill first"
+        ]
+    );
+}
+
+#[test]
+fn wait_for_exit_returns_how_the_thread_ended() {
+    let statuses = run(|| {
+        let normal = spawn(|| {}, STACK).unwrap();
+        let panicked = spawn(|| panic!("oops"), STACK).unwrap();
+        let killed = spawn(wait_for_message, STACK).unwrap();
+        assert_eq!(exit_status(killed), None);
+        assert!(kill(killed));
+        assert!(!kill(killed));
+        (
+            wait_for_exit(normal),
+            wait_for_exit(panicked),
+            exit_status(killed),
+        )
+    });
+    assert_eq!(statuses.0, ExitStatus::Normal);
+    assert_eq!(statuses.1, ExitStatus::Panicked("oops".to_string()));
+    assert_eq!(statuses.2, Some(ExitStatus::Killed));
+}
+
+#[test]
+fn exit_statuses_are_forgotten_past_max_exits() {
+    let (first, last) = run(|| {
+        let first = spawn(|| {}, MIN_STACK_SIZE).unwrap();
+        wait_for_exit(first);
+        let mut last = first;
+        for _ in 0..MAX_EXITS {
+            last = spawn(|| {}, MIN_STACK_SIZE).unwrap();
+        }
+        wait_for_exit(last);
+        (exit_status(first), exit_status(last))
+    });
+    assert_eq!(first, None);
+    assert_eq!(last, Some(ExitStatus::Normal));
+}
+
+#[test]
+fn cancel_tree_kills_the_descendants() {
+    let (killed, statuses) = run(|| {
+        let grandchild = Rc::new(Cell::new(None));
+        let out = grandchild.clone();
+        let child = spawn(
+            move || {
+                out.set(Some(spawn(wait_for_message, STACK).unwrap()));
+                recv();
+            },
+            STACK,
+        )
+        .unwrap();
+        // the child runs until it parks once its spawn has returned
+        while grandchild.get().is_none() {
+            yield_now();
+        }
+        let grandchild = grandchild.get().unwrap();
+        let bystander = spawn(wait_for_message, STACK).unwrap();
+        let killed = cancel_tree(child);
+        let statuses = (
+            exit_status(child),
+            exit_status(grandchild),
+            exit_status(bystander),
+        );
+        kill(bystander);
+        (killed, statuses)
+    });
+    assert_eq!(killed, 2);
+    assert_eq!(statuses.0, Some(ExitStatus::Killed));
+    assert_eq!(statuses.1, Some(ExitStatus::Killed));
+    assert_eq!(statuses.2, None);
+}
+
+#[test]
+fn children_are_cancelled_with_a_parent_asking_for_it() {
+    let (orphan, cancelled) = run(|| {
+        let spawn_child = |cancel: bool| {
+            let child = Rc::new(Cell::new(None));
+            let out = child.clone();
+            let parent = spawn(
+                move || {
+                    if cancel {
+                        cancel_children_on_exit();
+                    }
+                    out.set(Some(spawn(wait_for_message, STACK).unwrap()));
+                },
+                STACK,
+            )
+            .unwrap();
+            wait_for_exit(parent);
+            child.get().unwrap()
+        };
+        let orphan = spawn_child(false);
+        let cancelled = spawn_child(true);
+        let statuses = (exit_status(orphan), exit_status(cancelled));
+        kill(orphan);
+        statuses
+    });
+    assert_eq!(orphan, None);
+    assert_eq!(cancelled, Some(ExitStatus::Killed));
+}
+
+#[test]
+fn spawning_at_the_thread_limit_parks_until_a_thread_ends() {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let out = log.clone();
+    // the main green thread counts
+    Runtime::builder()
+        .max_threads(Some(2), LimitPolicy::Park)
+        .run(move || {
+            let log = out.clone();
+            spawn(
+                move || {
+                    yield_now();
+                    log.borrow_mut().push("first ends");
+                },
+                STACK,
+            )
+            .unwrap();
+            assert_eq!(try_spawn(|| {}, STACK), Err(SpawnError::WouldBlock));
+            let log = out.clone();
+            spawn(move || log.borrow_mut().push("spawned"), STACK).unwrap();
+            out.borrow_mut().push("spawn returned");
+        });
+    assert_eq!(*log.borrow(), ["first ends", "spawned", "spawn returned"]);
+}
+
+#[test]
+fn spawning_at_the_thread_limit_fails_with_the_fail_policy() {
+    let failed = Rc::new(Cell::new(false));
+    let out = failed.clone();
+    Runtime::builder()
+        .max_threads(Some(1), LimitPolicy::Fail)
+        .run(move || {
+            let spawned = std::panic::catch_unwind(|| spawn(|| {}, STACK));
+            out.set(spawned.is_err());
+        });
+    assert!(failed.get());
+}
+
+// the snapshot worker keeps its messages until the test lets it go
+static GO: AtomicBool = AtomicBool::new(false);
+
+fn snapshot_worker() {
+    while !GO.load(Ordering::Relaxed) {
+        yield_now();
+    }
+    while recv() != Some(0) {}
+}
+
+#[test]
+fn snapshots_are_saved_loaded_and_restored() {
+    register_factory("snapshot_worker", snapshot_worker);
+    let path = std::env::temp_dir().join(format!("green-snapshot-{}", std::process::id()));
+    let (saved, loaded, restored) = run(move || {
+        GO.store(false, Ordering::Relaxed);
+        let worker = spawn(snapshot_worker, STACK).unwrap();
+        send(worker, 7);
+        send(worker, 8);
+        let saved = snapshot();
+        saved.save(&path).unwrap();
+        let loaded = Snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let worker_only = Snapshot {
+            threads: loaded
+                .threads
+                .iter()
+                .filter(|thread| thread.id == worker)
+                .cloned()
+                .collect(),
+        };
+        let ids = worker_only.restore(STACK).unwrap();
+        let copy = ids[&worker];
+        let restored = snapshot()
+            .threads
+            .into_iter()
+            .find(|thread| thread.id == copy)
+            .unwrap();
+        GO.store(true, Ordering::Relaxed);
+        send(worker, 0);
+        send(copy, 0);
+        (
+            saved.threads.into_iter().find(|t| t.id == worker).unwrap(),
+            loaded.threads.into_iter().find(|t| t.id == worker).unwrap(),
+            restored,
+        )
+    });
+    assert_eq!(saved, loaded);
+    assert_eq!(saved.state, ThreadState::Executable);
+    assert_eq!(saved.factory.as_deref(), Some("snapshot_worker"));
+    assert_eq!(saved.mailbox, [7, 8]);
+    assert_eq!(restored.factory.as_deref(), Some("snapshot_worker"));
+    assert_eq!(restored.mailbox, [7, 8]);
+}
+
+#[test]
+fn snapshots_keep_factory_names_with_spaces() {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("green-spaces-{}", std::process::id()));
+    let text = "green-snapshot 2\n\
+                thread 1 - waiting 20:a worker with spaces 3 4\n\
+                thread 2 1 executable 5:12:34\n\
+                thread 3 - waiting - 5\n";
+    std::fs::write(&path, text).unwrap();
+    let loaded = Snapshot::load(&path).unwrap();
+    loaded.save(&path).unwrap();
+    let saved = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let factories: Vec<_> = loaded
+        .threads
+        .iter()
+        .map(|t| t.factory.as_deref())
+        .collect();
+    assert_eq!(
+        factories,
+        [Some("a worker with spaces"), Some("12:34"), None]
+    );
+    assert_eq!(loaded.threads[0].mailbox, [3, 4]);
+    assert!(loaded.threads[1].mailbox.is_empty());
+    assert_eq!(saved, text);
+}
+
+fn unrestorable_worker() {
+    recv();
+}
+
+#[test]
+fn restoring_with_a_stack_too_small_spawns_nothing() {
+    register_factory("unrestorable_worker", unrestorable_worker);
+    let (restored, before, after) = run(|| {
+        let worker = spawn(unrestorable_worker, STACK).unwrap();
+        let saved = snapshot();
+        let restored = saved.restore(MIN_STACK_SIZE - 1);
+        let after = snapshot().threads.len();
+        send(worker, 0);
+        (restored, saved.threads.len(), after)
+    });
+    assert_eq!(
+        restored,
+        Err(SpawnError::InvalidStackSize(MIN_STACK_SIZE - 1))
+    );
+    assert_eq!(before, after);
+}
+
+#[test]
+fn tree_renders_who_spawned_whom() {
+    let (tree, parent, child) = run(|| {
+        let child = Rc::new(Cell::new(None));
+        let out = child.clone();
+        let parent = spawn(
+            move || {
+                out.set(Some(
+                    spawn(
+                        || loop {
+                            yield_now();
+                        },
+                        STACK,
+                    )
+                    .unwrap(),
+                ));
+                recv();
+            },
+            STACK,
+        )
+        .unwrap();
+        while child.get().is_none() {
+            yield_now();
+        }
+        let child = child.get().unwrap();
+        send(child, 3);
+        let tree = tree();
+        kill(child);
+        kill(parent);
+        (tree, parent, child)
+    });
+    assert!(
+        tree.contains(&format!("└─ {} Waiting mailbox=0", parent)),
+        "{}",
+        tree
+    );
+    assert!(
+        tree.contains(&format!("   └─ {} Executable mailbox=1", child)),
+        "{}",
+        tree
+    );
+}
+
+#[test]
+fn closures_carry_their_captures_into_the_thread() {
+    let got = run(|| {
+        let got = Rc::new(RefCell::new(String::new()));
+        let (out, owned) = (got.clone(), String::from("captured"));
+        let id = spawn(move || out.borrow_mut().push_str(&owned), STACK).unwrap();
+        wait_for_exit(id);
+        got.take()
+    });
+    assert_eq!(got, "captured");
+}
+
+#[test]
+fn spawn_with_hands_the_argument_to_the_function() {
+    fn echo(arg: (ThreadId, u64)) {
+        send(arg.0, arg.1 * 2);
+    }
+    let doubled = run(|| {
+        spawn_with(echo, (current(), 21), STACK).unwrap();
+        recv()
+    });
+    assert_eq!(doubled, Some(42));
+}
+
+#[test]
+fn runtimes_run_independently_on_several_os_threads() {
+    let workers: Vec<_> = (0..4u64)
+        .map(|n| {
+            std::thread::spawn(move || {
+                let sum = Rc::new(Cell::new(0));
+                let out = sum.clone();
+                let report = Runtime::builder().run(move || {
+                    let me = current();
+                    for i in 0..10 {
+                        spawn(move || send(me, n * i), STACK).unwrap();
+                    }
+                    for _ in 0..10 {
+                        out.set(out.get() + recv().unwrap());
+                    }
+                });
+                (sum.get(), report.spawned)
+            })
+        })
+        .collect();
+    for (n, worker) in workers.into_iter().enumerate() {
+        assert_eq!(worker.join().unwrap(), (n as u64 * 45, 11));
+    }
+}
+
+#[test]
+fn a_runtime_cannot_be_nested_nor_used_from_outside() {
+    let nested = run(|| std::panic::catch_unwind(|| Runtime::builder().run(|| {})).is_err());
+    assert!(nested);
+    assert_eq!(spawn(|| {}, STACK), Err(SpawnError::NoRuntime));
+    assert_eq!(lookup("anything"), None);
+}
+
+#[test]
+fn spawn_fails_on_an_invalid_stack_size() {
+    let spawned = run(|| spawn(|| {}, MIN_STACK_SIZE - 1));
+    assert_eq!(
+        spawned,
+        Err(SpawnError::InvalidStackSize(MIN_STACK_SIZE - 1))
+    );
+}
+
+#[test]
+fn stacks_of_sizes_off_the_alignment_run_their_thread() {
+    let formatted = run(|| {
+        let formatted = Rc::new(RefCell::new(Vec::new()));
+        for extra in [1, 4, 8, 12] {
+            let out = formatted.clone();
+            let id = spawn(
+                move || {
+                    out.borrow_mut()
+                        .push(format!("{:.1}", black_box(1.5f64) * 2.0))
+                },
+                MIN_STACK_SIZE + extra,
+            )
+            .unwrap();
+            assert_eq!(wait_for_exit(id), ExitStatus::Normal);
+        }
+        formatted.take()
+    });
+    assert_eq!(formatted, ["3.0"; 4]);
+}
+
+#[test]
+fn thread_ids_are_distinct_and_printable() {
+    let ids = run(|| {
+        (0..100)
+            .map(|_| spawn(|| {}, STACK).unwrap())
+            .collect::<Vec<_>>()
+    });
+    let distinct: std::collections::HashSet<_> = ids.iter().collect();
+    assert_eq!(distinct.len(), ids.len());
+    assert!(ids[0].to_string().parse::<u64>().is_ok());
+}
+
+#[test]
+fn named_threads_are_found_until_they_end() {
+    let (found, taken, after) = run(|| {
+        let id = spawn_named("worker", wait_for_message, STACK).unwrap();
+        let found = lookup("worker") == Some(id);
+        let taken = spawn_named("worker", || {}, STACK);
+        send(id, 0);
+        wait_for_exit(id);
+        (found, taken, lookup("worker"))
+    });
+    assert!(found);
+    assert_eq!(taken, Err(SpawnError::NameInUse("worker".to_string())));
+    assert_eq!(after, None);
+}
+
+#[test]
+fn threads_lists_the_live_threads_with_their_state() {
+    let (me, listed) = run(|| {
+        let waiting = spawn_named(
+            "waiting",
+            || {
+                recv_matching(|m| m == 0);
+            },
+            STACK,
+        )
+        .unwrap();
+        send(waiting, 5);
+        let listed = threads();
+        kill(waiting);
+        (current(), listed)
+    });
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].id, me);
+    assert_eq!(listed[0].state, ThreadState::Running);
+    assert_eq!(listed[1].state, ThreadState::Waiting);
+    assert_eq!(listed[1].name.as_deref(), Some("waiting"));
+    assert_eq!(listed[1].mailbox_depth, 1);
+    assert_eq!(listed[1].stack_size, STACK);
+    assert!(threads().is_empty());
+}
+
+#[test]
+fn yield_with_runs_the_hinted_thread_first() {
+    let order = run(|| {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let ids = spawn_gated(&order, 3);
+        send_all(&ids, 0);
+        yield_with(YieldHint::AfterSend(ids[2]));
+        for id in ids {
+            wait_for_exit(id);
+        }
+        order.take()
+    });
+    assert_eq!(order, [2, 0, 1]);
+}
+
+#[test]
+fn yield_to_switches_straight_to_the_thread() {
+    let order = run(|| {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let ids = spawn_gated(&order, 3);
+        send_all(&ids, 0);
+        assert!(yield_to(ids[1]));
+        order.borrow_mut().push(9);
+        assert!(!yield_to(current()));
+        for id in ids {
+            wait_for_exit(id);
+        }
+        order.take()
+    });
+    assert_eq!(order, [1, 9, 0, 2]);
+}
+
+#[test]
+fn the_prelude_runs_a_producer_and_a_consumer() {
+    use green_thread_rs::prelude::*;
+    let sum = Rc::new(Cell::new(0));
+    let out = sum.clone();
+    spawn_from_main(
+        move || {
+            let consumer = spawn(
+                move || {
+                    while let Some(msg) = recv().filter(|&msg| msg != 0) {
+                        out.set(out.get() + msg);
+                    }
+                },
+                STACK,
+            )
+            .unwrap();
+            for msg in [1, 2, 3, 0] {
+                send(consumer, msg);
+            }
+            yield_now();
+        },
+        STACK,
+    );
+    assert_eq!(sum.get(), 6);
+}
+
+#[cfg(feature = "profile")]
+#[test]
+fn switches_are_profiled() {
+    let profile = run(|| {
+        let id = spawn(
+            || {
+                for _ in 0..10 {
+                    yield_now();
+                }
+            },
+            STACK,
+        )
+        .unwrap();
+        // a thread alone in the queue does not switch when it yields, so take turns
+        for _ in 0..10 {
+            yield_now();
+        }
+        wait_for_exit(id);
+        switch_profile().unwrap()
+    });
+    assert!(profile.count >= 20, "{:?}", profile);
+    assert!(profile.min <= profile.p50 && profile.p50 <= profile.max);
+}
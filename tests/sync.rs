@@ -0,0 +1,398 @@
+// Futures, join handles, fork-join, nurseries, and what green threads share with OS threads
+// and other async runtimes.
+#![cfg(feature = "sync")]
+
+mod common;
+
+use common::{run, STACK};
+use green_thread_rs::green::*;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::pin;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+
+// A waker unparking an OS thread, to poll futures off the runtime like another executor would
+struct Unpark(std::thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on_os_thread<F: Future>(fut: F) -> F::Output {
+    let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+// A future completed by another OS thread, which wakes its waker
+#[derive(Default)]
+struct Flag {
+    state: Mutex<(Option<u64>, Option<Waker>)>,
+}
+
+struct FlagFuture(Arc<Flag>);
+
+impl Future for FlagFuture {
+    type Output = u64;
+
+    fn poll(self: std::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u64> {
+        let mut state = self.0.state.lock().unwrap();
+        match state.0 {
+            Some(value) => Poll::Ready(value),
+            None => {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[test]
+fn block_on_parks_until_the_waker_is_woken_from_another_os_thread() {
+    let (value, ran) = run(|| {
+        let flag = Arc::new(Flag::default());
+        let setter = flag.clone();
+        let worker = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            let mut state = setter.state.lock().unwrap();
+            state.0 = Some(5);
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+        });
+        // runs while the first thread is parked on the future
+        let ran = Rc::new(RefCell::new(false));
+        let out = ran.clone();
+        spawn(move || *out.borrow_mut() = true, STACK).unwrap();
+        let value = block_on(FlagFuture(flag));
+        worker.join().unwrap();
+        (value, ran.take())
+    });
+    assert_eq!(value, 5);
+    assert!(ran);
+}
+
+#[test]
+fn spawned_futures_run_on_green_threads() {
+    let value = run(|| {
+        let value = Rc::new(RefCell::new(0));
+        let out = value.clone();
+        spawn_future(async move { *out.borrow_mut() += 2 }, STACK);
+        let forty = block_on(async { 40 });
+        let value = *value.borrow();
+        value + forty
+    });
+    assert_eq!(value, 42);
+}
+
+#[test]
+fn spawned_futures_are_awaited_through_their_handle() {
+    let sum = run(|| {
+        let first = spawn_future(async { 2 }, STACK);
+        let second = spawn_future(async move { first.await.unwrap() * 10 }, STACK);
+        block_on(async { second.await.unwrap() + 1 })
+    });
+    assert_eq!(sum, 21);
+}
+
+#[test]
+fn a_join_handle_is_awaited_from_another_os_thread() {
+    let (tx, rx) = mpsc::channel();
+    let waiter = std::thread::spawn(move || {
+        let handle: JoinHandle<u64> = rx.recv().unwrap();
+        block_on_os_thread(handle)
+    });
+    run(move || {
+        let handle = spawn_joinable(
+            || {
+                sleep(Duration::from_millis(10));
+                7
+            },
+            STACK,
+        );
+        tx.send(handle).unwrap();
+    });
+    assert_eq!(waiter.join().unwrap(), Ok(7));
+}
+
+#[test]
+fn awaiting_a_thread_ended_without_a_result_gives_how_it_ended() {
+    let (panicked, killed) = run(|| {
+        let panics = spawn_joinable(|| -> u64 { panic!("no result") }, STACK);
+        let parked = spawn_joinable(|| recv().unwrap(), STACK);
+        let id = parked.id();
+        let me = current();
+        spawn(
+            move || {
+                kill(id);
+                send(me, 0);
+            },
+            STACK,
+        )
+        .unwrap();
+        let killed = timeout(Duration::from_secs(5), || block_on(parked));
+        (panics.try_join(), killed)
+    });
+    assert_eq!(panicked, Err(ExitStatus::Panicked("no result".to_string())));
+    assert_eq!(killed, Ok(Err(ExitStatus::Killed)));
+}
+
+#[test]
+fn yielding_adapters_let_green_threads_run_while_waiting_on_std_channels() {
+    let (received, consumed) = run(|| {
+        let (tx, rx) = mpsc::channel();
+        let producer = std::thread::spawn(move || {
+            for value in 0..3 {
+                std::thread::sleep(Duration::from_millis(2));
+                tx.send(value).unwrap();
+            }
+        });
+        let ticks = Rc::new(RefCell::new(0));
+        let out = ticks.clone();
+        let ticker = spawn(
+            move || {
+                for _ in 0..3 {
+                    *out.borrow_mut() += 1;
+                    yield_now();
+                }
+            },
+            STACK,
+        )
+        .unwrap();
+        let rx = YieldingReceiver::new(rx);
+        let received: Vec<u64> = (0..3).map(|_| rx.recv().unwrap()).collect();
+        producer.join().unwrap();
+        wait_for_exit(ticker);
+        assert!(rx.recv().is_err());
+
+        let (tx, rx) = mpsc::sync_channel(1);
+        let consumer = std::thread::spawn(move || rx.iter().collect::<Vec<u64>>());
+        let tx = YieldingSyncSender::new(tx);
+        for value in 0..5 {
+            tx.send(value).unwrap();
+        }
+        drop(tx);
+        assert_eq!(*ticks.borrow(), 3);
+        (received, consumer.join().unwrap())
+    });
+    assert_eq!(received, [0, 1, 2]);
+    assert_eq!(consumed, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn join_and_par_map_run_halves_on_green_threads() {
+    let (pair, squares) = run(|| {
+        let pair = join(current, current);
+        let squares = par_map((1..=20u64).collect(), 3, |x| x * x);
+        (pair.0 != pair.1, squares)
+    });
+    assert!(pair);
+    assert_eq!(squares, (1..=20u64).map(|x| x * x).collect::<Vec<_>>());
+}
+
+#[test]
+fn a_panic_in_a_forked_half_is_resumed_on_the_caller() {
+    let (joined, mapped) = run(|| {
+        let joined = std::panic::catch_unwind(|| join(|| 1, || -> u64 { panic!("right half") }));
+        let mapped = std::panic::catch_unwind(|| {
+            par_map((0..8u64).collect(), 1, |x| {
+                assert!(x != 5, "item five");
+                x
+            })
+        });
+        let message = |payload: Box<dyn std::any::Any + Send>| {
+            payload
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+        };
+        (
+            joined.map_err(message).unwrap_err(),
+            mapped.map_err(message).unwrap_err(),
+        )
+    });
+    assert_eq!(joined.as_deref(), Some("right half"));
+    assert_eq!(mapped.as_deref(), Some("item five"));
+}
+
+#[test]
+fn bridges_pass_values_between_green_threads_and_other_runtimes() {
+    let (to_green, from_outside) = bridge::<u64>();
+    let (to_outside, from_green) = bridge::<u64>();
+    // an async task of some other executor, on an OS thread of its own
+    let task = std::thread::spawn(move || {
+        block_on_os_thread(async move {
+            let mut sum = 0;
+            while let Some(value) = from_green.recv_async().await {
+                sum += value;
+            }
+            to_green.send(sum).unwrap();
+        })
+    });
+    let received = run(move || {
+        for value in 1..=4 {
+            to_outside.send(value).unwrap();
+        }
+        drop(to_outside);
+        from_outside.recv()
+    });
+    task.join().unwrap();
+    assert_eq!(received, Some(10));
+}
+
+#[test]
+fn block_in_place_lets_the_other_green_threads_run() {
+    let (value, ticks, panicked) = run(|| {
+        let ticks = Rc::new(RefCell::new(0));
+        let out = ticks.clone();
+        let ticker = spawn(
+            move || {
+                for _ in 0..5 {
+                    *out.borrow_mut() += 1;
+                    yield_now();
+                }
+            },
+            STACK,
+        )
+        .unwrap();
+        let value = block_in_place(|| {
+            std::thread::sleep(Duration::from_millis(30));
+            42
+        });
+        let ticks_meanwhile = *ticks.borrow();
+        wait_for_exit(ticker);
+        let panicked =
+            std::panic::catch_unwind(|| block_in_place(|| panic!("in the pool"))).is_err();
+        (value, ticks_meanwhile, panicked)
+    });
+    assert_eq!(value, 42);
+    assert!(ticks >= 2, "{}", ticks);
+    assert!(panicked);
+    assert_eq!(block_in_place(|| 1), 1);
+}
+
+#[test]
+fn an_executor_runs_its_tasks_on_green_threads() {
+    let outputs = run(|| {
+        let executor = Executor::with_stack_size(STACK);
+        let tasks: Vec<Task<u64>> = (1..=3)
+            .map(|n| executor.spawn(async move { n * 2 }))
+            .collect();
+        executor.run(async move {
+            let mut outputs = Vec::new();
+            for task in tasks {
+                outputs.push(task.await.unwrap());
+            }
+            outputs
+        })
+    });
+    assert_eq!(outputs, [2, 4, 6]);
+}
+
+#[test]
+fn a_failing_child_cancels_its_siblings() {
+    let (ok, failed, panicked, sibling) = run(|| {
+        let ok = nursery::<&str, _, _>(|n| {
+            n.spawn(|| Ok(()), STACK);
+            n.spawn(|| Ok(()), STACK);
+            "body"
+        });
+        let mut sibling = None;
+        let failed = nursery::<&str, _, _>(|n| {
+            sibling = Some(n.spawn(
+                || {
+                    recv();
+                    Ok(())
+                },
+                STACK,
+            ));
+            n.spawn(|| Err("failed"), STACK);
+            "body"
+        });
+        let sibling = exit_status(sibling.unwrap());
+        let panicked = nursery::<&str, _, _>(|n| {
+            n.spawn(|| panic!("child"), STACK);
+            "body"
+        });
+        (ok, failed, panicked, sibling)
+    });
+    assert_eq!(ok, Ok("body"));
+    assert_eq!(failed, Err(NurseryError::Failed("failed")));
+    assert_eq!(panicked, Err(NurseryError::Panicked("child".to_string())));
+    assert_eq!(sibling, Some(ExitStatus::Killed));
+}
+
+#[test]
+fn join_returns_the_result_of_the_thread() {
+    let result = run(|| {
+        let handle = spawn_joinable(|| "result".to_string(), STACK);
+        let id = handle.id();
+        (handle.join(), exit_status(id))
+    });
+    assert_eq!(result, ("result".to_string(), Some(ExitStatus::Normal)));
+}
+
+#[test]
+fn join_sets_return_the_results_as_they_complete() {
+    let (next, all) = run(|| {
+        let mut set = JoinSet::new();
+        for turns in [20, 5, 10] {
+            set.spawn(
+                move || {
+                    for _ in 0..turns {
+                        yield_now();
+                    }
+                    turns
+                },
+                STACK,
+            );
+        }
+        assert_eq!(set.len(), 3);
+        let mut next = Vec::new();
+        while let Some(turns) = set.join_next() {
+            next.push(turns);
+        }
+        assert!(set.is_empty());
+
+        let handles = (0..3).map(|i| spawn_joinable(move || i, STACK)).collect();
+        (next, join_all(handles))
+    });
+    assert_eq!(next, [5, 10, 20]);
+    assert_eq!(all, [0, 1, 2]);
+}
+
+#[test]
+fn join_timeout_gives_the_handle_back_in_time() {
+    let (elapsed, joined) = run(|| {
+        let handle = spawn_joinable(
+            || {
+                sleep(Duration::from_millis(50));
+                3
+            },
+            STACK,
+        );
+        let started = Instant::now();
+        let handle = match handle.join_timeout(Duration::from_millis(10)) {
+            Ok(_) => panic!("the thread is still sleeping"),
+            Err(handle) => handle,
+        };
+        let elapsed = started.elapsed();
+        let joined = handle.join_timeout(Duration::from_secs(5)).ok();
+        (elapsed, joined)
+    });
+    assert!(elapsed >= Duration::from_millis(10), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(40), "{:?}", elapsed);
+    assert_eq!(joined, Some(3));
+}
@@ -0,0 +1,168 @@
+// Timers: sleeping, delayed spawns, intervals, timeouts and receiving with a deadline.
+#![cfg(feature = "scheduler")]
+
+mod common;
+
+use common::{run, STACK};
+use green_thread_rs::green::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+#[test]
+fn sleep_lets_the_other_threads_run() {
+    let (order, slept) = run(|| {
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let started = Instant::now();
+        let ids: Vec<_> = [30, 10, 20]
+            .into_iter()
+            .map(|ms| {
+                let order = order.clone();
+                spawn(
+                    move || {
+                        sleep(Duration::from_millis(ms));
+                        order.borrow_mut().push(ms);
+                    },
+                    STACK,
+                )
+                .unwrap()
+            })
+            .collect();
+        for id in ids {
+            wait_for_exit(id);
+        }
+        (order.take(), started.elapsed())
+    });
+    assert_eq!(order, [10, 20, 30]);
+    // the sleeps overlap
+    assert!(slept >= Duration::from_millis(30) && slept < Duration::from_millis(60));
+}
+
+#[test]
+fn spawn_after_starts_the_thread_once_the_delay_has_elapsed() {
+    let (started, elapsed) = run(|| {
+        let started = Rc::new(RefCell::new(None));
+        let out = started.clone();
+        let spawned = Instant::now();
+        let id = spawn_after(
+            Duration::from_millis(20),
+            move || *out.borrow_mut() = Some(Instant::now()),
+            STACK,
+        )
+        .unwrap();
+        let early = started.borrow().is_some();
+        wait_for_exit(id);
+        let at = started.take().unwrap();
+        (early, at - spawned)
+    });
+    assert!(!started);
+    assert!(elapsed >= Duration::from_millis(20));
+}
+
+#[test]
+fn an_interval_ticks_at_the_boundaries_of_its_period() {
+    let ticks = run(|| {
+        let mut interval = interval(Duration::from_millis(10));
+        let first = interval.tick();
+        // busy past two boundaries: the first is returned right away, the second skipped
+        std::thread::sleep(Duration::from_millis(25));
+        let late = interval.tick();
+        let next = interval.tick();
+        (interval.period(), late - first, next - late)
+    });
+    assert_eq!(ticks.0, Duration::from_millis(10));
+    assert_eq!(ticks.1, Duration::from_millis(10));
+    assert_eq!(ticks.2, Duration::from_millis(20));
+}
+
+#[test]
+fn timeout_cuts_short_a_parked_operation() {
+    let (quick, parked, nested) = run(|| {
+        let quick = timeout(Duration::from_millis(50), || 7);
+        let parked = timeout(Duration::from_millis(10), recv);
+        // the inner deadline passes first, the outer one is not reached
+        let nested = timeout(Duration::from_millis(500), || {
+            timeout(Duration::from_millis(10), || sleep(Duration::from_secs(5)))
+        });
+        (quick, parked, nested)
+    });
+    assert_eq!(quick, Ok(7));
+    assert_eq!(parked, Err(Elapsed));
+    assert_eq!(nested, Ok(Err(Elapsed)));
+}
+
+#[test]
+fn a_sender_cut_short_does_not_take_the_wakeup_of_a_parked_one() {
+    let (gave_up, received) = run(|| {
+        let me = current();
+        let receiver = spawn_bounded(
+            move || {
+                sleep(Duration::from_millis(30));
+                recv();
+                let second = recv().unwrap();
+                send(me, second);
+            },
+            STACK,
+            1,
+            OverflowPolicy::Block,
+        )
+        .unwrap();
+        send(receiver, 0);
+        let gave_up = Rc::new(RefCell::new(None));
+        let out = gave_up.clone();
+        // parks on the full mailbox, then leaves it
+        spawn(
+            move || {
+                *out.borrow_mut() = Some(timeout(Duration::from_millis(5), || send(receiver, 1)))
+            },
+            STACK,
+        )
+        .unwrap();
+        // parks behind it, and must be woken once the receiver takes a message
+        spawn(move || send(receiver, 2), STACK).unwrap();
+        let received = recv_timeout(Duration::from_secs(5));
+        (gave_up.take(), received)
+    });
+    assert_eq!(gave_up, Some(Err(Elapsed)));
+    assert_eq!(received, Ok(2));
+}
+
+#[test]
+fn a_receiver_cut_short_does_not_take_the_next_dead_letter() {
+    let letter = run(|| {
+        let watcher = spawn(
+            || {
+                let _ = timeout(Duration::from_millis(5), recv_dead_letter);
+                recv();
+            },
+            STACK,
+        )
+        .unwrap();
+        sleep(Duration::from_millis(10));
+        let ended = spawn(|| {}, STACK).unwrap();
+        send(ended, 3);
+        let letter = recv_dead_letter();
+        kill(watcher);
+        letter.map(|letter| (letter.msg, letter.reason))
+    });
+    assert_eq!(letter, Some((3, DeadLetterReason::NoReceiver)));
+}
+
+#[test]
+fn recv_timeout_gives_up_without_a_message() {
+    let (timed_out, received, elapsed) = run(|| {
+        let started = Instant::now();
+        let timed_out = recv_timeout(Duration::from_millis(10));
+        let elapsed = started.elapsed();
+        let me = current();
+        spawn_after(Duration::from_millis(5), move || send(me, 9), STACK).unwrap();
+        (timed_out, recv_timeout(Duration::from_secs(5)), elapsed)
+    });
+    assert_eq!(timed_out, Err(RecvTimeoutError::Timeout));
+    assert!(elapsed >= Duration::from_millis(10));
+    assert_eq!(received, Ok(9));
+    assert_eq!(
+        recv_timeout(Duration::ZERO),
+        Err(RecvTimeoutError::NoMailbox)
+    );
+}